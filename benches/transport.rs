@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use drpc::wire::{frame, id::ID, packet, split};
+use drpc::{transport, Transport};
+
+// encode returns count packets of size bytes each, split into frames of at
+// most 64 KiB like a stream writes them.
+fn encode(count: u64, size: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for message in 1..=count {
+        let pkt = packet::Packet {
+            data: vec![7; size],
+            id: ID::new(1, message),
+            kind: packet::Kind::Message,
+        };
+        for fr in split::split(&pkt, 64 << 10) {
+            frame::append_frame(&mut buf, &fr);
+        }
+    }
+    buf
+}
+
+async fn read_all(input: &[u8]) -> usize {
+    let wire = tokio::io::join(input, tokio::io::sink());
+    let mut tr = transport::Transport::new(wire);
+    let mut buf = Vec::new();
+    let mut total = 0;
+    while tr.read_packet_into(&mut buf).await.is_ok() {
+        total += buf.len();
+    }
+    total
+}
+
+fn read_packets(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("read_packets");
+    for &(name, count, size) in &[("small_frames", 10_000, 16), ("huge_packets", 4, 4 << 20)] {
+        let input = encode(count, size);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| assert_eq!(rt.block_on(read_all(&input)), count as usize * size))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read_packets);
+criterion_main!(benches);
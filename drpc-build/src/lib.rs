@@ -0,0 +1,319 @@
+//! Code generation for drpc-rs services, meant to be called from `build.rs`.
+//!
+//! Messages are generated by prost and additionally derive
+//! `drpc::ProstMessage`, and every service becomes a trait annotated with
+//! `#[drpc::service]`, which in turn generates the client, the server and the
+//! rpc names. The crate using the output needs `drpc` and `prost` as
+//! dependencies.
+//!
+//! ```rust,no_run
+//! fn main() -> std::io::Result<()> {
+//!     drpc_build::configure()
+//!         .build_server(false)
+//!         .compile(&["proto/cookies.proto"], &["proto"])
+//! }
+//! ```
+
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Compiles the `.proto` files into `OUT_DIR` with the default options.
+pub fn compile_protos(
+    protos: &[impl AsRef<Path>],
+    includes: &[impl AsRef<Path>],
+) -> io::Result<()> {
+    configure().compile(protos, includes)
+}
+
+/// Returns a builder to customize the generated code.
+pub fn configure() -> Builder {
+    Builder {
+        client: true,
+        server: true,
+        service_attributes: Vec::new(),
+        type_attributes: Vec::new(),
+        extern_paths: Vec::new(),
+        out_dir: None,
+    }
+}
+
+/// Builder of the generated code, returned by `configure`.
+///
+/// Unary methods take the decoded input and return the output. Streaming
+/// methods are all generated as bidirectional streams taking a
+/// `&mut dyn drpc::Stream<Output, Input>`, whether the client, the server or
+/// both stream. A server streaming method receives its single input from the
+/// stream before sending, and a client streaming method sends its single
+/// output once the client closes its side.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    client: bool,
+    server: bool,
+    service_attributes: Vec<(String, String)>,
+    type_attributes: Vec<(String, String)>,
+    extern_paths: Vec<(String, String)>,
+    out_dir: Option<PathBuf>,
+}
+
+impl Builder {
+    /// Controls whether `<Service>Client` types are generated.
+    pub fn build_client(mut self, enable: bool) -> Self {
+        self.client = enable;
+        self
+    }
+
+    /// Controls whether `<Service>Server` types are generated.
+    pub fn build_server(mut self, enable: bool) -> Self {
+        self.server = enable;
+        self
+    }
+
+    /// Adds an attribute to the traits of the services matched by `path`.
+    ///
+    /// Paths are fully qualified protobuf names like `.pkg.Service`, or a
+    /// prefix of one like `.pkg`, or `.` to match every service.
+    pub fn service_attribute(mut self, path: impl AsRef<str>, attr: impl AsRef<str>) -> Self {
+        self.service_attributes
+            .push((path.as_ref().to_string(), attr.as_ref().to_string()));
+        self
+    }
+
+    /// Adds an attribute to the messages and enums matched by `path`. See
+    /// `prost_build::Config::type_attribute`.
+    pub fn type_attribute(mut self, path: impl AsRef<str>, attr: impl AsRef<str>) -> Self {
+        self.type_attributes
+            .push((path.as_ref().to_string(), attr.as_ref().to_string()));
+        self
+    }
+
+    /// Maps a protobuf package or type to an existing Rust module or type
+    /// instead of generating it. See `prost_build::Config::extern_path`.
+    pub fn extern_path(mut self, proto_path: impl AsRef<str>, rust_path: impl AsRef<str>) -> Self {
+        self.extern_paths.push((
+            proto_path.as_ref().to_string(),
+            rust_path.as_ref().to_string(),
+        ));
+        self
+    }
+
+    /// Writes the generated files to `dir` instead of `OUT_DIR`.
+    pub fn out_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.out_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Compiles the `.proto` files using protoc.
+    pub fn compile(
+        self,
+        protos: &[impl AsRef<Path>],
+        includes: &[impl AsRef<Path>],
+    ) -> io::Result<()> {
+        self.compile_with_config(prost_build::Config::new(), protos, includes)
+    }
+
+    /// Compiles the `.proto` files using an already customized prost config.
+    pub fn compile_with_config(
+        self,
+        mut config: prost_build::Config,
+        protos: &[impl AsRef<Path>],
+        includes: &[impl AsRef<Path>],
+    ) -> io::Result<()> {
+        self.setup(&mut config);
+        config.compile_protos(protos, includes)
+    }
+
+    /// Compiles an already parsed file descriptor set, without running protoc.
+    pub fn compile_fds(self, fds: prost_types::FileDescriptorSet) -> io::Result<()> {
+        let mut config = prost_build::Config::new();
+        self.setup(&mut config);
+        config.compile_fds(fds)
+    }
+
+    fn setup(self, config: &mut prost_build::Config) {
+        config.message_attribute(".", "#[derive(::drpc::ProstMessage)]");
+        for (path, attr) in &self.type_attributes {
+            config.type_attribute(path, attr);
+        }
+        for (proto_path, rust_path) in &self.extern_paths {
+            config.extern_path(proto_path, rust_path);
+        }
+        if let Some(dir) = &self.out_dir {
+            config.out_dir(dir);
+        }
+        config.service_generator(Box::new(ServiceGenerator {
+            client: self.client,
+            server: self.server,
+            attributes: self.service_attributes,
+        }));
+    }
+}
+
+// service generation
+
+struct ServiceGenerator {
+    client: bool,
+    server: bool,
+    attributes: Vec<(String, String)>,
+}
+
+fn matches(path: &str, name: &str) -> bool {
+    path == "." || path == name || name.starts_with(path) && name[path.len()..].starts_with('.')
+}
+
+impl prost_build::ServiceGenerator for ServiceGenerator {
+    fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
+        let name = if service.package.is_empty() {
+            service.proto_name.clone()
+        } else {
+            format!("{}.{}", service.package, service.proto_name)
+        };
+        let full = format!(".{}", name);
+
+        service.comments.append_with_indent(0, buf);
+        for (path, attr) in &self.attributes {
+            if matches(path, &full) {
+                buf.push_str(attr);
+                buf.push('\n');
+            }
+        }
+
+        let _ = writeln!(
+            buf,
+            "#[::drpc::service(name = {:?}, client = {}, server = {})]",
+            name, self.client, self.server,
+        );
+        let _ = writeln!(buf, "pub trait {} {{", service.name);
+        for method in &service.methods {
+            method.comments.append_with_indent(1, buf);
+            let _ = writeln!(buf, "    #[drpc(name = {:?})]", method.proto_name);
+            if method.client_streaming || method.server_streaming {
+                let _ = writeln!(
+                    buf,
+                    "    async fn {}(&self, stream: &mut dyn ::drpc::Stream<{}, {}>) -> ::drpc::stream::Result<()>;",
+                    method.name, method.output_type, method.input_type,
+                );
+            } else {
+                let _ = writeln!(
+                    buf,
+                    "    async fn {}(&self, input: {}) -> ::drpc::stream::Result<{}>;",
+                    method.name, method.input_type, method.output_type,
+                );
+            }
+        }
+        buf.push_str("}\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_build::ServiceGenerator;
+    use prost_types::{
+        DescriptorProto, FileDescriptorProto, FileDescriptorSet, MethodDescriptorProto,
+        ServiceDescriptorProto,
+    };
+
+    fn method(name: &str, client_streaming: bool, server_streaming: bool) -> prost_build::Method {
+        prost_build::Method {
+            name: name.to_lowercase(),
+            proto_name: name.to_string(),
+            comments: Default::default(),
+            input_type: "Cookie".to_string(),
+            output_type: "Crumbs".to_string(),
+            input_proto_type: ".sesamestreet.Cookie".to_string(),
+            output_proto_type: ".sesamestreet.Crumbs".to_string(),
+            options: Default::default(),
+            client_streaming,
+            server_streaming,
+        }
+    }
+
+    fn service() -> prost_build::Service {
+        prost_build::Service {
+            name: "CookieMonster".to_string(),
+            proto_name: "CookieMonster".to_string(),
+            package: "sesamestreet".to_string(),
+            comments: Default::default(),
+            methods: vec![
+                method("Eat", false, false),
+                method("Chew", true, true),
+                method("Nibble", true, false),
+                method("Crumble", false, true),
+            ],
+            options: Default::default(),
+        }
+    }
+
+    #[test]
+    fn generate() {
+        let mut gen = super::ServiceGenerator {
+            client: true,
+            server: false,
+            attributes: vec![
+                (
+                    ".sesamestreet".to_string(),
+                    "#[allow(dead_code)]".to_string(),
+                ),
+                (".sesame".to_string(), "#[deprecated]".to_string()),
+            ],
+        };
+        let mut buf = String::new();
+        gen.generate(service(), &mut buf);
+
+        assert_eq!(
+            buf,
+            concat!(
+                "#[allow(dead_code)]\n",
+                "#[::drpc::service(name = \"sesamestreet.CookieMonster\", client = true, server = false)]\n",
+                "pub trait CookieMonster {\n",
+                "    #[drpc(name = \"Eat\")]\n",
+                "    async fn eat(&self, input: Cookie) -> ::drpc::stream::Result<Crumbs>;\n",
+                "    #[drpc(name = \"Chew\")]\n",
+                "    async fn chew(&self, stream: &mut dyn ::drpc::Stream<Crumbs, Cookie>) -> ::drpc::stream::Result<()>;\n",
+                "    #[drpc(name = \"Nibble\")]\n",
+                "    async fn nibble(&self, stream: &mut dyn ::drpc::Stream<Crumbs, Cookie>) -> ::drpc::stream::Result<()>;\n",
+                "    #[drpc(name = \"Crumble\")]\n",
+                "    async fn crumble(&self, stream: &mut dyn ::drpc::Stream<Crumbs, Cookie>) -> ::drpc::stream::Result<()>;\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn compile_fds() {
+        let message = |name: &str| DescriptorProto {
+            name: Some(name.to_string()),
+            ..Default::default()
+        };
+        let fds = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("cookies.proto".to_string()),
+                package: Some("sesamestreet".to_string()),
+                syntax: Some("proto3".to_string()),
+                message_type: vec![message("Cookie"), message("Crumbs")],
+                service: vec![ServiceDescriptorProto {
+                    name: Some("CookieMonster".to_string()),
+                    method: vec![MethodDescriptorProto {
+                        name: Some("EatCookie".to_string()),
+                        input_type: Some(".sesamestreet.Cookie".to_string()),
+                        output_type: Some(".sesamestreet.Crumbs".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+
+        let dir = std::env::temp_dir().join(format!("drpc-build-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        super::configure().out_dir(&dir).compile_fds(fds).unwrap();
+        let out = std::fs::read_to_string(dir.join("sesamestreet.rs")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(out.matches("#[derive(::drpc::ProstMessage)]").count(), 2);
+        assert!(out.contains("name = \"sesamestreet.CookieMonster\""));
+        assert!(out.contains("#[drpc(name = \"EatCookie\")]"));
+        assert!(out.contains("async fn eat_cookie(&self, input: Cookie)"));
+    }
+}
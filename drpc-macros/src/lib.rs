@@ -0,0 +1,42 @@
+use proc_macro::TokenStream;
+use syn::parse_macro_input;
+
+mod message;
+mod service;
+
+/// Turns a trait of async methods into a drpc service.
+///
+/// Every method must take `&self` and exactly one other argument. A method
+/// whose argument is a `&mut dyn drpc::Stream<Send, Recv>` is a streaming
+/// rpc and is handed the stream directly; any other method is unitary and
+/// receives the decoded input and returns a `Result` of the output. The error
+/// of a unitary method may be any type implementing `Display`, and fails the
+/// stream with its message and `drpc::server::ERROR_CODE`.
+///
+/// Alongside the trait this generates `<Trait>Description` holding the rpc
+/// names, `<Trait>Client<C>` wrapping any `drpc::Conn`, and
+/// `<Trait>Server<T>` implementing `drpc::server::Mux` for an implementor.
+///
+/// The service name defaults to the trait name and the method names to the
+/// camel cased method identifiers. They can be overridden with
+/// `#[drpc::service(name = "pkg.Service")]` and `#[drpc(name = "Method")]`.
+/// Passing `client = false` or `server = false` skips generating that half.
+#[proc_macro_attribute]
+pub fn service(args: TokenStream, input: TokenStream) -> TokenStream {
+    let mut opts = service::Options::default();
+    let parser = syn::meta::parser(|meta| opts.parse(meta));
+    parse_macro_input!(args with parser);
+
+    let item = parse_macro_input!(input as syn::ItemTrait);
+    service::expand(opts, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Implements `drpc::enc::Marshal` and `drpc::enc::Unmarshal` for a type
+/// implementing `prost::Message`, encoding it as protobuf.
+#[proc_macro_derive(ProstMessage)]
+pub fn prost_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    message::expand(input).into()
+}
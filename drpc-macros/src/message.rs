@@ -0,0 +1,26 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::DeriveInput;
+
+pub fn expand(input: DeriveInput) -> TokenStream {
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    quote! {
+        impl #impl_generics ::drpc::enc::Marshal for #ident #ty_generics #where_clause {
+            fn marshal(&self, buf: &mut Vec<u8>) -> ::drpc::enc::Result<()> {
+                buf.clear();
+                ::prost::Message::encode(self, buf)?;
+                Ok(())
+            }
+        }
+
+        impl #impl_generics ::drpc::enc::Unmarshal for #ident #ty_generics #where_clause {
+            fn unmarshal(&mut self, buf: &[u8]) -> ::drpc::enc::Result<()> {
+                ::prost::Message::clear(self);
+                ::prost::Message::merge(self, buf)?;
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    ext::IdentExt, meta::ParseNestedMeta, spanned::Spanned, FnArg, GenericArgument, Ident,
+    ItemTrait, LitBool, LitByteStr, LitStr, PathArguments, ReturnType, TraitItem, TraitItemFn,
+    Type, TypeParamBound,
+};
+
+// options
+
+pub struct Options {
+    name: Option<String>,
+    client: bool,
+    server: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            name: None,
+            client: true,
+            server: true,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse(&mut self, meta: ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("name") {
+            self.name = Some(meta.value()?.parse::<LitStr>()?.value());
+        } else if meta.path.is_ident("client") {
+            self.client = meta.value()?.parse::<LitBool>()?.value;
+        } else if meta.path.is_ident("server") {
+            self.server = meta.value()?.parse::<LitBool>()?.value;
+        } else {
+            return Err(meta.error("unsupported service option"));
+        }
+        Ok(())
+    }
+}
+
+// methods
+
+enum Kind {
+    Unitary { input: Type, output: Type },
+    Streaming { send: Type, recv: Type },
+}
+
+struct Method {
+    ident: Ident,
+    konst: Ident,
+    rpc: String,
+    kind: Kind,
+}
+
+fn parse_method(service: &str, f: &mut TraitItemFn) -> syn::Result<Method> {
+    let mut name = None;
+    let mut err = Ok(());
+    f.attrs.retain(|attr| {
+        if !attr.path().is_ident("drpc") {
+            return true;
+        }
+        let res = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported method option"))
+            }
+        });
+        if err.is_ok() {
+            err = res;
+        }
+        false
+    });
+    err?;
+
+    let sig = &f.sig;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new(sig.span(), "drpc methods must be async"));
+    }
+
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(recv)) if recv.reference.is_some() && recv.mutability.is_none() => (),
+        _ => {
+            return Err(syn::Error::new(
+                sig.span(),
+                "drpc methods must take `&self`",
+            ))
+        }
+    }
+    let arg = match (inputs.next(), inputs.next()) {
+        (Some(FnArg::Typed(arg)), None) => &*arg.ty,
+        _ => {
+            return Err(syn::Error::new(
+                sig.inputs.span(),
+                "drpc methods must take exactly one argument besides `&self`",
+            ))
+        }
+    };
+
+    let kind = match stream_args(arg) {
+        Some((send, recv)) => Kind::Streaming { send, recv },
+        None => Kind::Unitary {
+            input: arg.clone(),
+            output: result_output(&sig.output)?,
+        },
+    };
+
+    let ident = sig.ident.clone();
+    let raw = ident.unraw().to_string();
+    let name = name.unwrap_or_else(|| upper_camel(&raw));
+
+    Ok(Method {
+        konst: format_ident!("{}", raw.to_uppercase()),
+        rpc: format!("/{}/{}", service, name),
+        ident,
+        kind,
+    })
+}
+
+// stream_args returns the send and receive types of a `&mut dyn Stream<Send, Recv>`.
+fn stream_args(ty: &Type) -> Option<(Type, Type)> {
+    let mut elem = match ty {
+        Type::Reference(r) if r.mutability.is_some() => &*r.elem,
+        _ => return None,
+    };
+    while let Type::Paren(p) = elem {
+        elem = &*p.elem;
+    }
+    let obj = match elem {
+        Type::TraitObject(obj) => obj,
+        _ => return None,
+    };
+
+    obj.bounds.iter().find_map(|bound| {
+        let seg = match bound {
+            TypeParamBound::Trait(tb) => tb.path.segments.last()?,
+            _ => return None,
+        };
+        if seg.ident != "Stream" {
+            return None;
+        }
+        let args = match &seg.arguments {
+            PathArguments::AngleBracketed(args) => args,
+            _ => return None,
+        };
+        let mut types = args.args.iter().filter_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty.clone()),
+            _ => None,
+        });
+        match (types.next(), types.next(), types.next()) {
+            (Some(send), Some(recv), None) => Some((send, recv)),
+            _ => None,
+        }
+    })
+}
+
+// result_output returns `T` from a return type like `Result<T>` or
+// `Result<T, E>`. the server sends the error as a remote error, so `E` only
+// has to implement Display.
+fn result_output(ret: &ReturnType) -> syn::Result<Type> {
+    let span = match ret {
+        ReturnType::Type(_, ty) => ty.span(),
+        _ => ret.span(),
+    };
+    let err = || {
+        syn::Error::new(
+            span,
+            "drpc methods must return a `Result<T>` or `Result<T, E>`",
+        )
+    };
+
+    let path = match ret {
+        ReturnType::Type(_, ty) => match &**ty {
+            Type::Path(path) => path,
+            _ => return Err(err()),
+        },
+        _ => return Err(err()),
+    };
+    let seg = path.path.segments.last().ok_or_else(err)?;
+    if seg.ident != "Result" {
+        return Err(err());
+    }
+    match &seg.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() <= 2 => {
+            let mut args = args.args.iter();
+            match (args.next(), args.next()) {
+                (Some(GenericArgument::Type(ty)), None | Some(GenericArgument::Type(_))) => {
+                    Ok(ty.clone())
+                }
+                _ => Err(err()),
+            }
+        }
+        _ => Err(err()),
+    }
+}
+
+fn upper_camel(s: &str) -> String {
+    s.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+// expansion
+
+pub fn expand(opts: Options, mut item: ItemTrait) -> syn::Result<TokenStream> {
+    let service = opts.name.unwrap_or_else(|| item.ident.to_string());
+
+    let mut methods = Vec::new();
+    for ti in &mut item.items {
+        match ti {
+            TraitItem::Fn(f) => methods.push(parse_method(&service, f)?),
+            other => {
+                return Err(syn::Error::new(
+                    other.span(),
+                    "drpc services may only contain async methods",
+                ))
+            }
+        }
+    }
+
+    let vis = &item.vis;
+    let desc = format_ident!("{}Description", item.ident);
+
+    let consts = methods.iter().map(|m| {
+        let (konst, rpc) = (&m.konst, &m.rpc);
+        quote! { pub const #konst: &'static str = #rpc; }
+    });
+    let konsts = methods.iter().map(|m| &m.konst);
+
+    let client = if opts.client {
+        expand_client(&item, &desc, &methods)
+    } else {
+        TokenStream::new()
+    };
+    let server = if opts.server {
+        expand_server(&item, &methods)
+    } else {
+        TokenStream::new()
+    };
+
+    Ok(quote! {
+        #[::drpc::async_trait]
+        #item
+
+        #vis struct #desc;
+
+        impl #desc {
+            pub const SERVICE: &'static str = #service;
+            #(#consts)*
+            pub const METHODS: &'static [&'static str] = &[#(Self::#konsts),*];
+        }
+
+        #client
+        #server
+    })
+}
+
+fn expand_client(item: &ItemTrait, desc: &Ident, methods: &[Method]) -> TokenStream {
+    let vis = &item.vis;
+    let client = format_ident!("{}Client", item.ident);
+
+    let calls = methods.iter().map(|m| {
+        let (ident, konst) = (&m.ident, &m.konst);
+        match &m.kind {
+            Kind::Unitary { input, output } => quote! {
+                pub async fn #ident(&mut self, input: &#input) -> ::drpc::stream::Result<#output> {
+                    ::drpc::Conn::invoke(&mut self.conn, #desc::#konst.as_bytes(), input).await
+                }
+            },
+            Kind::Streaming { send, recv } => quote! {
+                pub async fn #ident(
+                    &mut self,
+                ) -> ::drpc::stream::Result<Box<dyn ::drpc::Stream<#recv, #send> + '_>> {
+                    ::drpc::Conn::stream(&mut self.conn, #desc::#konst.as_bytes()).await
+                }
+            },
+        }
+    });
+
+    quote! {
+        #vis struct #client<C> {
+            conn: C,
+        }
+
+        impl<C: ::drpc::Conn> #client<C> {
+            pub fn new(conn: C) -> Self {
+                #client { conn }
+            }
+
+            pub fn conn(&mut self) -> &mut C {
+                &mut self.conn
+            }
+
+            pub fn into_inner(self) -> C {
+                self.conn
+            }
+
+            #(#calls)*
+        }
+    }
+}
+
+fn expand_server(item: &ItemTrait, methods: &[Method]) -> TokenStream {
+    let vis = &item.vis;
+    let ident = &item.ident;
+    let server = format_ident!("{}Server", item.ident);
+
+    let arms = methods.iter().map(|m| {
+        let (method, rpc) = (&m.ident, LitByteStr::new(m.rpc.as_bytes(), m.ident.span()));
+        let body = match &m.kind {
+            Kind::Unitary { input, .. } => quote! {
+                let mut input = <#input as ::core::default::Default>::default();
+                ::drpc::StreamRecv::recv_into(st, &mut input).await?;
+                let output = match self.inner.#method(input).await {
+                    ::core::result::Result::Ok(output) => output,
+                    ::core::result::Result::Err(err) => {
+                        let msg = ::std::string::ToString::to_string(&err);
+                        return st.error(&msg, ::drpc::server::ERROR_CODE).await;
+                    }
+                };
+                ::drpc::StreamSend::send(st, &output).await?;
+            },
+            Kind::Streaming { .. } => quote! {
+                self.inner.#method(st).await?;
+            },
+        };
+        quote! {
+            #rpc => {
+                #body
+                st.close_send().await
+            }
+        }
+    });
+
+    quote! {
+        #vis struct #server<T> {
+            inner: ::std::sync::Arc<T>,
+        }
+
+        impl<T> #server<T> {
+            pub fn new(inner: T) -> Self {
+                #server { inner: ::std::sync::Arc::new(inner) }
+            }
+
+            pub fn from_arc(inner: ::std::sync::Arc<T>) -> Self {
+                #server { inner }
+            }
+        }
+
+        impl<T> ::core::clone::Clone for #server<T> {
+            fn clone(&self) -> Self {
+                #server { inner: ::std::sync::Arc::clone(&self.inner) }
+            }
+        }
+
+        #[::drpc::async_trait]
+        impl<T> ::drpc::server::Mux for #server<T>
+        where
+            T: #ident + ::core::marker::Send + ::core::marker::Sync + 'static,
+        {
+            async fn serve<'a>(
+                &self,
+                rpc: &[u8],
+                st: &mut ::drpc::stream::Stream<'a>,
+            ) -> ::drpc::stream::Result<()> {
+                match rpc {
+                    #(#arms)*
+                    _ => {
+                        let msg = ::std::format!(
+                            "unknown rpc: {:?}",
+                            ::std::string::String::from_utf8_lossy(rpc)
+                        );
+                        st.error(&msg, ::drpc::server::ERROR_CODE).await
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,21 @@
+#![no_main]
+
+use drpc::wire::frame;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = data;
+
+    while let Ok((fr, n)) = frame::parse_frame(buf) {
+        assert!(n <= buf.len());
+        assert!(fr.data.len() < n);
+
+        // varints may be encoded with redundant bytes, so only the parsed
+        // frame and not its encoding has to survive a round trip.
+        let mut out = Vec::new();
+        frame::append_frame(&mut out, &fr);
+        assert_eq!(frame::parse_frame(&out), Ok((fr, out.len())));
+
+        buf = &buf[n..];
+    }
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+use drpc::{transport, Transport};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let wire = tokio::io::join(data, tokio::io::sink());
+        let mut tr = transport::Transport::new(wire);
+        let mut buf = Vec::new();
+        let mut total = 0;
+
+        while tr.read_packet_into(&mut buf).await.is_ok() {
+            // packets only ever hold bytes from the input, so the memory they
+            // use is bounded by it.
+            total += buf.len();
+            assert!(total <= data.len());
+            assert!(buf.len() <= 4 << 20);
+        }
+    });
+});
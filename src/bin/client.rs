@@ -1,20 +1,440 @@
-use drpc::{conn, transport, Conn};
+use drpc::{conn, metadata, stream, transport, StreamRecv, StreamSend};
 
+use base64::Engine;
+use clap::{Parser, ValueEnum};
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+type Error = Box<dyn std::error::Error>;
+
+#[derive(Parser)]
+#[command(about = "Invokes an rpc on a drpc server")]
+struct Args {
+    /// Address to dial: host:port for tcp or unix:/path for a unix socket.
+    addr: String,
+
+    /// Name of the rpc, like /package.Service/Method.
+    rpc: String,
+
+    /// Input message. Read from --file or stdin when not given.
+    #[arg(short, long, conflicts_with = "file")]
+    data: Option<String>,
+
+    /// File to read the input message from.
+    #[arg(long)]
+    file: Option<PathBuf>,
+
+    /// Encoding of the input and output messages.
+    #[arg(short, long, value_enum, default_value_t = Format::Hex)]
+    format: Format,
+
+    /// Serialized FileDescriptorSet containing the service, needed for json.
+    #[arg(long)]
+    descriptor_set: Option<PathBuf>,
+
+    /// Streaming mode. With client or bidi every stdin line is sent as a
+    /// message; with any mode every received message is printed on a line.
+    /// Raw messages can not be streamed.
+    #[arg(long, value_enum)]
+    stream: Option<Mode>,
+
+    /// Metadata to send with the invoke as key=value. Can be repeated.
+    #[arg(short = 'H', long = "metadata", value_parser = parse_metadata)]
+    metadata: Vec<(String, String)>,
+
+    /// Seconds to wait for the rpc to finish.
+    #[arg(long, value_parser = parse_secs)]
+    timeout: Option<Duration>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Hex,
+    Base64,
+    Raw,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Mode {
+    Client,
+    Server,
+    Bidi,
+}
+
+fn parse_metadata(kv: &str) -> Result<(String, String), String> {
+    match kv.split_once('=') {
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => Err(format!("metadata {:?} is not of the form key=value", kv)),
+    }
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|err| format!("{}", err))?;
+    Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())
+}
 
 #[tokio::main]
-async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let rpc = "/sesamestreet.CookieMonster/EatCookie".as_bytes();
+async fn main() {
+    let args = Args::parse();
 
-    let mut socket = TcpStream::connect("localhost:8080").await?;
-    socket.set_nodelay(true)?;
-    let mut conn = conn::Conn::new(transport::Transport::new(&mut socket));
+    let res = match args.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, run(&args)).await {
+            Ok(res) => res,
+            Err(_) => Err("rpc timed out".into()),
+        },
+        None => run(&args).await,
+    };
+
+    if let Err(err) = res {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: &Args) -> Result<(), Error> {
+    let codec = Codec::new(args)?;
+    let md: metadata::Metadata = args.metadata.iter().cloned().collect();
+
+    // raw messages carry no delimiter, so they can neither be read from
+    // stdin lines nor printed on them.
+    if args.stream.is_some() && args.format == Format::Raw {
+        return Err("raw messages can not be streamed".into());
+    }
+
+    if let Some(Mode::Client) | Some(Mode::Bidi) = args.stream {
+        let wire = dial(&args.addr).await?;
+        let mut conn = conn::Conn::new(transport::SplitTransport::new(wire, Default::default()));
+        let mut st = open(&mut conn, args, &md).await?;
+        return stream_lines(&mut st, &codec).await;
+    }
+
+    let input = read_input(args, &codec)?;
+    let wire = dial(&args.addr).await?;
+    let mut conn = conn::Conn::new(transport::Transport::new(wire));
+    let mut st = open(&mut conn, args, &md).await?;
+
+    st.send(&input).await.map_err(rpc_error)?;
+    st.close_send().await.map_err(rpc_error)?;
 
-    let mut out: Vec<u8> = conn.invoke(rpc, &vec![8, 128, 10]).await?;
-    println!("{:?}", &out);
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let mut out = Vec::new();
+    loop {
+        match st.recv_into(&mut out).await {
+            Ok(()) => codec.write(&mut stdout, &out)?,
+            Err(stream::Error::StateError(stream::State::EOF)) if args.stream.is_some() => break,
+            Err(err) => return Err(rpc_error(err)),
+        }
+        stdout.flush()?;
 
-    conn.invoke_into(rpc, &vec![8, 10], &mut out).await?;
-    println!("{:?}", &out);
+        if args.stream.is_none() {
+            break;
+        }
+    }
 
+    st.close().await.map_err(rpc_error)?;
     Ok(())
 }
+
+async fn open<'c, T: drpc::Transport>(
+    conn: &'c mut conn::Conn<T>,
+    args: &Args,
+    md: &metadata::Metadata,
+) -> Result<stream::Stream<'c>, Error> {
+    let rpc = args.rpc.as_bytes();
+    let st = if md.is_empty() {
+        conn.stream(rpc).await
+    } else {
+        conn.stream_with_metadata(rpc, md).await
+    };
+    st.map_err(rpc_error)
+}
+
+// stream_lines sends every stdin line as a message as soon as it is read,
+// and prints the received messages meanwhile.
+async fn stream_lines(st: &mut stream::Stream<'_>, codec: &Codec) -> Result<(), Error> {
+    let mut lines = read_lines();
+    let mut sending = true;
+
+    let stdout = std::io::stdout();
+    let mut out = Vec::new();
+    loop {
+        tokio::select! {
+            line = lines.recv(), if sending => match line.transpose()? {
+                Some(line) if line.trim().is_empty() => (),
+                Some(line) => {
+                    st.send(&codec.read(line.as_bytes())?).await.map_err(rpc_error)?;
+                    st.transport().flush().await.map_err(stream::Error::from).map_err(rpc_error)?;
+                }
+                None => {
+                    st.close_send().await.map_err(rpc_error)?;
+                    sending = false;
+                }
+            },
+            res = st.recv_into(&mut out) => match res {
+                Ok(()) => {
+                    let mut stdout = stdout.lock();
+                    codec.write(&mut stdout, &out)?;
+                    stdout.flush()?;
+                }
+                Err(stream::Error::StateError(stream::State::EOF)) => break,
+                Err(err) => return Err(rpc_error(err)),
+            },
+        }
+    }
+
+    st.close().await.map_err(rpc_error)?;
+    Ok(())
+}
+
+// read_lines reads stdin on its own thread, as reads of stdin can not be
+// cancelled and would keep the runtime from shutting down.
+fn read_lines() -> mpsc::Receiver<std::io::Result<String>> {
+    let (tx, rx) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            if tx.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+fn rpc_error(err: stream::Error) -> Error {
+    match err {
+        stream::Error::StateError(stream::State::RemoteError((code, msg))) => {
+            format!("remote error (code {}): {}", code, msg).into()
+        }
+        err => err.into(),
+    }
+}
+
+async fn dial(addr: &str) -> Result<Box<dyn drpc::Wire>, Error> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return Ok(Box::new(tokio::net::UnixStream::connect(path).await?));
+    }
+
+    let socket = TcpStream::connect(addr).await?;
+    socket.set_nodelay(true)?;
+    Ok(Box::new(socket))
+}
+
+fn read_input(args: &Args, codec: &Codec) -> Result<Vec<u8>, Error> {
+    let data = match (&args.data, &args.file) {
+        (Some(data), _) => data.as_bytes().to_vec(),
+        (None, Some(path)) => std::fs::read(path)?,
+        (None, None) => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data)?;
+            data
+        }
+    };
+    codec.read(&data)
+}
+
+// codec
+
+struct Codec {
+    format: Format,
+    messages: Option<(MessageDescriptor, MessageDescriptor)>,
+}
+
+impl Codec {
+    fn new(args: &Args) -> Result<Codec, Error> {
+        let messages = match &args.descriptor_set {
+            Some(path) => Some(lookup_messages(&std::fs::read(path)?, &args.rpc)?),
+            None => None,
+        };
+        if args.format == Format::Json && messages.is_none() {
+            return Err("json messages require --descriptor-set".into());
+        }
+
+        Ok(Codec {
+            format: args.format,
+            messages,
+        })
+    }
+
+    fn read(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let text = || std::str::from_utf8(data).map(str::trim);
+
+        match (self.format, &self.messages) {
+            (Format::Raw, _) => Ok(data.to_vec()),
+            (Format::Hex, _) => Ok(hex::decode(text()?)?),
+            (Format::Base64, _) => Ok(base64::engine::general_purpose::STANDARD.decode(text()?)?),
+            (Format::Json, Some((input, _))) => {
+                let mut de = serde_json::Deserializer::from_str(text()?);
+                let msg = DynamicMessage::deserialize(input.clone(), &mut de)?;
+                de.end()?;
+                Ok(prost_reflect::prost::Message::encode_to_vec(&msg))
+            }
+            (Format::Json, None) => Err("json messages require --descriptor-set".into()),
+        }
+    }
+
+    fn write(&self, w: &mut impl Write, data: &[u8]) -> Result<(), Error> {
+        match (self.format, &self.messages) {
+            (Format::Raw, _) => w.write_all(data)?,
+            (Format::Hex, _) => writeln!(w, "{}", hex::encode(data))?,
+            (Format::Base64, _) => writeln!(
+                w,
+                "{}",
+                base64::engine::general_purpose::STANDARD.encode(data)
+            )?,
+            (Format::Json, Some((_, output))) => {
+                let msg = DynamicMessage::decode(output.clone(), data)?;
+                writeln!(w, "{}", serde_json::to_string(&msg)?)?
+            }
+            (Format::Json, None) => return Err("json messages require --descriptor-set".into()),
+        }
+        Ok(())
+    }
+}
+
+fn lookup_messages(fds: &[u8], rpc: &str) -> Result<(MessageDescriptor, MessageDescriptor), Error> {
+    let pool = DescriptorPool::decode(fds)?;
+
+    let (service, method) = rpc
+        .trim_start_matches('/')
+        .split_once('/')
+        .ok_or_else(|| format!("rpc {:?} is not of the form /package.Service/Method", rpc))?;
+
+    let service = pool
+        .get_service_by_name(service)
+        .ok_or_else(|| format!("service {:?} not found in descriptor set", service))?;
+    let method = service
+        .methods()
+        .find(|m| m.name() == method)
+        .ok_or_else(|| format!("method {:?} not found in descriptor set", method))?;
+
+    Ok((method.input(), method.output()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup_messages, parse_metadata, parse_secs, Codec, Format};
+
+    use prost_reflect::prost::Message;
+    use prost_reflect::prost_types::{
+        field_descriptor_proto, DescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+        FileDescriptorSet, MethodDescriptorProto, ServiceDescriptorProto,
+    };
+    use std::time::Duration;
+
+    // descriptor_set describes the service pkg.Svc with the method Call
+    // taking and returning a pkg.Msg { string name = 1; }.
+    fn descriptor_set() -> Vec<u8> {
+        let field = FieldDescriptorProto {
+            name: Some("name".into()),
+            number: Some(1),
+            label: Some(field_descriptor_proto::Label::Optional as i32),
+            r#type: Some(field_descriptor_proto::Type::String as i32),
+            json_name: Some("name".into()),
+            ..Default::default()
+        };
+        let method = MethodDescriptorProto {
+            name: Some("Call".into()),
+            input_type: Some(".pkg.Msg".into()),
+            output_type: Some(".pkg.Msg".into()),
+            ..Default::default()
+        };
+        let file = FileDescriptorProto {
+            name: Some("pkg.proto".into()),
+            package: Some("pkg".into()),
+            message_type: vec![DescriptorProto {
+                name: Some("Msg".into()),
+                field: vec![field],
+                ..Default::default()
+            }],
+            service: vec![ServiceDescriptorProto {
+                name: Some("Svc".into()),
+                method: vec![method],
+                ..Default::default()
+            }],
+            syntax: Some("proto3".into()),
+            ..Default::default()
+        };
+        FileDescriptorSet { file: vec![file] }.encode_to_vec()
+    }
+
+    fn codec(format: Format) -> Codec {
+        let messages = match format {
+            Format::Json => Some(lookup_messages(&descriptor_set(), "/pkg.Svc/Call").unwrap()),
+            _ => None,
+        };
+        Codec { format, messages }
+    }
+
+    fn roundtrip(format: Format, input: &str) -> (Vec<u8>, String) {
+        let codec = codec(format);
+        let data = codec.read(input.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        codec.write(&mut out, &data).unwrap();
+        (data, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn codec_roundtrip() {
+        assert_eq!(
+            roundtrip(Format::Hex, "0a0268690a\n"),
+            (vec![0x0a, 0x02, 0x68, 0x69, 0x0a], "0a0268690a\n".into())
+        );
+        assert_eq!(
+            roundtrip(Format::Base64, " CgJoaQ==\n"),
+            (vec![0x0a, 0x02, 0x68, 0x69], "CgJoaQ==\n".into())
+        );
+        assert_eq!(
+            roundtrip(Format::Json, r#"{"name": "hi"}"#),
+            (vec![0x0a, 0x02, 0x68, 0x69], "{\"name\":\"hi\"}\n".into())
+        );
+
+        assert!(codec(Format::Hex).read(b"0g").is_err());
+        assert!(codec(Format::Base64).read(b"!!").is_err());
+        assert!(codec(Format::Json).read(br#"{"name": 1}"#).is_err());
+        assert!(codec(Format::Json).read(br#"{} {}"#).is_err());
+    }
+
+    #[test]
+    fn metadata_args() {
+        assert_eq!(parse_metadata("k=v"), Ok(("k".into(), "v".into())));
+        assert_eq!(parse_metadata("k=v=w"), Ok(("k".into(), "v=w".into())));
+        assert_eq!(parse_metadata("k="), Ok(("k".into(), "".into())));
+        assert_eq!(
+            parse_metadata("kv"),
+            Err("metadata \"kv\" is not of the form key=value".into())
+        );
+    }
+
+    #[test]
+    fn secs_args() {
+        assert_eq!(parse_secs("1.5"), Ok(Duration::from_millis(1500)));
+        assert!(parse_secs("-1").is_err());
+        assert!(parse_secs("inf").is_err());
+        assert!(parse_secs("soon").is_err());
+    }
+
+    #[test]
+    fn missing_rpc() {
+        let fds = descriptor_set();
+        let err = |rpc| lookup_messages(&fds, rpc).unwrap_err().to_string();
+        assert_eq!(
+            err("/pkg.Svc/Missing"),
+            "method \"Missing\" not found in descriptor set"
+        );
+        assert_eq!(
+            err("/pkg.Missing/Call"),
+            "service \"pkg.Missing\" not found in descriptor set"
+        );
+        assert_eq!(
+            err("Call"),
+            "rpc \"Call\" is not of the form /package.Service/Method"
+        );
+    }
+}
@@ -0,0 +1,601 @@
+use drpc::metadata;
+use drpc::wire::{frame, id, packet};
+
+use clap::{Parser, ValueEnum};
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+type Error = Box<dyn std::error::Error>;
+
+#[derive(Parser)]
+#[command(about = "Decodes a drpc byte stream into frames and packets")]
+struct Args {
+    /// File holding the bytes sent in one direction of a connection, like a
+    /// tcp payload extracted from a pcap. Read from stdin when not given.
+    file: Option<PathBuf>,
+
+    /// Encoding of the input bytes.
+    #[arg(short, long, value_enum, default_value_t = Input::Raw)]
+    input: Input,
+
+    /// How to print message payloads.
+    #[arg(short, long, value_enum, default_value_t = Payload::None)]
+    payload: Payload,
+
+    /// Only print reassembled packets, not every frame.
+    #[arg(long)]
+    packets: bool,
+
+    /// Serialized FileDescriptorSet used to decode protobuf payloads.
+    #[arg(long)]
+    descriptor_set: Option<PathBuf>,
+
+    /// Fully qualified protobuf message type of the payloads. When not given
+    /// it is looked up from the rpc invoked on the stream of every payload.
+    #[arg(long)]
+    message_type: Option<String>,
+
+    /// Which side sent the bytes, deciding whether the rpc input or output
+    /// type decodes payloads.
+    #[arg(long, value_enum, default_value_t = Side::Client)]
+    from: Side,
+
+    /// File holding the bytes the client sent on the same connection, read
+    /// for the rpcs invoked on every stream. Decoding protobuf payloads sent
+    /// by the server needs either this or --message-type.
+    #[arg(long)]
+    client_bytes: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Input {
+    Raw,
+    Hex,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Payload {
+    None,
+    Hex,
+    Proto,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Side {
+    Client,
+    Server,
+}
+
+fn main() {
+    if let Err(err) = run(&Args::parse()) {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Error> {
+    let data = read_bytes(args.file.as_deref(), args.input)?;
+
+    let pool = match &args.descriptor_set {
+        Some(path) => Some(DescriptorPool::decode(&*std::fs::read(path)?)?),
+        None => None,
+    };
+    if args.payload == Payload::Proto && pool.is_none() {
+        return Err("protobuf payloads require --descriptor-set".into());
+    }
+
+    // the bytes of the server hold no invokes to look up message types from.
+    let rpcs = match &args.client_bytes {
+        Some(path) => invokes(&read_bytes(Some(path), args.input)?)?,
+        None if args.payload == Payload::Proto
+            && args.from == Side::Server
+            && args.message_type.is_none() =>
+        {
+            return Err(
+                "protobuf payloads from the server require --message-type or --client-bytes".into(),
+            );
+        }
+        None => HashMap::new(),
+    };
+
+    let stdout = std::io::stdout();
+    let mut dumper = Dumper::new(args, pool, rpcs, stdout.lock());
+    dumper.dump(&data)
+}
+
+// read_bytes reads the file, or stdin when there is none, decoding it as the
+// input says.
+fn read_bytes(path: Option<&Path>, input: Input) -> Result<Vec<u8>, Error> {
+    let mut data = match path {
+        Some(path) => std::fs::read(path)?,
+        None => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data)?;
+            data
+        }
+    };
+    if input == Input::Hex {
+        let text = String::from_utf8(data)?;
+        data = hex::decode(text.replace(|c: char| c.is_whitespace() || c == ':', ""))?;
+    }
+    Ok(data)
+}
+
+// invokes returns the rpc invoked on every stream in the bytes a client sent.
+fn invokes(mut buf: &[u8]) -> Result<HashMap<u64, String>, Error> {
+    let mut rpcs = HashMap::new();
+    let mut pending: Option<Pending> = None;
+
+    while !buf.is_empty() {
+        let (fr, n) = match frame::parse_frame(buf) {
+            Ok(v) => v,
+            Err(frame::Error::NotEnoughData) => break,
+            Err(frame::Error::ParseError) => return Err("invalid frame in --client-bytes".into()),
+        };
+        buf = &buf[n..];
+
+        let kind = packet::Kind::from(fr.kind);
+        if fr.control || kind != packet::Kind::Invoke {
+            continue;
+        }
+        match &mut pending {
+            Some(pkt) if pkt.id == fr.id => pkt.data.extend_from_slice(fr.data),
+            pending => {
+                *pending = Some(Pending {
+                    id: fr.id,
+                    kind,
+                    data: fr.data.to_vec(),
+                })
+            }
+        }
+        if fr.done {
+            if let Some(pkt) = pending.take() {
+                let rpc = String::from_utf8_lossy(&pkt.data).to_string();
+                rpcs.insert(pkt.id.stream, rpc);
+            }
+        }
+    }
+    Ok(rpcs)
+}
+
+// dumper
+
+struct Pending {
+    id: id::ID,
+    kind: packet::Kind,
+    data: Vec<u8>,
+}
+
+// dumper writes a line for every frame and packet in the bytes to out.
+struct Dumper<'a, W> {
+    args: &'a Args,
+    pool: Option<DescriptorPool>,
+    // the rpc invoked on every stream.
+    rpcs: HashMap<u64, String>,
+    pending: Option<Pending>,
+    out: W,
+}
+
+impl<'a, W: Write> Dumper<'a, W> {
+    fn new(
+        args: &'a Args,
+        pool: Option<DescriptorPool>,
+        rpcs: HashMap<u64, String>,
+        out: W,
+    ) -> Dumper<'a, W> {
+        Dumper {
+            args,
+            pool,
+            rpcs,
+            pending: None,
+            out,
+        }
+    }
+
+    fn dump(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        let mut offset = 0;
+
+        while !buf.is_empty() {
+            let (fr, n) = match frame::parse_frame(buf) {
+                Ok(v) => v,
+                Err(frame::Error::NotEnoughData) => {
+                    writeln!(
+                        self.out,
+                        "trailing  off={} len={} incomplete frame",
+                        offset,
+                        buf.len()
+                    )?;
+                    break;
+                }
+                Err(frame::Error::ParseError) => {
+                    return Err(format!("invalid frame at offset {}", offset).into());
+                }
+            };
+
+            if !self.args.packets {
+                writeln!(
+                    self.out,
+                    "frame     off={} stream={} message={} kind={:?} done={} control={} len={}",
+                    offset,
+                    fr.id.stream,
+                    fr.id.message,
+                    packet::Kind::from(fr.kind),
+                    fr.done,
+                    fr.control,
+                    fr.data.len(),
+                )?;
+            }
+            if !fr.control {
+                self.frame(&fr)?;
+            }
+
+            buf = &buf[n..];
+            offset += n;
+        }
+
+        if let Some(pending) = &self.pending {
+            writeln!(
+                self.out,
+                "pending   stream={} message={} kind={:?} len={} incomplete packet",
+                pending.id.stream,
+                pending.id.message,
+                pending.kind,
+                pending.data.len(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self, fr: &frame::Frame<'_>) -> Result<(), Error> {
+        let kind = packet::Kind::from(fr.kind);
+
+        match &mut self.pending {
+            Some(pending) if pending.id == fr.id && pending.kind == kind => {
+                pending.data.extend_from_slice(fr.data)
+            }
+            pending => {
+                if let Some(old) = pending {
+                    writeln!(
+                        self.out,
+                        "dropped   stream={} message={} kind={:?} len={} incomplete packet",
+                        old.id.stream,
+                        old.id.message,
+                        old.kind,
+                        old.data.len(),
+                    )?;
+                }
+                *pending = Some(Pending {
+                    id: fr.id,
+                    kind,
+                    data: fr.data.to_vec(),
+                });
+            }
+        }
+
+        if fr.done {
+            if let Some(pkt) = self.pending.take() {
+                self.packet(pkt)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn packet(&mut self, pkt: Pending) -> Result<(), Error> {
+        let detail = match pkt.kind {
+            packet::Kind::Invoke => {
+                let rpc = String::from_utf8_lossy(&pkt.data).to_string();
+                self.rpcs.insert(pkt.id.stream, rpc.clone());
+                format!(" rpc={:?}", rpc)
+            }
+            packet::Kind::InvokeMetadata => match metadata::read(&pkt.data) {
+                Ok(md) => format!(" metadata={:?}", md),
+                Err(err) => format!(" metadata error={}", err),
+            },
+            packet::Kind::Error if pkt.data.len() >= 8 => {
+                let (code, msg) = pkt.data.split_at(8);
+                format!(
+                    " code={} error={:?}",
+                    u64::from_be_bytes(code.try_into().unwrap()),
+                    String::from_utf8_lossy(msg),
+                )
+            }
+            packet::Kind::Message => self.payload(pkt.id.stream, &pkt.data)?,
+            _ => String::new(),
+        };
+
+        writeln!(
+            self.out,
+            "packet    stream={} message={} kind={:?} len={}{}",
+            pkt.id.stream,
+            pkt.id.message,
+            pkt.kind,
+            pkt.data.len(),
+            detail,
+        )?;
+        Ok(())
+    }
+
+    fn payload(&self, stream: u64, data: &[u8]) -> Result<String, Error> {
+        match self.args.payload {
+            Payload::None => Ok(String::new()),
+            Payload::Hex => Ok(format!(" data={}", hex::encode(data))),
+            Payload::Proto => match self.message_type(stream)? {
+                Some(desc) => match DynamicMessage::decode(desc, data) {
+                    Ok(msg) => Ok(format!(" data={}", serde_json::to_string(&msg)?)),
+                    Err(err) => Ok(format!(" decode error={}", err)),
+                },
+                None => Ok(format!(" data={}", hex::encode(data))),
+            },
+        }
+    }
+
+    fn message_type(&self, stream: u64) -> Result<Option<MessageDescriptor>, Error> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Ok(None),
+        };
+
+        if let Some(name) = &self.args.message_type {
+            return match pool.get_message_by_name(name) {
+                Some(desc) => Ok(Some(desc)),
+                None => Err(format!("message {:?} not found in descriptor set", name).into()),
+            };
+        }
+
+        let rpc = match self.rpcs.get(&stream) {
+            Some(rpc) => rpc,
+            None => return Ok(None),
+        };
+        let method = rpc
+            .trim_start_matches('/')
+            .split_once('/')
+            .and_then(|(service, method)| {
+                pool.get_service_by_name(service)?
+                    .methods()
+                    .find(|m| m.name() == method)
+            });
+
+        Ok(method.map(|m| match self.args.from {
+            Side::Client => m.input(),
+            Side::Server => m.output(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{invokes, Args, Dumper};
+
+    use clap::Parser;
+    use drpc::wire::{frame, id::ID, packet};
+    use prost_reflect::prost::Message;
+    use prost_reflect::prost_types::{
+        field_descriptor_proto, DescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+        FileDescriptorSet, MethodDescriptorProto, ServiceDescriptorProto,
+    };
+    use prost_reflect::DescriptorPool;
+    use std::collections::HashMap;
+
+    // pool describes the service pkg.Svc with the method Call taking a
+    // pkg.In { string name = 1; } and returning a pkg.Out { int32 count = 1; }.
+    fn pool() -> DescriptorPool {
+        let message = |name: &str, field: &str, ty| DescriptorProto {
+            name: Some(name.into()),
+            field: vec![FieldDescriptorProto {
+                name: Some(field.into()),
+                number: Some(1),
+                label: Some(field_descriptor_proto::Label::Optional as i32),
+                r#type: Some(ty as i32),
+                json_name: Some(field.into()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let method = MethodDescriptorProto {
+            name: Some("Call".into()),
+            input_type: Some(".pkg.In".into()),
+            output_type: Some(".pkg.Out".into()),
+            ..Default::default()
+        };
+        let file = FileDescriptorProto {
+            name: Some("pkg.proto".into()),
+            package: Some("pkg".into()),
+            message_type: vec![
+                message("In", "name", field_descriptor_proto::Type::String),
+                message("Out", "count", field_descriptor_proto::Type::Int32),
+            ],
+            service: vec![ServiceDescriptorProto {
+                name: Some("Svc".into()),
+                method: vec![method],
+                ..Default::default()
+            }],
+            syntax: Some("proto3".into()),
+            ..Default::default()
+        };
+        let fds = FileDescriptorSet { file: vec![file] }.encode_to_vec();
+        DescriptorPool::decode(&*fds).unwrap()
+    }
+
+    fn append(buf: &mut Vec<u8>, data: &[u8], id: ID, kind: packet::Kind, done: bool) {
+        let fr = frame::Frame {
+            data,
+            id,
+            kind: kind.into(),
+            done,
+            control: false,
+        };
+        frame::append_frame(buf, &fr);
+    }
+
+    fn append_ping(buf: &mut Vec<u8>) {
+        let fr = frame::Frame {
+            data: &[1],
+            id: ID::default(),
+            kind: 1,
+            done: true,
+            control: true,
+        };
+        frame::append_frame(buf, &fr);
+    }
+
+    fn dump(
+        args: &[&str],
+        pool: Option<DescriptorPool>,
+        rpcs: HashMap<u64, String>,
+        data: &[u8],
+    ) -> Vec<String> {
+        let args = Args::try_parse_from([&["dump"], args].concat()).unwrap();
+        let mut out = Vec::new();
+        Dumper::new(&args, pool, rpcs, &mut out).dump(data).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn split_frames() {
+        let mut data = Vec::new();
+        append(
+            &mut data,
+            b"/pkg.Svc/",
+            ID::new(1, 1),
+            packet::Kind::Invoke,
+            false,
+        );
+        append(
+            &mut data,
+            b"Call",
+            ID::new(1, 1),
+            packet::Kind::Invoke,
+            true,
+        );
+        append(
+            &mut data,
+            b"ab",
+            ID::new(1, 2),
+            packet::Kind::Message,
+            false,
+        );
+        append_ping(&mut data);
+        append(&mut data, b"cd", ID::new(1, 2), packet::Kind::Message, true);
+
+        // the control frame is listed but not part of the message.
+        let lines = dump(&["-p", "hex"], None, HashMap::new(), &data);
+        assert_eq!(
+            lines,
+            [
+                "frame     off=0 stream=1 message=1 kind=Invoke done=false control=false len=9",
+                "frame     off=13 stream=1 message=1 kind=Invoke done=true control=false len=4",
+                "packet    stream=1 message=1 kind=Invoke len=13 rpc=\"/pkg.Svc/Call\"",
+                "frame     off=21 stream=1 message=2 kind=Message done=false control=false len=2",
+                "frame     off=27 stream=0 message=0 kind=Invoke done=true control=true len=1",
+                "frame     off=32 stream=1 message=2 kind=Message done=true control=false len=2",
+                "packet    stream=1 message=2 kind=Message len=4 data=61626364",
+            ]
+        );
+
+        let lines = dump(&["--packets"], None, HashMap::new(), &data);
+        assert_eq!(
+            lines,
+            [
+                "packet    stream=1 message=1 kind=Invoke len=13 rpc=\"/pkg.Svc/Call\"",
+                "packet    stream=1 message=2 kind=Message len=4",
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut data = Vec::new();
+        append(
+            &mut data,
+            b"ab",
+            ID::new(1, 1),
+            packet::Kind::Message,
+            false,
+        );
+        let partial = data.len();
+        append(&mut data, b"cd", ID::new(1, 1), packet::Kind::Message, true);
+        data.truncate(data.len() - 1);
+
+        let lines = dump(&["--packets"], None, HashMap::new(), &data);
+        assert_eq!(
+            lines,
+            [
+                format!("trailing  off={} len=5 incomplete frame", partial),
+                "pending   stream=1 message=1 kind=Message len=2 incomplete packet".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn server_capture() {
+        // the client invokes Call on stream 1 in two frames around a ping.
+        let mut client = Vec::new();
+        append(
+            &mut client,
+            b"/pkg.Svc/",
+            ID::new(1, 1),
+            packet::Kind::Invoke,
+            false,
+        );
+        append_ping(&mut client);
+        append(
+            &mut client,
+            b"Call",
+            ID::new(1, 1),
+            packet::Kind::Invoke,
+            true,
+        );
+        append(
+            &mut client,
+            b"\x0a\x02hi",
+            ID::new(1, 2),
+            packet::Kind::Message,
+            true,
+        );
+        let rpcs = invokes(&client).unwrap();
+        assert_eq!(rpcs.len(), 1);
+        assert_eq!(rpcs[&1], "/pkg.Svc/Call");
+
+        // the server answers with an Out, which does not decode as an In.
+        let mut server = Vec::new();
+        append(
+            &mut server,
+            b"\x08\x03",
+            ID::new(1, 1),
+            packet::Kind::Message,
+            true,
+        );
+        let args = ["--packets", "-p", "proto", "--from", "server"];
+        let lines = dump(&args, Some(pool()), rpcs.clone(), &server);
+        assert_eq!(
+            lines,
+            ["packet    stream=1 message=1 kind=Message len=2 data={\"count\":3}"]
+        );
+
+        // the client bytes decode as inputs of the same rpc.
+        let lines = dump(
+            &["--packets", "-p", "proto"],
+            Some(pool()),
+            HashMap::new(),
+            &client,
+        );
+        assert_eq!(
+            lines[1],
+            "packet    stream=1 message=2 kind=Message len=4 data={\"name\":\"hi\"}"
+        );
+
+        // streams without a known rpc fall back to hex.
+        let lines = dump(&args, Some(pool()), HashMap::new(), &server);
+        assert_eq!(
+            lines,
+            ["packet    stream=1 message=1 kind=Message len=2 data=0803"]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use drpc::{conn, stream, transport, StreamRecv, StreamSend};
+
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+type Conn = conn::Conn<transport::Transport<Box<dyn drpc::Wire>>>;
+
+#[derive(Parser)]
+#[command(about = "Generates load against a drpc server and reports latencies")]
+struct Args {
+    /// Address to dial: host:port for tcp or unix:/path for a unix socket.
+    addr: String,
+
+    /// Name of the rpc, like /package.Service/Method.
+    rpc: String,
+
+    /// Number of connections to open.
+    #[arg(short, long, default_value_t = 1)]
+    connections: usize,
+
+    /// Number of rpcs in flight. Connections serve one rpc at a time, so
+    /// rpcs queue when this exceeds the number of connections.
+    #[arg(long)]
+    concurrency: Option<usize>,
+
+    /// Target rpcs per second across all workers. Unlimited when not given.
+    #[arg(short, long, value_parser = parse_rate)]
+    rate: Option<f64>,
+
+    /// Seconds to generate load for.
+    #[arg(short, long, default_value = "10", value_parser = parse_secs)]
+    duration: Duration,
+
+    /// Stop after this many rpcs instead of after the duration.
+    #[arg(short = 'n', long)]
+    requests: Option<u64>,
+
+    /// Input message as hex. Defaults to --size zero bytes.
+    #[arg(long)]
+    data: Option<String>,
+
+    /// Size of the zeroed input message when --data is not given.
+    #[arg(long, default_value_t = 0)]
+    size: usize,
+
+    /// Send this many messages per rpc and read every response until the
+    /// server closes, instead of doing unitary rpcs.
+    #[arg(long)]
+    stream: Option<usize>,
+
+    /// Seconds before a single rpc is considered failed.
+    #[arg(long, value_parser = parse_secs)]
+    timeout: Option<Duration>,
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|err| format!("{}", err))?;
+    Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        Ok(_) => Err("rate must be positive".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run(Args::parse()).await {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn dial(addr: &str) -> Result<Conn, Error> {
+    let wire: Box<dyn drpc::Wire> = match addr.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        _ => {
+            let socket = TcpStream::connect(addr).await?;
+            socket.set_nodelay(true)?;
+            Box::new(socket)
+        }
+    };
+    Ok(conn::Conn::new(transport::Transport::new(wire)))
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+    let input = match &args.data {
+        Some(data) => hex::decode(data)?,
+        None => vec![0; args.size],
+    };
+
+    let mut conns = Vec::new();
+    for _ in 0..args.connections.max(1) {
+        conns.push(Mutex::new(dial(&args.addr).await?));
+    }
+
+    let concurrency = args.concurrency.unwrap_or(conns.len()).max(1);
+    let load = Arc::new(Load {
+        conns,
+        input,
+        issued: AtomicU64::new(0),
+        deadline: Instant::now().checked_add(args.duration),
+        args,
+    });
+
+    let start = Instant::now();
+    let workers: Vec<_> = (0..concurrency)
+        .map(|i| tokio::spawn(load.clone().worker(i, concurrency)))
+        .collect();
+
+    let mut report = Report::default();
+    for worker in workers {
+        report.merge(worker.await?);
+    }
+    report.print(start.elapsed());
+
+    Ok(())
+}
+
+// load
+
+struct Load {
+    args: Args,
+    conns: Vec<Mutex<Conn>>,
+    input: Vec<u8>,
+    issued: AtomicU64,
+    // none if the duration is too long to be reached.
+    deadline: Option<Instant>,
+}
+
+impl Load {
+    fn done(&self) -> bool {
+        match self.args.requests {
+            Some(total) => self.issued.fetch_add(1, Ordering::Relaxed) >= total,
+            None => self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline),
+        }
+    }
+
+    async fn worker(self: Arc<Self>, i: usize, concurrency: usize) -> Report {
+        let mut report = Report::default();
+        let mut ticker = self.args.rate.map(|rate| {
+            // very high rates round down to no wait, and very low rates
+            // beyond what a duration holds never tick again.
+            let period = Duration::try_from_secs_f64(concurrency as f64 / rate)
+                .unwrap_or(Duration::MAX)
+                .max(Duration::from_nanos(1));
+            let mut ticker = tokio::time::interval(period);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker
+        });
+
+        let conn = &self.conns[i % self.conns.len()];
+        let mut out = Vec::new();
+
+        loop {
+            if let Some(ticker) = &mut ticker {
+                ticker.tick().await;
+            }
+            if self.done() {
+                return report;
+            }
+
+            // the latency of an rpc starts once it has a connection, and does
+            // not include waiting for the rpcs queued before it.
+            let mut conn = conn.lock().await;
+            let start = Instant::now();
+            let res = match self.args.timeout {
+                Some(timeout) => {
+                    let rpc = self.request(&mut conn, &mut out);
+                    match tokio::time::timeout(timeout, rpc).await {
+                        Ok(res) => res.map_err(Failure::Rpc),
+                        Err(_) => Err(Failure::Timeout),
+                    }
+                }
+                None => self
+                    .request(&mut conn, &mut out)
+                    .await
+                    .map_err(Failure::Rpc),
+            };
+            report.record(start.elapsed(), res.as_ref().err());
+
+            // the connection is unusable after transport failures or a timed
+            // out rpc, so replace it before the next rpc.
+            if let Err(failure) = res {
+                if failure.is_fatal() {
+                    match dial(&self.args.addr).await {
+                        Ok(fresh) => *conn = fresh,
+                        Err(_) => {
+                            report.count("Dial");
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn request(&self, conn: &mut Conn, out: &mut Vec<u8>) -> stream::Result<()> {
+        let rpc = self.args.rpc.as_bytes();
+
+        let count = match self.args.stream {
+            Some(count) => count,
+            None => return conn.invoke_into(rpc, &self.input, out).await,
+        };
+
+        let mut st = conn.stream(rpc).await?;
+        for _ in 0..count {
+            st.send(&self.input).await?;
+        }
+        st.close_send().await?;
+        loop {
+            match st.recv_into(out).await {
+                Ok(()) => (),
+                Err(stream::Error::StateError(stream::State::EOF)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        st.close().await
+    }
+}
+
+// report
+
+enum Failure {
+    Rpc(stream::Error),
+    Timeout,
+}
+
+impl Failure {
+    fn is_fatal(&self) -> bool {
+        match self {
+            Failure::Rpc(err) => matches!(
+                err,
+                stream::Error::TransportError(_) | stream::Error::IOError(_)
+            ),
+            Failure::Timeout => true,
+        }
+    }
+
+    fn label(&self) -> String {
+        let err = match self {
+            Failure::Rpc(err) => err,
+            Failure::Timeout => return "Timeout".into(),
+        };
+        match err {
+            stream::Error::StateError(stream::State::RemoteError((code, _))) => {
+                format!("StateError(RemoteError({}))", code)
+            }
+            stream::Error::StateError(state) => format!("StateError({:?})", state),
+            stream::Error::TransportError(err) => format!("TransportError({:?})", err.kind()),
+            stream::Error::IOError(err) => format!("IOError({:?})", err.kind()),
+            stream::Error::EncodingError(_) => "EncodingError".into(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    errors: BTreeMap<String, u64>,
+}
+
+impl Report {
+    fn record(&mut self, latency: Duration, failure: Option<&Failure>) {
+        match failure {
+            Some(failure) => self.count(&failure.label()),
+            None => self.latencies.push(latency),
+        }
+    }
+
+    fn count(&mut self, label: &str) {
+        *self.errors.entry(label.to_string()).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        for (label, count) in other.errors {
+            *self.errors.entry(label).or_default() += count;
+        }
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        let idx = (p / 100.0 * (self.latencies.len() - 1) as f64).round() as usize;
+        self.latencies[idx]
+    }
+
+    fn print(mut self, elapsed: Duration) {
+        self.latencies.sort();
+
+        let ok = self.latencies.len() as u64;
+        let failed: u64 = self.errors.values().sum();
+        println!("requests:   {} ok, {} failed", ok, failed);
+        println!("elapsed:    {:.3?}", elapsed);
+        println!(
+            "throughput: {:.1} rpc/s",
+            ok as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+        );
+        println!("latency:");
+        for (name, p) in &[("p50", 50.0), ("p90", 90.0), ("p99", 99.0)] {
+            println!("  {}: {:.3?}", name, self.percentile(*p));
+        }
+        println!(
+            "  max: {:.3?}",
+            self.latencies.last().copied().unwrap_or_default()
+        );
+
+        if !self.errors.is_empty() {
+            println!("errors:");
+            for (label, count) in &self.errors {
+                println!("  {}: {}", label, count);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rate, parse_secs, Report};
+
+    use std::time::Duration;
+
+    fn report(millis: &[u64]) -> Report {
+        Report {
+            latencies: millis.iter().map(|&ms| Duration::from_millis(ms)).collect(),
+            ..Report::default()
+        }
+    }
+
+    #[test]
+    fn percentile() {
+        let ms = Duration::from_millis;
+
+        let empty = report(&[]);
+        for &p in &[0.0, 50.0, 100.0] {
+            assert_eq!(empty.percentile(p), Duration::ZERO);
+        }
+
+        let single = report(&[7]);
+        for &p in &[0.0, 50.0, 99.0, 100.0] {
+            assert_eq!(single.percentile(p), ms(7));
+        }
+
+        // percentile takes sorted latencies, as print sorts them first.
+        let five = report(&[1, 2, 3, 4, 5]);
+        assert_eq!(five.percentile(0.0), ms(1));
+        assert_eq!(five.percentile(50.0), ms(3));
+        assert_eq!(five.percentile(90.0), ms(5));
+        assert_eq!(five.percentile(100.0), ms(5));
+
+        let hundred = report(&(1..=100).collect::<Vec<_>>());
+        assert_eq!(hundred.percentile(99.0), ms(99));
+        assert_eq!(hundred.percentile(100.0), ms(100));
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = report(&[1]);
+        a.count("Timeout");
+        let mut b = report(&[2, 3]);
+        b.count("Timeout");
+        b.count("EncodingError");
+
+        a.merge(b);
+        assert_eq!(a.latencies.len(), 3);
+        assert_eq!(a.errors["Timeout"], 2);
+        assert_eq!(a.errors["EncodingError"], 1);
+    }
+
+    #[test]
+    fn duration_args() {
+        assert_eq!(parse_secs("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_secs("0.25"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_secs("0"), Ok(Duration::ZERO));
+        assert!(parse_secs("-1").is_err());
+        assert!(parse_secs("NaN").is_err());
+        assert!(parse_secs("1e30").is_err());
+        assert!(parse_secs("10s").is_err());
+    }
+
+    #[test]
+    fn rate_args() {
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+        assert_eq!(parse_rate("0"), Err("rate must be positive".into()));
+        assert_eq!(parse_rate("inf"), Err("rate must be positive".into()));
+        assert!(parse_rate("fast").is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use drpc::proxy;
+
+use clap::Parser;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+#[derive(Parser)]
+#[command(about = "Proxies drpc connections to backends chosen by rpc name")]
+struct Args {
+    /// Address to listen on: host:port for tcp or unix:/path for a unix socket.
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Route as pattern=backend, like /billing.*=127.0.0.1:9000 or
+    /// *=unix:/run/default.sock. Patterns ending in * match by prefix and the
+    /// first matching route wins.
+    #[arg(short, long = "route", value_parser = parse_route, required = true)]
+    routes: Vec<(String, String)>,
+}
+
+fn parse_route(route: &str) -> Result<(String, String), String> {
+    match route.split_once('=') {
+        Some((pattern, backend)) => Ok((pattern.to_string(), backend.to_string())),
+        None => Err(format!(
+            "route {:?} is not of the form pattern=backend",
+            route
+        )),
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let mut p = proxy::Proxy::new();
+    for (pattern, backend) in &args.routes {
+        p = match backend.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => p.route(pattern, proxy::UnixDialer(path.into())),
+            _ => p.route(pattern, proxy::TcpDialer(backend.clone())),
+        };
+    }
+    let p = Arc::new(p);
+
+    #[cfg(unix)]
+    if let Some(path) = args.listen.strip_prefix("unix:") {
+        let lis = tokio::net::UnixListener::bind(path)?;
+        eprintln!("listening on {}", args.listen);
+        p.run(lis).await?;
+        return Ok(());
+    }
+
+    let lis = TcpListener::bind(&args.listen).await?;
+    eprintln!("listening on {}", lis.local_addr()?);
+    p.run(lis).await?;
+    Ok(())
+}
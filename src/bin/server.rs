@@ -1,61 +1,397 @@
-use drpc::{enc, server, stream};
+use drpc::{server, stream, transport, StreamRecv, StreamSend};
 
 use async_trait::async_trait;
+use clap::{Parser, Subcommand};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpListener;
 
+#[derive(Parser)]
+#[command(about = "Serves configurable test rpcs over drpc")]
+struct Args {
+    /// Address to listen on: host:port for tcp or unix:/path for a unix socket.
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Latency to inject before serving an rpc as rpc=duration, like
+    /// /pkg.Service/Method=50ms. Use * as the rpc to match every rpc.
+    #[arg(long, value_parser = parse_latency)]
+    latency: Vec<(String, Duration)>,
+
+    /// Probability to fail an rpc as rpc=rate, like /pkg.Service/Method=0.1.
+    /// Use * as the rpc to match every rpc.
+    #[arg(long, value_parser = parse_error_rate)]
+    error_rate: Vec<(String, f64)>,
+
+    /// Code sent with injected errors.
+    #[arg(long, default_value_t = 1)]
+    error_code: u64,
+
+    /// Seed deciding which rpcs fail.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Seconds between keepalive pings on idle connections. Connections of
+    /// clients that answer pings are closed when nothing arrives for three
+    /// intervals after the first ping.
+    #[arg(long, value_parser = parse_interval)]
+    keepalive: Option<Duration>,
+
+    /// Seconds a new connection may take to invoke its first rpc.
+    #[arg(long, value_parser = parse_secs)]
+    handshake_timeout: Option<Duration>,
+
+    /// Seconds a connection may stay idle between rpcs.
+    #[arg(long, value_parser = parse_secs)]
+    idle_timeout: Option<Duration>,
+
+    /// Seconds a read may wait for the rest of a partially received packet.
+    #[arg(long, value_parser = parse_secs)]
+    read_timeout: Option<Duration>,
+
+    /// Seconds after which the requests of streams are cancelled.
+    #[arg(long, value_parser = parse_secs)]
+    stream_timeout: Option<Duration>,
+
+    /// Connections served at once. Further connections wait to be accepted.
+    #[arg(long, value_parser = parse_limit)]
+    max_connections: Option<usize>,
+
+    /// Close connections past --max-connections instead of waiting.
+    #[arg(long, requires = "max_connections")]
+    reject_overflow: bool,
+
+    /// Connections served at once from the same IP address. Further
+    /// connections are closed.
+    #[arg(long, value_parser = parse_limit)]
+    max_connections_per_ip: Option<usize>,
+
+    /// Serve the streams of a connection concurrently, at most this many at
+    /// once. Streams are served one at a time otherwise.
+    #[arg(long, value_parser = parse_limit)]
+    max_concurrent_streams: Option<usize>,
+
+    /// Print every rpc that is served.
+    #[arg(short, long)]
+    verbose: bool,
+
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Clone, Subcommand)]
+enum Mode {
+    /// Reply to every message with the message itself.
+    Echo,
+    /// Read every message and reply with a single empty message.
+    Discard,
+    /// Read every message and reply with a single message of a fixed size.
+    Fixed {
+        #[arg(long, default_value_t = 0)]
+        size: usize,
+    },
+    /// Read every message and reply with a stream of messages.
+    Fanout {
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+        #[arg(long, default_value_t = 0)]
+        size: usize,
+    },
+}
+
+fn parse_rule(rule: &str) -> Result<(&str, &str), String> {
+    rule.split_once('=')
+        .ok_or_else(|| format!("{:?} is not of the form rpc=value", rule))
+}
+
+fn parse_latency(rule: &str) -> Result<(String, Duration), String> {
+    let (rpc, value) = parse_rule(rule)?;
+    let (num, unit) = value.split_at(value.trim_end_matches(char::is_alphabetic).len());
+    let num: f64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", value))?;
+    let secs = match unit {
+        "us" => num / 1e6,
+        "ms" | "" => num / 1e3,
+        "s" => num,
+        _ => return Err(format!("invalid duration unit {:?}", unit)),
+    };
+    let latency = Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())?;
+    Ok((rpc.to_string(), latency))
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|err| format!("{}", err))?;
+    Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())
+}
+
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    match parse_secs(s)? {
+        interval if interval.is_zero() => Err("interval must be positive".to_string()),
+        interval => Ok(interval),
+    }
+}
+
+fn parse_limit(s: &str) -> Result<usize, String> {
+    match s.parse().map_err(|err| format!("{}", err))? {
+        0 => Err("limit must be positive".to_string()),
+        n => Ok(n),
+    }
+}
+
+fn parse_error_rate(rule: &str) -> Result<(String, f64), String> {
+    let (rpc, value) = parse_rule(rule)?;
+    match value.parse() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok((rpc.to_string(), rate)),
+        _ => Err(format!("invalid error rate {:?}", value)),
+    }
+}
+
 #[tokio::main]
 async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let lis = TcpListener::bind("127.0.0.1:8080").await?;
-    server::run(lis, EchoMux {}).await?;
+    let args = Args::parse();
+
+    let mux = TestMux {
+        mode: args.mode.clone(),
+        faults: Arc::new(Faults {
+            latency: args.latency.iter().cloned().collect(),
+            error_rate: args.error_rate.iter().cloned().collect(),
+            error_code: args.error_code,
+            rng: Mutex::new(StdRng::seed_from_u64(args.seed)),
+        }),
+        verbose: args.verbose,
+    };
+
+    let mut opts = server::Options {
+        handshake_timeout: args.handshake_timeout,
+        idle_timeout: args.idle_timeout,
+        read_timeout: args.read_timeout,
+        stream_timeout: args.stream_timeout,
+        max_connections: args.max_connections,
+        max_connections_per_ip: args.max_connections_per_ip,
+        max_concurrent_streams: args.max_concurrent_streams,
+        ..server::Options::default()
+    };
+    if let Some(interval) = args.keepalive {
+        opts.keepalive = Some(transport::Keepalive {
+            interval,
+            timeout: interval.saturating_mul(3),
+        });
+    }
+
+    if args.reject_overflow {
+        opts.overflow = server::Overflow::Reject;
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = args.listen.strip_prefix("unix:") {
+        let lis = bind_unix(path)?;
+        eprintln!("listening on {}", args.listen);
+        server::run_with_options(lis, mux, opts).await?;
+        return Ok(());
+    }
+
+    let lis = TcpListener::bind(&args.listen).await?;
+    eprintln!("listening on {}", lis.local_addr()?);
+    server::run_with_options(lis, mux, opts).await?;
     Ok(())
 }
 
+// bind_unix binds to the socket path, removing the socket file of a server
+// that is gone first. paths that are not sockets or that a server listens on
+// are left alone.
+#[cfg(unix)]
+fn bind_unix(path: &str) -> std::io::Result<tokio::net::UnixListener> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            let msg = format!("{} exists and is not a socket", path);
+            return Err(Error::new(ErrorKind::AlreadyExists, msg));
+        }
+        Ok(_) if std::os::unix::net::UnixStream::connect(path).is_ok() => {
+            let msg = format!("{} is in use by another server", path);
+            return Err(Error::new(ErrorKind::AddrInUse, msg));
+        }
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+// faults
+
+struct Faults {
+    latency: HashMap<String, Duration>,
+    error_rate: HashMap<String, f64>,
+    error_code: u64,
+    rng: Mutex<StdRng>,
+}
+
+fn lookup<'a, V>(rules: &'a HashMap<String, V>, rpc: &str) -> Option<&'a V> {
+    rules.get(rpc).or_else(|| rules.get("*"))
+}
+
+impl Faults {
+    fn latency(&self, rpc: &str) -> Option<Duration> {
+        lookup(&self.latency, rpc).copied()
+    }
+
+    fn should_fail(&self, rpc: &str) -> bool {
+        match lookup(&self.error_rate, rpc) {
+            Some(&rate) => self.rng.lock().unwrap().gen_bool(rate),
+            None => false,
+        }
+    }
+}
+
+// mux
+
 #[derive(Clone)]
-struct EchoMux;
+struct TestMux {
+    mode: Mode,
+    faults: Arc<Faults>,
+    verbose: bool,
+}
+
+async fn recv_all(st: &mut stream::Stream<'_>, buf: &mut Vec<u8>) -> stream::Result<()> {
+    loop {
+        match st.recv_into(buf).await {
+            Ok(()) => (),
+            Err(stream::Error::StateError(stream::State::EOF)) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
 
 #[async_trait]
-impl server::Mux for EchoMux {
+impl server::Mux for TestMux {
     async fn serve<'a>(&self, rpc: &[u8], st: &mut stream::Stream<'a>) -> stream::Result<()> {
-        println!("{:?}: {:?}", st.id(), String::from_utf8_lossy(rpc));
+        let rpc = String::from_utf8_lossy(rpc);
+        if self.verbose {
+            eprintln!("{}: {}", st.id(), rpc);
+        }
 
-        rpc1(st).await?;
-        rpc2(st).await?;
+        if let Some(latency) = self.faults.latency(&rpc) {
+            tokio::time::sleep(latency).await;
+        }
+        if self.faults.should_fail(&rpc) {
+            return st.error("injected error", self.faults.error_code).await;
+        }
 
-        println!("done");
+        let mut buf = Vec::new();
+        match self.mode {
+            Mode::Echo => loop {
+                match st.recv_into(&mut buf).await {
+                    Ok(()) => st.send(&buf).await?,
+                    Err(stream::Error::StateError(stream::State::EOF)) => break,
+                    Err(err) => return Err(err),
+                }
+            },
+            Mode::Discard => {
+                recv_all(st, &mut buf).await?;
+                st.send(&Vec::new()).await?;
+            }
+            Mode::Fixed { size } => {
+                recv_all(st, &mut buf).await?;
+                st.send(&vec![0; size]).await?;
+            }
+            Mode::Fanout { count, size } => {
+                recv_all(st, &mut buf).await?;
+                let out = vec![0; size];
+                for _ in 0..count {
+                    st.send(&out).await?;
+                }
+            }
+        }
 
-        Ok(())
+        st.close_send().await
     }
 }
 
-struct Foo;
+#[cfg(test)]
+mod tests {
+    use super::{parse_error_rate, parse_interval, parse_latency, parse_limit, parse_secs};
+
+    use std::time::Duration;
+
+    #[test]
+    fn latency_args() {
+        let latency = |rule| parse_latency(rule).map(|(rpc, d)| (rpc, d.as_micros()));
+        assert_eq!(latency("/a/b=50ms"), Ok(("/a/b".into(), 50_000)));
+        assert_eq!(latency("*=50"), Ok(("*".into(), 50_000)));
+        assert_eq!(latency("*=1.5s"), Ok(("*".into(), 1_500_000)));
+        assert_eq!(latency("*=20us"), Ok(("*".into(), 20)));
+        assert_eq!(latency("/a=b=1s"), Err("invalid duration \"b=1s\"".into()));
 
-impl enc::Marshal for Foo {
-    fn marshal(&self, _: &mut Vec<u8>) -> enc::Result<()> {
-        Ok(())
+        assert_eq!(
+            latency("/a/b"),
+            Err("\"/a/b\" is not of the form rpc=value".into())
+        );
+        assert_eq!(latency("*=1m"), Err("invalid duration unit \"m\"".into()));
+        assert_eq!(latency("*=fast"), Err("invalid duration \"fast\"".into()));
+        assert!(latency("*=-1ms").is_err());
+        assert!(latency("*=NaNs").is_err());
     }
-}
 
-impl enc::Unmarshal for Foo {
-    fn unmarshal(&mut self, _: &[u8]) -> enc::Result<()> {
-        Ok(())
+    #[test]
+    fn secs_args() {
+        assert_eq!(parse_secs("0"), Ok(Duration::ZERO));
+        assert_eq!(parse_secs("2.5"), Ok(Duration::from_millis(2500)));
+        assert!(parse_secs("-1").is_err());
+        assert!(parse_secs("inf").is_err());
+
+        assert_eq!(parse_interval("1"), Ok(Duration::from_secs(1)));
+        assert_eq!(parse_interval("0"), Err("interval must be positive".into()));
     }
-}
 
-async fn rpc1<S>(s: &mut S) -> stream::Result<()>
-where
-    S: drpc::Stream<Foo, Foo>,
-{
-    s.recv_into(&mut Foo {}).await?;
-    s.send(&Foo {}).await?;
-    Ok(())
-}
+    #[test]
+    fn limit_args() {
+        assert_eq!(parse_limit("3"), Ok(3));
+        assert_eq!(parse_limit("0"), Err("limit must be positive".into()));
+        assert!(parse_limit("-1").is_err());
+    }
 
-async fn rpc2<S>(s: &mut S) -> stream::Result<()>
-where
-    S: drpc::Stream<Vec<u8>, Vec<u8>>,
-{
-    let mut i = vec![];
-    s.recv_into(&mut i).await?;
-    // s.send(&vec![1, 2, 3]).await?;
-    Ok(())
+    #[test]
+    fn error_rate_args() {
+        assert_eq!(parse_error_rate("/a/b=0.1"), Ok(("/a/b".into(), 0.1)));
+        assert_eq!(parse_error_rate("*=1"), Ok(("*".into(), 1.0)));
+        assert_eq!(
+            parse_error_rate("*=1.5"),
+            Err("invalid error rate \"1.5\"".into())
+        );
+        assert!(parse_error_rate("*=NaN").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stale_socket() {
+        let dir = std::env::temp_dir().join(format!("drpc-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sock");
+        let path = path.to_str().unwrap();
+
+        // a server listening on the path keeps it.
+        let lis = super::bind_unix(path).unwrap();
+        let err = super::bind_unix(path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+        // the socket file outlives the listener and is replaced.
+        drop(lis);
+        assert!(std::path::Path::new(path).exists());
+        drop(super::bind_unix(path).unwrap());
+
+        // other files are not removed.
+        std::fs::remove_file(path).unwrap();
+        std::fs::write(path, b"data").unwrap();
+        let err = super::bind_unix(path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(path).unwrap(), b"data");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
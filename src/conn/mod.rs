@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 
-use crate::{enc, stream, Transport};
+use crate::{enc, metadata, stream, transport, Transport};
 
 use crate::{StreamRecv, StreamSend};
 
@@ -8,20 +8,25 @@ pub struct Conn<T: crate::Transport> {
     sid: u64,
     tr: T,
     buf: Vec<u8>,
+    opts: transport::TransportOptions,
 }
 
 impl<T: crate::Transport> Conn<T> {
+    /// Returns a connection over the transport, whose streams split packets
+    /// into frames as the options of the transport say.
     pub fn new(tr: T) -> Conn<T> {
+        let opts = tr.options();
         Conn {
             sid: 0,
             tr,
             buf: Vec::new(),
+            opts,
         }
     }
 
     fn new_stream<'s>(&'s mut self) -> stream::Stream<'s> {
         self.sid += 1;
-        stream::Stream::new(self.sid, &mut self.tr, &mut self.buf)
+        stream::Stream::with_options(self.sid, &mut self.tr, &mut self.buf, &self.opts)
     }
 
     pub fn transport(&mut self) -> &mut T {
@@ -48,6 +53,16 @@ impl<T: crate::Transport> Conn<T> {
         st.invoke(rpc).await?;
         Ok(st)
     }
+
+    pub async fn stream_with_metadata<'s>(
+        &'s mut self,
+        rpc: &[u8],
+        md: &metadata::Metadata,
+    ) -> stream::Result<stream::Stream<'s>> {
+        let mut st = self.new_stream();
+        st.invoke_with_metadata(rpc, md).await?;
+        Ok(st)
+    }
 }
 
 #[async_trait]
@@ -23,3 +23,16 @@ impl Unmarshal for Vec<u8> {
         Ok(())
     }
 }
+
+impl Marshal for () {
+    fn marshal(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        Ok(())
+    }
+}
+
+impl Unmarshal for () {
+    fn unmarshal(&mut self, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,13 +1,20 @@
-use async_trait::async_trait;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 pub mod conn;
 pub mod enc;
+pub mod metadata;
+pub mod pool;
+pub mod proxy;
+pub mod record;
 pub mod server;
 pub mod stream;
+pub mod testing;
 pub mod transport;
 pub mod wire;
 
+pub use async_trait::async_trait;
+pub use drpc_macros::{service, ProstMessage};
+
 pub trait Wire: Unpin + Send + AsyncRead + AsyncWrite {}
 
 impl<T> Wire for T where T: Unpin + Send + AsyncRead + AsyncWrite {}
@@ -22,6 +29,27 @@ pub trait Transport: Send {
     ) -> transport::Result<(wire::id::ID, wire::packet::Kind)>;
     async fn write_frame(&mut self, fr: wire::frame::Frame<'_>) -> transport::Result<()>;
     async fn flush(&mut self) -> transport::Result<()>;
+
+    /// Drops what was written since the last flush, for when the writer was
+    /// interrupted, as by a panic. Returns false if a write to the wire was
+    /// interrupted midway, which leaves the wire unusable. Transports that
+    /// buffer nothing return true.
+    fn discard(&mut self) -> bool {
+        true
+    }
+
+    /// Returns a snapshot of what the transport has read and written.
+    /// Transports that keep no statistics return zeros.
+    fn stats(&self) -> transport::Stats {
+        transport::Stats::default()
+    }
+
+    /// Returns the options the transport was created with, which streams
+    /// over it split packets by. Transports without options return the
+    /// defaults.
+    fn options(&self) -> transport::TransportOptions {
+        transport::TransportOptions::default()
+    }
 }
 
 #[async_trait]
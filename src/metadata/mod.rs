@@ -0,0 +1,177 @@
+use crate::wire::varint;
+
+use std::collections::BTreeMap;
+
+// metadata is sent in an InvokeMetadata packet before the Invoke packet. it
+// is encoded like the protobuf message `message Metadata { map<string,
+// string> data = 1; }` to stay compatible with other drpc implementations.
+
+pub type Metadata = BTreeMap<String, String>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    ParseError,
+    InvalidUtf8,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<varint::Error> for Error {
+    fn from(_: varint::Error) -> Error {
+        Error::ParseError
+    }
+}
+
+fn append_bytes(buf: &mut Vec<u8>, tag: u8, data: &[u8]) {
+    buf.push(tag);
+    varint::append(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+pub fn append(buf: &mut Vec<u8>, md: &Metadata) {
+    let mut entry = Vec::new();
+    for (key, value) in md {
+        entry.clear();
+        append_bytes(&mut entry, 0x0a, key.as_bytes());
+        append_bytes(&mut entry, 0x12, value.as_bytes());
+        append_bytes(buf, 0x0a, &entry);
+    }
+}
+
+// field is a protobuf field with its data if it is length delimited.
+struct Field<'a> {
+    num: u64,
+    data: Option<&'a [u8]>,
+}
+
+impl Field<'_> {
+    fn skipped(tag: u64) -> Self {
+        Field {
+            num: tag >> 3,
+            data: None,
+        }
+    }
+}
+
+// read_field returns the next field and the remaining buffer.
+fn read_field(buf: &[u8]) -> Result<(Field<'_>, &[u8]), Error> {
+    let (tag, n) = varint::read(buf)?;
+    let buf = &buf[n..];
+
+    match tag & 7 {
+        0 => {
+            let (_, n) = varint::read(buf)?;
+            Ok((Field::skipped(tag), &buf[n..]))
+        }
+        1 if buf.len() >= 8 => Ok((Field::skipped(tag), &buf[8..])),
+        2 => {
+            let (len, n) = varint::read(buf)?;
+            let buf = &buf[n..];
+            if len > buf.len() as u64 {
+                return Err(Error::ParseError);
+            }
+            let (data, rem) = buf.split_at(len as usize);
+            let num = tag >> 3;
+            Ok((
+                Field {
+                    num,
+                    data: Some(data),
+                },
+                rem,
+            ))
+        }
+        5 if buf.len() >= 4 => Ok((Field::skipped(tag), &buf[4..])),
+        _ => Err(Error::ParseError),
+    }
+}
+
+fn utf8(data: &[u8]) -> Result<String, Error> {
+    String::from_utf8(data.to_vec()).map_err(|_| Error::InvalidUtf8)
+}
+
+fn read_entry(mut buf: &[u8]) -> Result<(String, String), Error> {
+    let mut key = String::new();
+    let mut value = String::new();
+
+    while !buf.is_empty() {
+        let (field, rem) = read_field(buf)?;
+        buf = rem;
+
+        match (field.num, field.data) {
+            (1, Some(data)) => key = utf8(data)?,
+            (2, Some(data)) => value = utf8(data)?,
+            _ => (),
+        }
+    }
+
+    Ok((key, value))
+}
+
+pub fn read(mut buf: &[u8]) -> Result<Metadata, Error> {
+    let mut md = Metadata::new();
+
+    while !buf.is_empty() {
+        let (field, rem) = read_field(buf)?;
+        buf = rem;
+
+        if let (1, Some(data)) = (field.num, field.data) {
+            let (key, value) = read_entry(data)?;
+            md.insert(key, value);
+        }
+    }
+
+    Ok(md)
+}
+
+#[cfg(test)]
+mod tests {
+    fn md() -> super::Metadata {
+        let mut md = super::Metadata::new();
+        md.insert("a".into(), "bc".into());
+        md.insert("key".into(), "".into());
+        md
+    }
+
+    #[test]
+    fn append_read() {
+        let mut buf = vec![];
+        super::append(&mut buf, &md());
+
+        assert_eq!(
+            buf,
+            vec![
+                10, 7, 10, 1, b'a', 18, 2, b'b', b'c', //
+                10, 7, 10, 3, b'k', b'e', b'y', 18, 0,
+            ]
+        );
+        assert_eq!(super::read(&buf), Ok(md()));
+    }
+
+    #[test]
+    fn read_unknown_fields() {
+        let buf = [
+            8, 150, 1, // field 1 varint
+            10, 8, 10, 1, b'a', 24, 1, 18, 1, b'b', // entry with unknown field 3
+            21, 1, 2, 3, 4, // field 2 fixed32
+        ];
+
+        let mut md = super::Metadata::new();
+        md.insert("a".into(), "b".into());
+        assert_eq!(super::read(&buf), Ok(md));
+    }
+
+    #[test]
+    fn read_parse_error() {
+        assert_eq!(super::read(&[10, 5, 10]), Err(super::Error::ParseError));
+        assert_eq!(
+            super::read(&[10, 3, 10, 1, 255]),
+            Err(super::Error::InvalidUtf8)
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::sync::{Arc, Mutex};
+
+// buffers are kept in size classes by the power of two at or below their
+// capacity, so any buffer in a class is at least as large as the class. a
+// request is served from the smallest class guaranteed to be large enough,
+// which keeps large buffers away from connections that only need small ones.
+
+/// Pool hands out buffers and takes them back once they are no longer used,
+/// so that they can be reused by other connections.
+pub trait Pool: Send + Sync {
+    /// Returns an empty buffer with a capacity of at least min.
+    fn get(&self, min: usize) -> Vec<u8>;
+    /// Takes back a buffer. Its contents are not used.
+    fn put(&self, buf: Vec<u8>);
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Buffers handed out.
+    pub gets: u64,
+    /// Buffers handed out that were reused instead of allocated.
+    pub hits: u64,
+    /// Buffers taken back.
+    pub puts: u64,
+    /// Buffers taken back and dropped because they were too large or the
+    /// pool was full.
+    pub discards: u64,
+    pub retained_buffers: usize,
+    pub retained_bytes: usize,
+}
+
+struct Inner {
+    classes: Vec<Vec<Vec<u8>>>,
+    max_buffer_size: usize,
+    max_retained: usize,
+    stats: Stats,
+}
+
+/// BufferPool is a handle to a pool of buffers. Clones share the same pool.
+#[derive(Clone)]
+pub struct BufferPool(Arc<Mutex<Inner>>);
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(1 << 20, 64 << 20)
+    }
+}
+
+impl BufferPool {
+    /// Returns a pool that drops buffers with a capacity above
+    /// max_buffer_size, and buffers that would make the capacity of all
+    /// retained buffers exceed max_retained.
+    pub fn new(max_buffer_size: usize, max_retained: usize) -> BufferPool {
+        BufferPool(Arc::new(Mutex::new(Inner {
+            classes: vec![Vec::new(); class_of(max_buffer_size) + 1],
+            max_buffer_size,
+            max_retained,
+            stats: Stats::default(),
+        })))
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.0.lock().unwrap().stats
+    }
+}
+
+// class_of returns the class of a buffer with the capacity.
+fn class_of(capacity: usize) -> usize {
+    match capacity {
+        0 => 0,
+        n => (usize::BITS - 1 - n.leading_zeros()) as usize,
+    }
+}
+
+impl Pool for BufferPool {
+    fn get(&self, min: usize) -> Vec<u8> {
+        let mut inner = self.0.lock().unwrap();
+        inner.stats.gets += 1;
+
+        // no pooled buffer can be larger than the largest power of two, so
+        // anything above it is left to the allocator.
+        let first = match min.checked_next_power_of_two() {
+            Some(size) => class_of(size),
+            None => {
+                drop(inner);
+                return Vec::with_capacity(min);
+            }
+        };
+        let found = inner
+            .classes
+            .iter_mut()
+            .skip(first)
+            .find_map(|class| class.pop());
+
+        match found {
+            Some(buf) => {
+                inner.stats.hits += 1;
+                inner.stats.retained_buffers -= 1;
+                inner.stats.retained_bytes -= buf.capacity();
+                buf
+            }
+            None => Vec::with_capacity(min),
+        }
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.stats.puts += 1;
+
+        let size = buf.capacity();
+        if size == 0
+            || size > inner.max_buffer_size
+            || inner.stats.retained_bytes + size > inner.max_retained
+        {
+            inner.stats.discards += 1;
+            return;
+        }
+
+        buf.clear();
+        inner.classes[class_of(size)].push(buf);
+        inner.stats.retained_buffers += 1;
+        inner.stats.retained_bytes += size;
+    }
+}
+
+// acquire replaces buf with one from the pool if it has no capacity.
+pub(crate) fn acquire(pool: &Option<Arc<dyn Pool>>, buf: &mut Vec<u8>, min: usize) {
+    if let Some(pool) = pool {
+        if buf.capacity() == 0 {
+            *buf = pool.get(min);
+        }
+    }
+}
+
+// release gives buf back to the pool, leaving it without capacity.
+pub(crate) fn release(pool: &Option<Arc<dyn Pool>>, buf: &mut Vec<u8>) {
+    if let Some(pool) = pool {
+        if buf.capacity() > 0 {
+            pool.put(std::mem::take(buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, Pool, Stats};
+
+    #[test]
+    fn size_classes() {
+        let pool = BufferPool::new(1 << 20, 1 << 30);
+        pool.put(Vec::with_capacity(100));
+        pool.put(Vec::with_capacity(5000));
+
+        // 100 bytes are not guaranteed to hold 128, so the larger buffer is
+        // used.
+        assert!(pool.get(128).capacity() >= 5000);
+        assert!(pool.get(16).capacity() >= 100);
+        assert_eq!(pool.get(16).capacity(), 16);
+
+        assert_eq!(
+            pool.stats(),
+            Stats {
+                gets: 3,
+                hits: 2,
+                puts: 2,
+                ..Stats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let pool = BufferPool::new(1024, 2048);
+        pool.put(Vec::with_capacity(2000));
+        for _ in 0..3 {
+            pool.put(Vec::with_capacity(1000));
+        }
+        pool.put(Vec::new());
+
+        let stats = pool.stats();
+        assert_eq!((stats.puts, stats.discards), (5, 3));
+        assert_eq!(stats.retained_buffers, 2);
+        assert!(stats.retained_bytes <= 2048);
+    }
+
+    #[test]
+    fn oversized_get() {
+        // a request above the largest class is allocated rather than served
+        // from a smaller class, and is not kept when put back.
+        let pool = BufferPool::new(1024, 1 << 20);
+        pool.put(Vec::with_capacity(1000));
+        let buf = pool.get(4000);
+        assert!(buf.capacity() >= 4000);
+        pool.put(buf);
+
+        assert_eq!(
+            pool.stats(),
+            Stats {
+                gets: 1,
+                hits: 0,
+                puts: 2,
+                discards: 1,
+                retained_buffers: 1,
+                retained_bytes: 1000,
+            }
+        );
+    }
+}
@@ -0,0 +1,1263 @@
+use crate::transport::{self, ReadBuffer, TransportOptions, MAX_HEADER_SIZE};
+use crate::wire::{frame, id, packet};
+use crate::{server, stream};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::{net, task, time};
+
+// the proxy reads the rpc name out of each Invoke packet and then relays the
+// raw frames of that stream to and from the backend chosen for it, without
+// reassembling or decoding the other packets. control frames only concern a
+// single hop, so the proxy answers the pings of both sides itself. once the
+// client starts a new stream, the backend of the previous one is sent a
+// Close for it, and what it still sends on it is dropped.
+
+// dialers
+
+#[async_trait]
+pub trait Dialer: Send + Sync {
+    async fn dial(&self) -> io::Result<Box<dyn crate::Wire>>;
+}
+
+pub struct TcpDialer(pub String);
+
+#[async_trait]
+impl Dialer for TcpDialer {
+    async fn dial(&self) -> io::Result<Box<dyn crate::Wire>> {
+        let socket = net::TcpStream::connect(&self.0).await?;
+        socket.set_nodelay(true)?;
+        Ok(Box::new(socket))
+    }
+}
+
+#[cfg(unix)]
+pub struct UnixDialer(pub std::path::PathBuf);
+
+#[cfg(unix)]
+#[async_trait]
+impl Dialer for UnixDialer {
+    async fn dial(&self) -> io::Result<Box<dyn crate::Wire>> {
+        Ok(Box::new(net::UnixStream::connect(&self.0).await?))
+    }
+}
+
+// routes
+
+enum Pattern {
+    Exact(Vec<u8>),
+    Prefix(Vec<u8>),
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => Pattern::Prefix(prefix.as_bytes().to_vec()),
+            None => Pattern::Exact(pattern.as_bytes().to_vec()),
+        }
+    }
+
+    fn matches(&self, rpc: &[u8]) -> bool {
+        match self {
+            Pattern::Exact(exact) => rpc == &exact[..],
+            Pattern::Prefix(prefix) => rpc.starts_with(prefix),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Proxy {
+    routes: Vec<(Pattern, usize)>,
+    backends: Vec<Arc<dyn Dialer>>,
+    opts: TransportOptions,
+    backoff: server::Backoff,
+}
+
+impl Proxy {
+    pub fn new() -> Proxy {
+        Proxy::default()
+    }
+
+    /// Sends rpcs matching the pattern to the backend. Patterns ending in `*`
+    /// match by prefix, others match exactly, and the first matching route
+    /// wins.
+    pub fn route<D: Dialer + 'static>(mut self, pattern: &str, backend: D) -> Proxy {
+        self.routes
+            .push((Pattern::parse(pattern), self.backends.len()));
+        self.backends.push(Arc::new(backend));
+        self
+    }
+
+    /// Sets the sizes frames and invokes are limited to on both sides. Frames
+    /// are relayed without being split again, so the other options have no
+    /// effect.
+    pub fn transport_options(mut self, opts: TransportOptions) -> Proxy {
+        self.opts = opts;
+        self
+    }
+
+    /// Sets how long run waits after a transient accept error, like
+    /// server::Options::accept_backoff.
+    pub fn accept_backoff(mut self, backoff: server::Backoff) -> Proxy {
+        self.backoff = backoff;
+        self
+    }
+
+    fn lookup(&self, rpc: &[u8]) -> Option<usize> {
+        self.routes
+            .iter()
+            .find(|(pattern, _)| pattern.matches(rpc))
+            .map(|(_, backend)| *backend)
+    }
+
+    pub async fn run<L, W>(self: Arc<Self>, lis: L) -> stream::Result<()>
+    where
+        L: server::Listener<W>,
+        W: crate::Wire + 'static,
+    {
+        let mut backoff = None;
+
+        loop {
+            let wire = match lis.accept().await {
+                Ok(wire) => wire,
+                Err(err) if server::limits::transient(&err) => {
+                    let delay = self.backoff.next(backoff);
+                    backoff = Some(delay);
+                    time::sleep(delay).await;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            backoff = None;
+            let proxy = self.clone();
+            task::spawn(async move { proxy.handle(wire).await });
+        }
+    }
+
+    pub async fn handle<W: crate::Wire + 'static>(&self, wire: W) {
+        let (r, w) = io::split(wire);
+        let (out, out_rx) = mpsc::channel(64);
+        let (notices, mut notices_rx) = mpsc::unbounded_channel();
+        let writer = task::spawn(write_frames(w, out_rx));
+
+        let mut relay = Relay {
+            proxy: self,
+            out,
+            notices,
+            backends: HashMap::new(),
+            dialing: HashMap::new(),
+            generation: 0,
+            current: None,
+            sid: Arc::new(AtomicU64::new(0)),
+        };
+        let mut client = FrameReader::new(r, &self.opts);
+
+        loop {
+            let res = tokio::select! {
+                fr = client.next() => match fr {
+                    Ok(Some(fr)) => relay.client_frame(fr).await,
+                    _ => break,
+                },
+                Some(notice) = notices_rx.recv() => relay.notice(notice).await,
+            };
+            if res.is_err() {
+                break;
+            }
+        }
+
+        // the writers of the backends finish once their queues are dropped.
+        for (_, backend) in relay.backends.drain() {
+            backend.reader.abort();
+        }
+        for (_, dial) in relay.dialing.drain() {
+            dial.abort();
+        }
+        drop(relay);
+        let _ = writer.await;
+    }
+}
+
+// frames
+
+struct RawFrame {
+    id: id::ID,
+    kind: packet::Kind,
+    done: bool,
+    control: bool,
+    header: usize,
+    bytes: Vec<u8>,
+}
+
+impl RawFrame {
+    fn data(&self) -> &[u8] {
+        &self.bytes[self.header..]
+    }
+}
+
+struct FrameReader<R> {
+    r: R,
+    rbuf: ReadBuffer,
+    max_frame_size: usize,
+    read_chunk_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    fn new(r: R, opts: &TransportOptions) -> Self {
+        FrameReader {
+            r,
+            rbuf: ReadBuffer::default(),
+            max_frame_size: opts.max_frame_size,
+            read_chunk_size: opts.read_chunk_size,
+        }
+    }
+
+    // next is cancel safe: the only await point is a read into the spare
+    // capacity of the read buffer, which keeps the bytes only once it
+    // completes.
+    async fn next(&mut self) -> transport::Result<Option<RawFrame>> {
+        loop {
+            let unread = self.rbuf.unread();
+            if let Some((fr, n)) = transport::parse_frame(unread, self.max_frame_size)? {
+                let raw = RawFrame {
+                    id: fr.id,
+                    kind: fr.kind.into(),
+                    done: fr.done,
+                    control: fr.control,
+                    header: n - fr.data.len(),
+                    bytes: unread[..n].to_vec(),
+                };
+                self.rbuf.consume(n);
+                return Ok(Some(raw));
+            }
+
+            let mut spare = self.rbuf.spare(self.read_chunk_size);
+            match self.r.read_buf(&mut spare).await {
+                Ok(0) if self.rbuf.is_empty() => return Ok(None),
+                Ok(0) => {
+                    let err = io::ErrorKind::UnexpectedEof.into();
+                    return Err(transport::Error::io(transport::Op::Read, err));
+                }
+                Ok(_) => (),
+                Err(err) => return Err(transport::Error::io(transport::Op::Read, err)),
+            }
+        }
+    }
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    w: W,
+    mut frames: mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    let mut w = io::BufWriter::new(w);
+    while let Some(bytes) = frames.recv().await {
+        w.write_all(&bytes).await?;
+        while let Ok(bytes) = frames.try_recv() {
+            w.write_all(&bytes).await?;
+        }
+        w.flush().await?;
+    }
+    Ok(())
+}
+
+fn pong(ping: &RawFrame) -> Option<Vec<u8>> {
+    if u8::from(ping.kind) != transport::CONTROL_PING {
+        return None;
+    }
+    let mut buf = Vec::new();
+    let fr = transport::control_frame(transport::CONTROL_PONG, ping.data());
+    frame::append_frame(&mut buf, &fr);
+    Some(buf)
+}
+
+fn error_packet(id: id::ID, msg: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + msg.len());
+    data.extend_from_slice(&server::ERROR_CODE.to_be_bytes());
+    data.extend_from_slice(msg.as_bytes());
+
+    let mut buf = Vec::new();
+    frame::append_frame(
+        &mut buf,
+        &frame::Frame {
+            data: &data,
+            id,
+            kind: packet::Kind::Error.into(),
+            done: true,
+            control: false,
+        },
+    );
+    buf
+}
+
+// relay
+
+struct Notice {
+    backend: usize,
+    generation: u64,
+    event: Event,
+}
+
+enum Event {
+    // the pong answering a ping of the backend.
+    Pong(Vec<u8>),
+    // reading from or writing to the backend failed.
+    Closed,
+    // the backend was dialed.
+    Dialed(io::Result<Box<dyn crate::Wire>>),
+}
+
+struct Backend {
+    // the frames queued for the writer of the backend.
+    out: mpsc::Sender<Vec<u8>>,
+    generation: u64,
+    reader: task::JoinHandle<()>,
+    writer: task::JoinHandle<()>,
+    // the id of the last frame the reader relayed to the client.
+    last: Arc<Mutex<id::ID>>,
+}
+
+enum Route {
+    Pending { head: Vec<u8>, rpc: Vec<u8> },
+    // the frames of the stream are queued while its backend is dialed.
+    Dialing { backend: usize, queued: Vec<u8> },
+    Backend(usize),
+    Dropped,
+}
+
+struct Current {
+    sid: u64,
+    // the message id of the last frame of the stream read from the client.
+    message: u64,
+    route: Route,
+}
+
+struct Relay<'a> {
+    proxy: &'a Proxy,
+    out: mpsc::Sender<Vec<u8>>,
+    notices: mpsc::UnboundedSender<Notice>,
+    backends: HashMap<usize, Backend>,
+    // the tasks dialing backends.
+    dialing: HashMap<usize, task::JoinHandle<()>>,
+    generation: u64,
+    current: Option<Current>,
+    // the id of the current stream, shared with the readers of the backends.
+    sid: Arc<AtomicU64>,
+}
+
+// read_backend relays the frames of the current stream read from a backend
+// to the client, and drops those of earlier streams.
+async fn read_backend(
+    mut r: FrameReader<io::ReadHalf<Box<dyn crate::Wire>>>,
+    out: mpsc::Sender<Vec<u8>>,
+    notices: mpsc::UnboundedSender<Notice>,
+    backend: usize,
+    generation: u64,
+    sid: Arc<AtomicU64>,
+    last: Arc<Mutex<id::ID>>,
+) {
+    while let Ok(Some(fr)) = r.next().await {
+        if fr.control {
+            if let Some(pong) = pong(&fr) {
+                let event = Event::Pong(pong);
+                let _ = notices.send(Notice {
+                    backend,
+                    generation,
+                    event,
+                });
+            }
+            continue;
+        } else if fr.id.stream != sid.load(Ordering::Relaxed) {
+            continue;
+        }
+        *last.lock().unwrap() = fr.id;
+        if out.send(fr.bytes).await.is_err() {
+            return;
+        }
+    }
+    let _ = notices.send(Notice {
+        backend,
+        generation,
+        event: Event::Closed,
+    });
+}
+
+// write_backend writes the frames queued for a backend until the queue is
+// dropped or a write fails.
+async fn write_backend(
+    w: io::WriteHalf<Box<dyn crate::Wire>>,
+    frames: mpsc::Receiver<Vec<u8>>,
+    notices: mpsc::UnboundedSender<Notice>,
+    backend: usize,
+    generation: u64,
+) {
+    if write_frames(w, frames).await.is_err() {
+        let _ = notices.send(Notice {
+            backend,
+            generation,
+            event: Event::Closed,
+        });
+    }
+}
+
+// error_id returns the id of an error failing stream sid, after the last
+// frame relayed to the client from the backend.
+fn error_id(sid: u64, last: id::ID) -> id::ID {
+    match last.stream == sid {
+        true => id::ID::new(sid, last.message + 1),
+        false => id::ID::new(sid, 1),
+    }
+}
+
+impl<'a> Relay<'a> {
+    async fn client_frame(&mut self, fr: RawFrame) -> Result<(), ()> {
+        if fr.control {
+            return match pong(&fr) {
+                Some(pong) => self.out.send(pong).await.map_err(|_| ()),
+                None => Ok(()),
+            };
+        }
+
+        let sid = fr.id.stream;
+        match &self.current {
+            Some(cur) if cur.sid == sid => (),
+            Some(cur) if sid < cur.sid => return Ok(()),
+            _ => {
+                self.close_current().await;
+                self.sid.store(sid, Ordering::Relaxed);
+                self.current = Some(Current {
+                    sid,
+                    message: 0,
+                    route: Route::Pending {
+                        head: Vec::new(),
+                        rpc: Vec::new(),
+                    },
+                })
+            }
+        }
+
+        let cur = self.current.as_mut().unwrap();
+        cur.message = cur.message.max(fr.id.message);
+        match &mut cur.route {
+            Route::Pending { head, rpc } => {
+                head.extend_from_slice(&fr.bytes);
+                if fr.kind == packet::Kind::Invoke {
+                    rpc.extend_from_slice(fr.data());
+                }
+
+                // the head holds at most an InvokeMetadata and an Invoke
+                // packet.
+                let opts = &self.proxy.opts;
+                if head.len() > 2 * (opts.max_packet_size + MAX_HEADER_SIZE) {
+                    cur.route = Route::Dropped;
+                    return self.fail(fr.id, "invoke too large").await;
+                } else if fr.kind == packet::Kind::Invoke && fr.done {
+                    let (head, rpc) = (std::mem::take(head), std::mem::take(rpc));
+                    return self.route(sid, &rpc, head).await;
+                }
+                Ok(())
+            }
+            Route::Dialing { queued, .. } => {
+                // the queue holds at most the head, a message and a
+                // CloseSend packet.
+                queued.extend_from_slice(&fr.bytes);
+                let opts = &self.proxy.opts;
+                if queued.len() > 3 * (opts.max_packet_size + MAX_HEADER_SIZE) + MAX_HEADER_SIZE {
+                    cur.route = Route::Dropped;
+                    let id = error_id(sid, id::ID::default());
+                    return self
+                        .fail(id, "too much data before the backend is connected")
+                        .await;
+                }
+                Ok(())
+            }
+            Route::Backend(backend) => {
+                let backend = *backend;
+                self.write_backend(backend, fr.bytes).await
+            }
+            Route::Dropped => Ok(()),
+        }
+    }
+
+    // close_current sends a Close for the current stream to its backend,
+    // which would otherwise keep serving it after the client moved on.
+    async fn close_current(&mut self) {
+        let (id, backend) = match &self.current {
+            Some(Current {
+                sid,
+                message,
+                route: Route::Backend(backend),
+            }) => (id::ID::new(*sid, message + 1), *backend),
+            _ => return,
+        };
+
+        let mut buf = Vec::new();
+        frame::append_frame(
+            &mut buf,
+            &frame::Frame {
+                data: &[],
+                id,
+                kind: packet::Kind::Close.into(),
+                done: true,
+                control: false,
+            },
+        );
+        // a failed write is noticed by the writer.
+        if let Some(b) = self.backends.get(&backend) {
+            let _ = b.out.send(buf).await;
+        }
+    }
+
+    async fn route(&mut self, sid: u64, rpc: &[u8], head: Vec<u8>) -> Result<(), ()> {
+        let id = id::ID::new(sid, 1);
+        self.set_route(Route::Dropped);
+
+        let backend = match self.proxy.lookup(rpc) {
+            Some(backend) => backend,
+            None => {
+                let msg = format!("no route for rpc {:?}", String::from_utf8_lossy(rpc));
+                return self.fail(id, &msg).await;
+            }
+        };
+
+        if self.backends.contains_key(&backend) {
+            self.set_route(Route::Backend(backend));
+            return self.write_backend(backend, head).await;
+        }
+
+        // the backend is dialed in its own task, so that pings and later
+        // streams are relayed meanwhile.
+        if !self.dialing.contains_key(&backend) {
+            let dialer = self.proxy.backends[backend].clone();
+            let notices = self.notices.clone();
+            let dial = task::spawn(async move {
+                let event = Event::Dialed(dialer.dial().await);
+                let _ = notices.send(Notice {
+                    backend,
+                    generation: 0,
+                    event,
+                });
+            });
+            self.dialing.insert(backend, dial);
+        }
+        self.set_route(Route::Dialing {
+            backend,
+            queued: head,
+        });
+        Ok(())
+    }
+
+    // dialed adds the dialed backend, and sends it the queued frames of the
+    // stream waiting for it, if the stream is still current.
+    async fn dialed(
+        &mut self,
+        backend: usize,
+        res: io::Result<Box<dyn crate::Wire>>,
+    ) -> Result<(), ()> {
+        self.dialing.remove(&backend);
+
+        let waiting = match &mut self.current {
+            Some(Current {
+                sid,
+                route: Route::Dialing { backend: b, queued },
+                ..
+            }) if *b == backend => Some((*sid, std::mem::take(queued))),
+            _ => None,
+        };
+
+        let wire = match (res, &waiting) {
+            (Ok(wire), _) => wire,
+            (Err(err), Some((sid, _))) => {
+                let id = id::ID::new(*sid, 1);
+                self.set_route(Route::Dropped);
+                return self
+                    .fail(id, &format!("backend unavailable: {}", err))
+                    .await;
+            }
+            (Err(_), None) => return Ok(()),
+        };
+
+        self.generation += 1;
+        let (r, w) = io::split(wire);
+        let (out, out_rx) = mpsc::channel(64);
+        let last = Arc::new(Mutex::new(id::ID::default()));
+        let reader = task::spawn(read_backend(
+            FrameReader::new(r, &self.proxy.opts),
+            self.out.clone(),
+            self.notices.clone(),
+            backend,
+            self.generation,
+            self.sid.clone(),
+            last.clone(),
+        ));
+        let writer = task::spawn(write_backend(
+            w,
+            out_rx,
+            self.notices.clone(),
+            backend,
+            self.generation,
+        ));
+        self.backends.insert(
+            backend,
+            Backend {
+                out,
+                generation: self.generation,
+                reader,
+                writer,
+                last,
+            },
+        );
+
+        match waiting {
+            Some((_, queued)) => {
+                self.set_route(Route::Backend(backend));
+                self.write_backend(backend, queued).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    // write_backend queues bytes for the writer of the backend, so that a
+    // slow backend only holds up the relay once its queue is full.
+    async fn write_backend(&mut self, backend: usize, bytes: Vec<u8>) -> Result<(), ()> {
+        match self.backends.get(&backend) {
+            Some(b) if b.out.send(bytes).await.is_ok() => Ok(()),
+            _ => self.backend_closed(backend).await,
+        }
+    }
+
+    // notice handles what a dial task tells, and what the reader of a
+    // backend tells unless the backend was dialed again since.
+    async fn notice(&mut self, notice: Notice) -> Result<(), ()> {
+        let backend = match self.backends.get_mut(&notice.backend) {
+            Some(b) if b.generation == notice.generation => Some(b),
+            _ => None,
+        };
+        match (notice.event, backend) {
+            (Event::Dialed(res), _) => self.dialed(notice.backend, res).await,
+            // a failed write is noticed by the writer.
+            (Event::Pong(pong), Some(b)) => {
+                let _ = b.out.send(pong).await;
+                Ok(())
+            }
+            (Event::Closed, Some(_)) => self.backend_closed(notice.backend).await,
+            (_, None) => Ok(()),
+        }
+    }
+
+    // backend_closed drops a backend that failed, and fails the current
+    // stream if it was relayed to it.
+    async fn backend_closed(&mut self, backend: usize) -> Result<(), ()> {
+        // the reader is stopped before the error, so that it follows the
+        // last frame relayed from the backend.
+        let last = match self.backends.remove(&backend) {
+            Some(b) => {
+                b.writer.abort();
+                b.reader.abort();
+                let _ = b.reader.await;
+                let last = *b.last.lock().unwrap();
+                last
+            }
+            None => id::ID::default(),
+        };
+
+        let sid = match &self.current {
+            Some(cur) => match cur.route {
+                Route::Backend(b) if b == backend => cur.sid,
+                _ => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        self.set_route(Route::Dropped);
+        self.fail(error_id(sid, last), "backend closed").await
+    }
+
+    fn set_route(&mut self, route: Route) {
+        if let Some(cur) = &mut self.current {
+            cur.route = route;
+        }
+    }
+
+    async fn fail(&mut self, id: id::ID, msg: &str) -> Result<(), ()> {
+        self.out.send(error_packet(id, msg)).await.map_err(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dialer, Proxy};
+    use crate::testing::Echo;
+    use crate::wire::{frame, id, packet};
+    use crate::{conn, server, stream, transport, StreamRecv, StreamSend, Transport as _};
+
+    use async_trait::async_trait;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+    use tokio::sync::{mpsc, Mutex, Notify};
+
+    struct MemDialer<M>(M);
+
+    #[async_trait]
+    impl<M: server::Mux + Send + Sync + 'static> Dialer for MemDialer<M> {
+        async fn dial(&self) -> std::io::Result<Box<dyn crate::Wire>> {
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(server::handle_transport(server, self.0.clone()));
+            Ok(Box::new(client))
+        }
+    }
+
+    // slow_dialer takes a second to dial a tagged Echo, or fails to after a
+    // second if it is down.
+    struct SlowDialer {
+        down: bool,
+    }
+
+    #[async_trait]
+    impl Dialer for SlowDialer {
+        async fn dial(&self) -> std::io::Result<Box<dyn crate::Wire>> {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            if self.down {
+                return Err(std::io::ErrorKind::ConnectionRefused.into());
+            }
+            MemDialer(Echo::tagged(b"slow:")).dial().await
+        }
+    }
+
+    // keepalive_dialer serves connections that ping the proxy.
+    struct KeepaliveDialer(transport::Keepalive);
+
+    #[async_trait]
+    impl Dialer for KeepaliveDialer {
+        async fn dial(&self) -> std::io::Result<Box<dyn crate::Wire>> {
+            let (client, server) = tokio::io::duplex(4096);
+            let opts = server::Options {
+                keepalive: Some(self.0),
+                ..server::Options::default()
+            };
+            tokio::spawn(async move {
+                server::handle_transport_with_options(server, Echo::default(), &opts).await
+            });
+            Ok(Box::new(client))
+        }
+    }
+
+    // dying_dialer serves connections until the backend is killed, which
+    // closes every connection to it.
+    struct DyingDialer(Arc<Notify>);
+
+    #[async_trait]
+    impl Dialer for DyingDialer {
+        async fn dial(&self) -> std::io::Result<Box<dyn crate::Wire>> {
+            let (client, server) = tokio::io::duplex(4096);
+            let kill = self.0.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = server::handle_transport(server, Echo::default()) => (),
+                    _ = kill.notified() => (),
+                }
+            });
+            Ok(Box::new(client))
+        }
+    }
+
+    // breaking_dialer serves connections whose writes fail once the backend
+    // is broken, while their reads go on.
+    struct BreakingDialer(Arc<AtomicBool>);
+
+    struct BreakingWire(DuplexStream, Arc<AtomicBool>);
+
+    impl AsyncRead for BreakingWire {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for BreakingWire {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            data: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if self.1.load(Ordering::Relaxed) {
+                return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            Pin::new(&mut self.0).poll_write(cx, data)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    #[async_trait]
+    impl Dialer for BreakingDialer {
+        async fn dial(&self) -> std::io::Result<Box<dyn crate::Wire>> {
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(server::handle_transport(server, Echo::default()));
+            Ok(Box::new(BreakingWire(client, self.0.clone())))
+        }
+    }
+
+    // connect serves a connection with the proxy and returns a client for
+    // it, which splits packets into frames of at most split_size.
+    fn connect(
+        proxy: Proxy,
+        split_size: usize,
+    ) -> conn::Conn<transport::Transport<tokio::io::DuplexStream>> {
+        let proxy = Arc::new(proxy);
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move { proxy.handle(server).await });
+        let opts = transport::TransportOptions::new().split_size(split_size);
+        conn::Conn::new(transport::Transport::with_options(client, opts))
+    }
+
+    async fn recv_all(st: &mut stream::Stream<'_>) -> Vec<Vec<u8>> {
+        let mut msgs = Vec::new();
+        loop {
+            let mut buf = Vec::new();
+            match st.recv_into(&mut buf).await {
+                Ok(()) => msgs.push(buf),
+                Err(stream::Error::StateError(stream::State::EOF)) => return msgs,
+                Err(err) => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_by_prefix() {
+        let mut conn = connect(
+            Proxy::new()
+                .route("/billing.*", MemDialer(Echo::tagged(b"billing:")))
+                .route("/exact", MemDialer(Echo::tagged(b"exact:"))),
+            64 << 10,
+        );
+
+        let mut out = Vec::new();
+        conn.invoke_into(b"/billing.Invoices/List", &vec![1], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"billing:\x01");
+
+        conn.invoke_into(b"/exact", &vec![2], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"exact:\x02");
+
+        match conn.invoke_into(b"/exactly", &vec![3], &mut out).await {
+            Err(stream::Error::StateError(stream::State::RemoteError((
+                server::ERROR_CODE,
+                msg,
+            )))) => {
+                assert_eq!(msg, "no route for rpc \"/exactly\"")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        conn.invoke_into(b"/billing.Invoices/Get", &vec![4], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"billing:\x04");
+    }
+
+    #[tokio::test]
+    async fn relays_streams() {
+        // messages are split into many frames, and are larger than the
+        // buffers of the pipes on both sides of the proxy.
+        let mut conn = connect(Proxy::new().route("*", MemDialer(Echo::default())), 1000);
+
+        let mut st = conn.stream(b"/echo").await.unwrap();
+        let mut out = Vec::new();
+        for size in [0, 10, 1000, 10_000, 100_000].iter() {
+            let msg = vec![*size as u8; *size];
+            st.send(&msg).await.unwrap();
+            st.recv_into(&mut out).await.unwrap();
+            assert_eq!(out, msg);
+        }
+        st.close_send().await.unwrap();
+        assert!(recv_all(&mut st).await.is_empty());
+        drop(st);
+
+        // the client streams.
+        let mut st = conn.stream(b"/sum").await.unwrap();
+        for _ in 0..3 {
+            st.send(&vec![7; 1500]).await.unwrap();
+        }
+        st.close_send().await.unwrap();
+        assert_eq!(recv_all(&mut st).await, [4500u64.to_be_bytes()]);
+        drop(st);
+
+        // the server streams.
+        let mut st = conn.stream(b"/fanout").await.unwrap();
+        st.send(&vec![4]).await.unwrap();
+        st.close_send().await.unwrap();
+        let msgs = recv_all(&mut st).await;
+        let sizes: Vec<_> = msgs.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [0, 1000, 2000, 3000]);
+    }
+
+    #[tokio::test]
+    async fn backend_dies_mid_stream() {
+        let kill = Arc::new(Notify::new());
+        let mut conn = connect(
+            Proxy::new()
+                .route("/hang", DyingDialer(kill.clone()))
+                .route("*", MemDialer(Echo::default())),
+            64 << 10,
+        );
+
+        let mut st = conn.stream(b"/hang").await.unwrap();
+        let mut out = Vec::new();
+        st.recv_into(&mut out).await.unwrap();
+        assert_eq!(out, b"hanging");
+
+        kill.notify_waiters();
+        match st.recv_into(&mut out).await {
+            Err(stream::Error::StateError(stream::State::RemoteError((
+                server::ERROR_CODE,
+                msg,
+            )))) => {
+                assert_eq!(msg, "backend closed")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(st);
+
+        // the connection to the client outlives the backend.
+        conn.invoke_into(b"/echo", &vec![1, 2], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn backend_write_fails_mid_stream() {
+        let broken = Arc::new(AtomicBool::new(false));
+        let proxy = Arc::new(Proxy::new().route("*", BreakingDialer(broken.clone())));
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move { proxy.handle(server).await });
+
+        let mut tr = transport::Transport::new(client);
+        let mut buf = Vec::new();
+        let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+        st.invoke(b"/hang").await.unwrap();
+        let mut out = Vec::new();
+        st.recv_into(&mut out).await.unwrap();
+        assert_eq!(out, b"hanging");
+
+        broken.store(true, Ordering::Relaxed);
+        st.send(&vec![1]).await.unwrap();
+        st.transport().flush().await.unwrap();
+        drop(st);
+
+        // the error follows the message the backend sent.
+        let (id, kind) = tr.read_packet_into(&mut buf).await.unwrap();
+        assert_eq!((id.stream, id.message), (1, 2));
+        assert_eq!(kind, packet::Kind::Error);
+        assert_eq!(buf[..8], server::ERROR_CODE.to_be_bytes());
+        assert_eq!(&buf[8..], b"backend closed");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive() {
+        let keepalive = transport::Keepalive {
+            interval: std::time::Duration::from_millis(100),
+            timeout: std::time::Duration::from_millis(300),
+        };
+        let mut conn = connect(
+            Proxy::new().route("*", KeepaliveDialer(keepalive)),
+            64 << 10,
+        );
+        conn.transport().set_keepalive(keepalive);
+
+        // the proxy answers the pings of the client waiting for a slow rpc.
+        let mut out = Vec::new();
+        conn.invoke_into(b"/slow", &vec![1], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, [1]);
+
+        // and those of the backend waiting for the client.
+        let mut st = conn.stream(b"/echo").await.unwrap();
+        st.transport().flush().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        st.send(&vec![2]).await.unwrap();
+        st.recv_into(&mut out).await.unwrap();
+        assert_eq!(out, [2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_dial() {
+        let keepalive = transport::Keepalive {
+            interval: std::time::Duration::from_millis(100),
+            timeout: std::time::Duration::from_millis(300),
+        };
+        let proxy = Proxy::new()
+            .route("/slow.*", SlowDialer { down: false })
+            .route("/down.*", SlowDialer { down: true })
+            .route("*", MemDialer(Echo::default()));
+        let mut conn = connect(proxy, 64 << 10);
+        conn.transport().set_keepalive(keepalive);
+
+        // the proxy answers pings while the backend is dialed.
+        let start = tokio::time::Instant::now();
+        let mut out = Vec::new();
+        conn.invoke_into(b"/slow.echo", &vec![1], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"slow:\x01");
+        assert_eq!(start.elapsed(), std::time::Duration::from_secs(1));
+
+        match conn.invoke_into(b"/down.echo", &vec![2], &mut out).await {
+            Err(stream::Error::StateError(stream::State::RemoteError((code, msg)))) => {
+                assert_eq!(code, server::ERROR_CODE);
+                assert!(msg.starts_with("backend unavailable: "), "{}", msg);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(start.elapsed(), std::time::Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dial_does_not_block_streams() {
+        let proxy = Arc::new(
+            Proxy::new()
+                .route("/slow.*", SlowDialer { down: false })
+                .route("*", MemDialer(Echo::default())),
+        );
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move { proxy.handle(server).await });
+        let mut tr = transport::Transport::new(client);
+        let mut buf = Vec::new();
+
+        // a stream gives up on its slow backend, and the next one is served
+        // right away.
+        let start = tokio::time::Instant::now();
+        let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+        st.invoke(b"/slow.echo").await.unwrap();
+        st.send(&vec![1]).await.unwrap();
+        st.transport().flush().await.unwrap();
+        drop(st);
+
+        let mut st = stream::Stream::new(2, &mut tr, &mut buf);
+        st.invoke(b"/echo").await.unwrap();
+        st.send(&vec![2]).await.unwrap();
+        st.close_send().await.unwrap();
+        assert_eq!(recv_all(&mut st).await, [[2]]);
+        assert_eq!(start.elapsed(), std::time::Duration::ZERO);
+        drop(st);
+
+        // the slow backend is kept once it is dialed.
+        let mut st = stream::Stream::new(3, &mut tr, &mut buf);
+        st.invoke(b"/slow.echo").await.unwrap();
+        st.send(&vec![3]).await.unwrap();
+        st.close_send().await.unwrap();
+        assert_eq!(recv_all(&mut st).await, [b"slow:\x03"]);
+        assert_eq!(start.elapsed(), std::time::Duration::from_secs(1));
+    }
+
+    // queue is a listener that accepts whatever is sent to it.
+    struct Queue(Mutex<mpsc::UnboundedReceiver<stream::Result<DuplexStream>>>);
+
+    #[async_trait]
+    impl server::Listener<DuplexStream> for Queue {
+        async fn accept(&self) -> stream::Result<DuplexStream> {
+            match self.0.lock().await.recv().await {
+                Some(accepted) => accepted,
+                None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into()),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accept_errors() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let proxy = Arc::new(Proxy::new().route("*", MemDialer(Echo::default())));
+        let run = tokio::spawn(proxy.run(Queue(Mutex::new(rx))));
+
+        // running out of file descriptors backs off and keeps accepting.
+        for _ in 0..3 {
+            let err = std::io::Error::from_raw_os_error(24);
+            tx.send(Err(err.into())).unwrap();
+        }
+        let (client, server) = tokio::io::duplex(4096);
+        tx.send(Ok(server)).unwrap();
+
+        let start = tokio::time::Instant::now();
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        let mut out = Vec::new();
+        conn.invoke_into(b"/echo", &vec![1], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, [1]);
+        assert_eq!(
+            start.elapsed(),
+            std::time::Duration::from_millis(5 + 10 + 20)
+        );
+
+        // other errors stop the proxy.
+        drop(tx);
+        assert!(run.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn frame_limit() {
+        let opts = transport::TransportOptions::new().max_frame_size(2000);
+        let proxy = || {
+            Proxy::new()
+                .route("*", MemDialer(Echo::default()))
+                .transport_options(opts.clone())
+        };
+        // frames over the limit fail the rpc and close the connection,
+        // whether they arrive in one read or not.
+        for &size in &[5000, 2001] {
+            let mut conn = connect(proxy(), 64 << 10);
+            let mut out = Vec::new();
+            conn.invoke_into(b"/echo", &vec![1; 2000], &mut out)
+                .await
+                .unwrap();
+            assert_eq!(out, vec![1; 2000]);
+
+            let res = conn.invoke_into(b"/echo", &vec![1; size], &mut out).await;
+            assert!(res.is_err());
+            let res = conn.invoke_into(b"/echo", &vec![1], &mut out).await;
+            assert!(res.unwrap_err().is_remote_closed(), "{}", size);
+        }
+    }
+
+    // raw_dialer hands out a wire whose other end is driven by the test.
+    struct RawDialer(std::sync::Mutex<Option<DuplexStream>>);
+
+    impl RawDialer {
+        fn new() -> (RawDialer, transport::Transport<DuplexStream>) {
+            let (wire, backend) = tokio::io::duplex(4096);
+            let dialer = RawDialer(std::sync::Mutex::new(Some(wire)));
+            (dialer, transport::Transport::new(backend))
+        }
+    }
+
+    #[async_trait]
+    impl Dialer for RawDialer {
+        async fn dial(&self) -> std::io::Result<Box<dyn crate::Wire>> {
+            match self.0.lock().unwrap().take() {
+                Some(wire) => Ok(Box::new(wire)),
+                None => Err(std::io::ErrorKind::ConnectionRefused.into()),
+            }
+        }
+    }
+
+    fn message(id: (u64, u64), data: &[u8]) -> frame::Frame<'_> {
+        frame::Frame {
+            data,
+            id: id::ID::new(id.0, id.1),
+            kind: packet::Kind::Message.into(),
+            done: true,
+            control: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_backend_stream() {
+        let (first, mut a) = RawDialer::new();
+        let (second, mut b) = RawDialer::new();
+        let proxy = Arc::new(Proxy::new().route("/a.*", first).route("/b.*", second));
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move { proxy.handle(server).await });
+        let mut tr = transport::Transport::new(client);
+        let (mut buf, mut bbuf) = (Vec::new(), Vec::new());
+
+        // the first backend answers stream 1.
+        let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+        st.invoke(b"/a.chat").await.unwrap();
+        st.send(&vec![1]).await.unwrap();
+        st.transport().flush().await.unwrap();
+        drop(st);
+        let (id, kind) = a.read_packet_into(&mut bbuf).await.unwrap();
+        assert_eq!((id.stream, kind), (1, packet::Kind::Invoke));
+        a.read_packet_into(&mut bbuf).await.unwrap();
+        a.write_frame(message((1, 1), &[1])).await.unwrap();
+        a.flush().await.unwrap();
+        let (id, kind) = tr.read_packet_into(&mut buf).await.unwrap();
+        assert_eq!(
+            (id.stream, kind, &buf[..]),
+            (1, packet::Kind::Message, &[1][..])
+        );
+
+        // once the client moves on to stream 2 on the second backend, the
+        // first is told to close stream 1, and what it still sends on it is
+        // dropped.
+        let mut st = stream::Stream::new(2, &mut tr, &mut buf);
+        st.invoke(b"/b.echo").await.unwrap();
+        st.send(&vec![2]).await.unwrap();
+        st.close_send().await.unwrap();
+        drop(st);
+        let (id, kind) = a.read_packet_into(&mut bbuf).await.unwrap();
+        assert_eq!((id.stream, kind), (1, packet::Kind::Close));
+        for i in 2..10 {
+            a.write_frame(message((1, i), &[1])).await.unwrap();
+        }
+        a.flush().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        for _ in 0..3 {
+            b.read_packet_into(&mut bbuf).await.unwrap();
+        }
+        b.write_frame(message((2, 1), &[2])).await.unwrap();
+        b.flush().await.unwrap();
+        drop(b);
+
+        let (id, kind) = tr.read_packet_into(&mut buf).await.unwrap();
+        assert_eq!(
+            (id.stream, kind, &buf[..]),
+            (2, packet::Kind::Message, &[2][..])
+        );
+        let (id, kind) = tr.read_packet_into(&mut buf).await.unwrap();
+        assert_eq!((id.stream, kind), (2, packet::Kind::Error));
+        assert_eq!(&buf[8..], b"backend closed");
+    }
+
+    #[tokio::test]
+    async fn slow_backend() {
+        let (backend, _a) = RawDialer::new();
+        let proxy = Arc::new(Proxy::new().route("*", backend));
+        let (client, server) = tokio::io::duplex(64 << 10);
+        tokio::spawn(async move { proxy.handle(server).await });
+        let mut tr = transport::Transport::new(client);
+
+        // a backend that reads nothing does not hold up the answers to the
+        // pings of the client.
+        let mut buf = Vec::new();
+        let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+        st.invoke(b"/slow").await.unwrap();
+        st.send(&vec![1; 32 << 10]).await.unwrap();
+        st.transport().flush().await.unwrap();
+        drop(st);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let wire = tr.wire();
+        wire.write_all(&[0x83, 0, 0, 1, 7]).await.unwrap();
+        let mut pong = [0; 5];
+        let read = wire.read_exact(&mut pong);
+        tokio::time::timeout(std::time::Duration::from_secs(1), read)
+            .await
+            .expect("ping not answered")
+            .unwrap();
+        assert_eq!(pong, [0x85, 0, 0, 1, 7]);
+    }
+}
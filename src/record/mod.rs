@@ -0,0 +1,434 @@
+use crate::wire::varint;
+use crate::{conn, server, transport};
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// a recording holds every chunk of bytes read from and written to a wire,
+// stamped with when it happened relative to the start of the recording.
+// replaying feeds the recorded reads back into a drpc endpoint and compares
+// what it writes against the recorded writes.
+
+#[derive(Debug)]
+pub enum Error {
+    ParseError,
+    IOError(io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IOError(err)
+    }
+}
+
+impl From<varint::Error> for Error {
+    fn from(_: varint::Error) -> Error {
+        Error::ParseError
+    }
+}
+
+// recording
+
+const MAGIC: &[u8] = b"drpcrec1";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub at: Duration,
+    pub dir: Direction,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub events: Vec<Event>,
+}
+
+impl Recording {
+    /// Returns every byte recorded in the given direction, in order.
+    pub fn bytes(&self, dir: Direction) -> Vec<u8> {
+        let mut out = Vec::new();
+        for ev in self.events.iter().filter(|ev| ev.dir == dir) {
+            out.extend_from_slice(&ev.data);
+        }
+        out
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(MAGIC);
+        for ev in &self.events {
+            buf.push(match ev.dir {
+                Direction::Read => 0,
+                Direction::Write => 1,
+            });
+            varint::append(buf, ev.at.as_nanos() as u64);
+            varint::append(buf, ev.data.len() as u64);
+            buf.extend_from_slice(&ev.data);
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Recording, Error> {
+        let mut buf = buf.strip_prefix(MAGIC).ok_or(Error::ParseError)?;
+        let mut events = Vec::new();
+
+        while let [dir, rem @ ..] = buf {
+            let dir = match dir {
+                0 => Direction::Read,
+                1 => Direction::Write,
+                _ => return Err(Error::ParseError),
+            };
+            let (nanos, n) = varint::read(rem)?;
+            let rem = &rem[n..];
+            let (len, n) = varint::read(rem)?;
+            let rem = &rem[n..];
+            if len > rem.len() as u64 {
+                return Err(Error::ParseError);
+            }
+            let (data, rem) = rem.split_at(len as usize);
+
+            events.push(Event {
+                at: Duration::from_nanos(nanos),
+                dir,
+                data: data.to_vec(),
+            });
+            buf = rem;
+        }
+
+        Ok(Recording { events })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        std::fs::write(path, buf)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Recording, Error> {
+        Recording::decode(&std::fs::read(path)?)
+    }
+}
+
+// recorder
+
+/// Log is a handle to the recording made by a Recorder. It stays usable after
+/// the Recorder and the wire it wraps are dropped.
+#[derive(Clone, Default)]
+pub struct Log(Arc<Mutex<Recording>>);
+
+impl Log {
+    pub fn recording(&self) -> Recording {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn push(&self, at: Duration, dir: Direction, data: &[u8]) {
+        if !data.is_empty() {
+            self.0.lock().unwrap().events.push(Event {
+                at,
+                dir,
+                data: data.to_vec(),
+            });
+        }
+    }
+}
+
+/// Recorder wraps a wire and records all of the bytes read from and written
+/// to it.
+pub struct Recorder<W> {
+    w: W,
+    start: Instant,
+    log: Log,
+}
+
+impl<W: crate::Wire> Recorder<W> {
+    pub fn new(w: W) -> Recorder<W> {
+        Recorder {
+            w,
+            start: Instant::now(),
+            log: Log::default(),
+        }
+    }
+
+    pub fn log(&self) -> Log {
+        self.log.clone()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+impl<W: crate::Wire> AsyncRead for Recorder<W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.w).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let at = this.start.elapsed();
+            this.log.push(at, Direction::Read, &buf.filled()[before..]);
+        }
+        res
+    }
+}
+
+impl<W: crate::Wire> AsyncWrite for Recorder<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.w).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            let at = this.start.elapsed();
+            this.log.push(at, Direction::Write, &buf[..n]);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().w).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().w).poll_shutdown(cx)
+    }
+}
+
+// replay
+
+/// Mismatch describes where the bytes written during a replay first differ
+/// from the recorded writes. The byte vectors hold everything from that
+/// offset onwards.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub offset: usize,
+    pub expected: Vec<u8>,
+    pub actual: Vec<u8>,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let head = |data: &[u8]| data[..data.len().min(16)].to_vec();
+        write!(
+            f,
+            "output differs at offset {}: expected {:02x?} ({} bytes), got {:02x?} ({} bytes)",
+            self.offset,
+            head(&self.expected),
+            self.expected.len(),
+            head(&self.actual),
+            self.actual.len(),
+        )
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Output is a handle to the bytes written to a ReplayWire.
+#[derive(Clone)]
+pub struct Output {
+    expected: Arc<Vec<u8>>,
+    actual: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Output {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.actual.lock().unwrap().clone()
+    }
+
+    /// Compares the bytes written so far against the recorded writes.
+    pub fn check(&self) -> Result<(), Mismatch> {
+        let actual = self.actual.lock().unwrap();
+        let offset = self
+            .expected
+            .iter()
+            .zip(actual.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        if offset == self.expected.len() && offset == actual.len() {
+            return Ok(());
+        }
+        Err(Mismatch {
+            offset,
+            expected: self.expected[offset..].to_vec(),
+            actual: actual[offset..].to_vec(),
+        })
+    }
+}
+
+/// ReplayWire is a wire that returns the reads of a recording in the same
+/// chunks they were recorded in, followed by EOF, and captures every write.
+/// Replaying a recording made on one side of a connection into the same
+/// side should write exactly the recorded writes.
+pub struct ReplayWire {
+    reads: VecDeque<Vec<u8>>,
+    pos: usize,
+    output: Output,
+}
+
+impl ReplayWire {
+    pub fn new(rec: &Recording) -> ReplayWire {
+        let reads = rec
+            .events
+            .iter()
+            .filter(|ev| ev.dir == Direction::Read)
+            .map(|ev| ev.data.clone())
+            .collect();
+
+        ReplayWire {
+            reads,
+            pos: 0,
+            output: Output {
+                expected: Arc::new(rec.bytes(Direction::Write)),
+                actual: Arc::default(),
+            },
+        }
+    }
+
+    pub fn output(&self) -> Output {
+        self.output.clone()
+    }
+}
+
+impl AsyncRead for ReplayWire {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(chunk) = this.reads.front() {
+            let n = buf.remaining().min(chunk.len() - this.pos);
+            buf.put_slice(&chunk[this.pos..this.pos + n]);
+            this.pos += n;
+            if this.pos == chunk.len() {
+                this.reads.pop_front();
+                this.pos = 0;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ReplayWire {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.output.actual.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Feeds a recording made on the server side of a connection into the mux and
+/// checks that it writes the same bytes the server wrote when recording.
+pub async fn replay_server<M: server::Mux>(rec: &Recording, mux: M) -> Result<(), Mismatch> {
+    let wire = ReplayWire::new(rec);
+    let output = wire.output();
+    server::handle_transport(wire, mux).await;
+    output.check()
+}
+
+/// Returns a connection that replays a recording made on the client side of a
+/// connection. After issuing the same rpcs that were recorded, the returned
+/// Output checks that the connection wrote the same bytes.
+pub fn replay_conn(rec: &Recording) -> (conn::Conn<transport::Transport<ReplayWire>>, Output) {
+    let wire = ReplayWire::new(rec);
+    let output = wire.output();
+    (conn::Conn::new(transport::Transport::new(wire)), output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Echo;
+
+    async fn record_session() -> (Recording, Recording) {
+        let (client, server) = tokio::io::duplex(4096);
+        let client = Recorder::new(client);
+        let server = Recorder::new(server);
+        let (client_log, server_log) = (client.log(), server.log());
+
+        let handle = tokio::spawn(server::handle_transport(server, Echo::tagged(&[1])));
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        for i in 0..3u8 {
+            assert_eq!(echo(&mut conn, i).await, vec![1, i]);
+        }
+        drop(conn);
+        handle.await.unwrap();
+
+        (client_log.recording(), server_log.recording())
+    }
+
+    async fn echo<T: crate::Transport>(conn: &mut conn::Conn<T>, i: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        conn.invoke_into(b"/echo", &vec![i], &mut out)
+            .await
+            .unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn encode_decode() {
+        let (rec, _) = record_session().await;
+        assert!(!rec.events.is_empty());
+
+        let mut buf = Vec::new();
+        rec.encode(&mut buf);
+        assert_eq!(Recording::decode(&buf).unwrap(), rec);
+        assert!(Recording::decode(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_server_diffs_output() {
+        let (_, rec) = record_session().await;
+        assert_eq!(replay_server(&rec, Echo::tagged(&[1])).await, Ok(()));
+
+        let mismatch = replay_server(&rec, Echo::tagged(&[2])).await.unwrap_err();
+        assert!(mismatch.offset > 0);
+        assert_ne!(mismatch.expected, mismatch.actual);
+    }
+
+    #[tokio::test]
+    async fn replay_client() {
+        let (rec, _) = record_session().await;
+        let (mut conn, output) = replay_conn(&rec);
+        for i in 0..3u8 {
+            assert_eq!(echo(&mut conn, i).await, vec![1, i]);
+        }
+        assert_eq!(output.check(), Ok(()));
+    }
+}
@@ -0,0 +1,472 @@
+use super::{context, CancelToken, Mux, Options, Stats, ERROR_CODE};
+use crate::pool;
+use crate::transport::split::{self, ChannelTransport, Command, ControlWire, QueueSender};
+use crate::transport::{self, ErrorKind, Op};
+use crate::wire::{id, packet};
+use crate::{stream, Transport as _};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::AsyncRead;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, Instant};
+
+// how long streams may run once cancelled by a closed connection before they
+// are aborted.
+const CANCEL_GRACE: Duration = Duration::from_secs(1);
+
+// the streams of a connection are served in their own tasks. the connection
+// reads packets and queues them for the tasks, and every task writes over a
+// channel transport to the writer of the wire.
+
+struct Route {
+    // none once the stream fell behind, which drops its later packets.
+    packets: Option<QueueSender>,
+    overflowed: Arc<AtomicBool>,
+    task: task::Id,
+    cancel: CancelToken,
+}
+
+pub(super) async fn handle<W, M>(wire: W, conn: &Arc<context::Conn>, mux: M, opts: &Options)
+where
+    W: crate::Wire,
+    M: Mux + Send + Sync + 'static,
+{
+    let (rd, wr) = tokio::io::split(wire);
+    let (cmds, rx) = mpsc::unbounded_channel();
+    let mut reported = transport::Stats::default();
+    let report = |stats| opts.stats.report(stats, &mut reported);
+    tokio::join!(
+        read_loop(rd, cmds, conn, mux, opts),
+        split::write_loop(wr, rx, &opts.transport, report)
+    );
+}
+
+async fn read_loop<R, M>(
+    rd: R,
+    cmds: mpsc::UnboundedSender<Command>,
+    conn: &Arc<context::Conn>,
+    mux: M,
+    opts: &Options,
+) where
+    R: AsyncRead + Unpin + Send,
+    M: Mux + Send + Sync + 'static,
+{
+    let wire = tokio::io::join(rd, ControlWire(cmds.clone()));
+    let mut tr = super::transport(wire, opts);
+    let max_streams = opts.max_concurrent_streams.unwrap_or(usize::MAX);
+    let max_queue = opts.max_stream_queue.unwrap_or(split::QUEUE_SIZE);
+
+    let mut routes = HashMap::<u64, Route>::new();
+    let mut tasks = JoinSet::new();
+    let mut buf = Vec::new();
+    let mut served = false;
+    let mut md = None;
+    let mut idle_since = Instant::now();
+    let mut reported = transport::Stats::default();
+
+    loop {
+        // the handshake and idle timeouts only run while no stream is served.
+        // the read is kept across finished streams, as dropping it could lose
+        // a partially read packet.
+        let res = {
+            let read = tr.read_packet_into(&mut buf);
+            tokio::pin!(read);
+            loop {
+                let (timeout, counter) = opts.wait_timeout(served);
+                let deadline = match tasks.is_empty() {
+                    true => timeout.map(|timeout| idle_since + timeout),
+                    false => None,
+                };
+
+                tokio::select! {
+                    res = &mut read => break Some(res),
+                    Some(joined) = tasks.join_next_with_id() => {
+                        let id = match joined {
+                            Ok((id, ())) => id,
+                            Err(err) => err.id(),
+                        };
+                        routes.retain(|_, route| route.task != id);
+                        if tasks.is_empty() {
+                            idle_since = Instant::now();
+                        }
+                    }
+                    _ = super::expire(deadline) => {
+                        Stats::count(counter);
+                        break None;
+                    }
+                }
+            }
+        };
+
+        let (id, kind) = match res {
+            Some(Ok(v)) => v,
+            Some(Err(err)) => {
+                opts.stats.transport_error(&err);
+                break;
+            }
+            None => break,
+        };
+
+        // packets of finished streams are dropped, like a stream drops
+        // packets of other streams.
+        if let Some(route) = routes.get_mut(&id.stream) {
+            let packets = match &route.packets {
+                Some(packets) => packets,
+                None => continue,
+            };
+            if !packets.try_send((id, kind, std::mem::take(&mut buf))) {
+                // the stream fails once it read the packets already queued.
+                packets.fail(transport::Error::new(
+                    ErrorKind::DataOverflowError,
+                    Op::Read,
+                ));
+                route.overflowed.store(true, Ordering::Relaxed);
+                route.packets = None;
+                route.cancel.cancel();
+            }
+            continue;
+        } else if kind == packet::Kind::InvokeMetadata {
+            md = super::parse_metadata(id.stream, &buf);
+            continue;
+        } else if kind != packet::Kind::Invoke {
+            continue;
+        }
+        served = true;
+        opts.stats.report(tr.stats(), &mut reported);
+
+        if routes.len() >= max_streams {
+            Stats::count(&opts.stats.0.refused_streams);
+            refuse(&cmds, id.stream);
+            continue;
+        }
+
+        let (tx, rx) = split::queue(max_queue);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let mut st_tr = ChannelTransport::new(rx, cmds.clone(), &opts.transport);
+
+        let ctx = opts.request_context(conn, id.stream, super::take_metadata(&mut md, id.stream));
+        let cancel = ctx.cancel_token().clone();
+        let rpc = std::mem::take(&mut buf);
+        let mux = mux.clone();
+        let opts = opts.clone();
+        let st_overflowed = overflowed.clone();
+        let task = tasks.spawn(async move {
+            let mut sbuf = Vec::new();
+            let mut st =
+                stream::Stream::with_options(id.stream, &mut st_tr, &mut sbuf, &opts.transport);
+            st.extensions_mut().insert(ctx);
+            super::serve_stream(&mux, &rpc, &mut st, &opts).await;
+            // a handler that returns once cancelled may not have read far
+            // enough to fail on the overflow.
+            if st_overflowed.load(Ordering::Relaxed) {
+                let msg = "too many packets queued for the stream";
+                let _ = st.error(msg, ERROR_CODE).await;
+            }
+            pool::release(&opts.transport.pool, &mut sbuf);
+        });
+        routes.insert(
+            id.stream,
+            Route {
+                packets: Some(tx),
+                overflowed,
+                task: task.id(),
+                cancel,
+            },
+        );
+    }
+
+    // streams still being served see the connection as closed by the remote
+    // and their requests as cancelled, and may still write to it.
+    for (_, route) in routes.drain() {
+        route.cancel.cancel();
+    }
+    let drain = async { while tasks.join_next().await.is_some() {} };
+    if time::timeout(CANCEL_GRACE, drain).await.is_err() {
+        tasks.shutdown().await;
+    }
+    pool::release(&opts.transport.pool, &mut buf);
+    opts.stats.report(tr.stats(), &mut reported);
+}
+
+// refuse fails an invoke past max_concurrent_streams, without setting up a
+// task for its stream.
+fn refuse(cmds: &mpsc::UnboundedSender<Command>, sid: u64) {
+    let msg = "too many concurrent streams";
+    let mut data = Vec::with_capacity(8 + msg.len());
+    data.extend_from_slice(&ERROR_CODE.to_be_bytes());
+    data.extend_from_slice(msg.as_bytes());
+    // the error is the only packet sent on the stream, and so its first.
+    let pkt = packet::Packet {
+        data,
+        id: id::ID::new(sid, 1),
+        kind: packet::Kind::Error,
+    };
+
+    let (done, _) = oneshot::channel();
+    let _ = cmds.send(Command::Packet(pkt, done));
+    let (done, _) = oneshot::channel();
+    let _ = cmds.send(Command::Flush(done));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::options;
+    use super::super::{handle_transport_concurrent, Mux, Options, RequestContext};
+    use crate::testing::Echo;
+    use crate::wire::packet;
+    use crate::{stream, transport, StreamRecv, StreamSend, Transport as _};
+
+    use async_trait::async_trait;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::{self, Instant};
+
+    // start invokes a stream on the client transport and sends it a message
+    // holding its id.
+    async fn start(tr: &mut transport::Transport<tokio::io::DuplexStream>, sid: u64, rpc: &[u8]) {
+        let mut buf = Vec::new();
+        let mut st = stream::Stream::new(sid, tr, &mut buf);
+        st.invoke(rpc).await.unwrap();
+        st.send(&vec![sid as u8]).await.unwrap();
+        st.close_send().await.unwrap();
+    }
+
+    async fn read_packets(
+        tr: &mut transport::Transport<tokio::io::DuplexStream>,
+        n: usize,
+    ) -> Vec<(u64, packet::Kind, Vec<u8>)> {
+        let mut pkts = Vec::new();
+        let mut buf = Vec::new();
+        for _ in 0..n {
+            let (id, kind) = tr.read_packet_into(&mut buf).await.unwrap();
+            pkts.push((id.stream, kind, buf.clone()));
+        }
+        pkts
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_streams() {
+        let opts = Options {
+            max_concurrent_streams: Some(8),
+            ..Options::default()
+        };
+        let stats = opts.stats.clone();
+        let (client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move {
+            handle_transport_concurrent(server, Echo::default(), &opts).await
+        });
+
+        let mut tr = transport::Transport::new(client);
+        let begin = Instant::now();
+        for (sid, rpc) in [(1, &b"/slow"[..]), (2, b"/echo"), (3, b"/echo")].iter() {
+            start(&mut tr, *sid, rpc).await;
+        }
+
+        // the slow stream does not hold up the streams invoked after it.
+        let pkts = read_packets(&mut tr, 6).await;
+        assert_eq!(begin.elapsed(), Duration::from_secs(1));
+        for (sid, _, _) in &pkts[..4] {
+            assert_ne!(*sid, 1);
+        }
+        assert_eq!(
+            pkts[4..].to_vec(),
+            vec![
+                (1, packet::Kind::Message, vec![1]),
+                (1, packet::Kind::CloseSend, vec![]),
+            ]
+        );
+        for sid in 2..=3 {
+            assert!(pkts.contains(&(sid, packet::Kind::Message, vec![sid as u8])));
+        }
+
+        drop(tr);
+        handle.await.unwrap().unwrap();
+        assert_eq!(stats.transport().packets_out.close_send, 3);
+        assert_eq!(stats.refused_streams(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_abort() {
+        let opts = Options {
+            max_concurrent_streams: Some(8),
+            ..Options::default()
+        };
+        let (client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move {
+            handle_transport_concurrent(server, Echo::default(), &opts).await
+        });
+
+        // a stream that ignores being cancelled is aborted a second after the
+        // connection is closed.
+        let mut tr = transport::Transport::new(client);
+        start(&mut tr, 1, b"/hang").await;
+        let pkts = read_packets(&mut tr, 1).await;
+        assert_eq!(pkts[0], (1, packet::Kind::Message, b"hanging".to_vec()));
+        drop(tr);
+        let begin = Instant::now();
+        handle.await.unwrap().unwrap();
+        assert_eq!(begin.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_stream_limit() {
+        let opts = Options {
+            max_concurrent_streams: Some(1),
+            ..Options::default()
+        };
+        let stats = opts.stats.clone();
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(
+            async move { handle_transport_concurrent(server, Echo::default(), &opts).await },
+        );
+
+        let mut tr = transport::Transport::new(client);
+        start(&mut tr, 1, b"/slow").await;
+        start(&mut tr, 2, b"/echo").await;
+
+        let pkts = read_packets(&mut tr, 3).await;
+        let mut refused = 10u64.to_be_bytes().to_vec();
+        refused.extend_from_slice(b"too many concurrent streams");
+        assert_eq!(pkts[0], (2, packet::Kind::Error, refused));
+        assert_eq!(pkts[1], (1, packet::Kind::Message, vec![1]));
+        assert_eq!(stats.refused_streams(), 1);
+
+        // streams after the slow one are served once it is done.
+        start(&mut tr, 3, b"/echo").await;
+        let pkts = read_packets(&mut tr, 2).await;
+        assert_eq!(pkts[0], (3, packet::Kind::Message, vec![3]));
+    }
+
+    #[derive(Clone)]
+    struct Stall;
+
+    #[async_trait]
+    impl Mux for Stall {
+        async fn serve<'a>(&self, rpc: &[u8], st: &mut stream::Stream<'a>) -> stream::Result<()> {
+            if rpc == b"/stall" {
+                let cancel = RequestContext::of(st).unwrap().cancel_token().clone();
+                cancel.cancelled().await;
+                return Ok(());
+            } else if rpc != b"/late" {
+                return Echo::default().serve(rpc, st).await;
+            }
+
+            // late only starts reading once a second passed, and counts the
+            // messages it reads.
+            time::sleep(Duration::from_secs(1)).await;
+            let mut count = 0u8;
+            loop {
+                match st.recv_into(&mut Vec::new()).await {
+                    Ok(()) => count += 1,
+                    Err(stream::Error::StateError(stream::State::EOF)) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+            st.send(&vec![count]).await?;
+            st.close_send().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_stream_burst() {
+        let opts = Options {
+            max_concurrent_streams: Some(8),
+            ..Options::default()
+        };
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move { handle_transport_concurrent(server, Stall, &opts).await });
+
+        // messages sent before the stream reads them are queued for it.
+        let mut tr = transport::Transport::new(client);
+        let mut buf = Vec::new();
+        let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+        st.invoke(b"/late").await.unwrap();
+        for _ in 0..100 {
+            st.send(&vec![1; 100]).await.unwrap();
+        }
+        st.close_send().await.unwrap();
+        drop(st);
+
+        let pkts = read_packets(&mut tr, 2).await;
+        assert_eq!(pkts[0], (1, packet::Kind::Message, vec![100]));
+        assert_eq!(pkts[1], (1, packet::Kind::CloseSend, vec![]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_stream_overflow() {
+        let opts = Options {
+            max_concurrent_streams: Some(8),
+            max_stream_queue: Some(1024),
+            ..Options::default()
+        };
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move { handle_transport_concurrent(server, Stall, &opts).await });
+
+        // a stream that never reads is failed once its queue is full, instead
+        // of holding up the streams invoked after it.
+        let mut tr = transport::Transport::new(client);
+        let mut buf = Vec::new();
+        let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+        st.invoke(b"/stall").await.unwrap();
+        for _ in 0..64 {
+            st.send(&vec![1]).await.unwrap();
+        }
+        drop(st);
+        start(&mut tr, 2, b"/echo").await;
+
+        let pkts = read_packets(&mut tr, 3).await;
+        let mut overflowed = 10u64.to_be_bytes().to_vec();
+        overflowed.extend_from_slice(b"too many packets queued for the stream");
+        assert!(pkts.contains(&(1, packet::Kind::Error, overflowed)));
+        assert!(pkts.contains(&(2, packet::Kind::Message, vec![2])));
+        assert!(pkts.contains(&(2, packet::Kind::CloseSend, vec![])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_keepalive() {
+        let opts = Options {
+            max_concurrent_streams: Some(8),
+            ..Options::default()
+        };
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(
+            async move { handle_transport_concurrent(server, Echo::default(), &opts).await },
+        );
+
+        // pings are answered while a stream is being served.
+        let mut tr = transport::Transport::new(client);
+        start(&mut tr, 1, b"/slow").await;
+        tr.wire().write_all(&[0x83, 0, 0, 1, 7]).await.unwrap();
+        let pkts = read_packets(&mut tr, 2).await;
+        assert_eq!(pkts[0], (1, packet::Kind::Message, vec![1]));
+        assert_eq!(tr.stats().control_frames_in, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_idle_timeout() {
+        let opts = Options {
+            max_concurrent_streams: Some(8),
+            ..options()
+        };
+        let stats = opts.stats.clone();
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(
+            async move { handle_transport_concurrent(server, Echo::default(), &opts).await },
+        );
+
+        // the idle timeout starts once the slow stream is done.
+        let mut tr = transport::Transport::new(client);
+        let begin = Instant::now();
+        start(&mut tr, 1, b"/slow").await;
+        read_packets(&mut tr, 2).await;
+        assert!(tr.read_packet_into(&mut Vec::new()).await.is_err());
+        assert_eq!(begin.elapsed(), Duration::from_secs(6));
+        assert_eq!(stats.idle_timeouts(), 1);
+        assert_eq!(stats.handshake_timeouts(), 0);
+    }
+}
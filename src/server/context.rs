@@ -0,0 +1,366 @@
+use crate::metadata::Metadata;
+use crate::stream;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+// connection ids are unique within the process, whatever options or listener
+// the connection is served with.
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// ConnInfo is what a listener knows about an accepted connection. Listeners
+/// outside the crate build it with new and the setters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConnInfo {
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    /// The DER encoded certificate chain the peer presented, for listeners
+    /// that terminate TLS and verified it.
+    pub peer_certificates: Option<Vec<Vec<u8>>>,
+}
+
+impl ConnInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.peer_addr = Some(addr);
+        self
+    }
+
+    pub fn local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    pub fn peer_certificates(mut self, chain: Vec<Vec<u8>>) -> Self {
+        self.peer_certificates = Some(chain);
+        self
+    }
+}
+
+// conn is the part of the context shared by every stream of a connection.
+#[derive(Debug)]
+pub(super) struct Conn {
+    id: u64,
+    info: ConnInfo,
+}
+
+impl Conn {
+    pub(super) fn new(info: ConnInfo) -> Arc<Conn> {
+        Arc::new(Conn {
+            id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            info,
+        })
+    }
+}
+
+/// RequestContext is what the server knows about the request a stream
+/// serves. The server adds it to the extensions of the stream, next to which
+/// muxes that wrap other muxes can add their own before passing it on.
+#[derive(Debug)]
+pub struct RequestContext {
+    conn: Arc<Conn>,
+    stream: u64,
+    metadata: Metadata,
+    deadline: Option<Instant>,
+    cancel: CancelToken,
+}
+
+impl RequestContext {
+    pub(super) fn new(
+        conn: Arc<Conn>,
+        stream: u64,
+        metadata: Metadata,
+        deadline: Option<Instant>,
+    ) -> RequestContext {
+        RequestContext {
+            conn,
+            stream,
+            metadata,
+            deadline,
+            cancel: CancelToken::default(),
+        }
+    }
+
+    /// Returns the context of the request the stream serves, if it is served
+    /// by the server.
+    pub fn of<'a>(st: &'a stream::Stream<'_>) -> Option<&'a RequestContext> {
+        st.extensions().get()
+    }
+
+    /// Returns the id of the connection, unique within the process.
+    pub fn conn_id(&self) -> u64 {
+        self.conn.id
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.conn.info.peer_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.conn.info.local_addr
+    }
+
+    /// Returns the certificate chain of the peer if the listener terminated
+    /// TLS for the connection.
+    pub fn peer_certificates(&self) -> Option<&[Vec<u8>]> {
+        self.conn.info.peer_certificates.as_deref()
+    }
+
+    pub fn stream_id(&self) -> u64 {
+        self.stream
+    }
+
+    /// Returns the metadata sent with the invoke, empty if there was none.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Returns when the server cancels the request, if it has a deadline.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the token cancelled once the deadline passes, or once the
+    /// connection is closed if streams are served concurrently.
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel
+    }
+}
+
+/// CancelToken tells a handler that its request was cancelled. Clones share
+/// the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<Cancel>);
+
+#[derive(Debug, Default)]
+struct Cancel {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        if !self.0.cancelled.swap(true, Ordering::AcqRel) {
+            self.0.notify.notify_waiters();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Waits until the token is cancelled.
+    pub async fn cancelled(&self) {
+        let notified = self.0.notify.notified();
+        tokio::pin!(notified);
+        // registering before checking makes sure a cancel in between wakes
+        // the wait.
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::Queue;
+    use super::super::{run_with_options, serve_connection, Mux, Options};
+    use super::{CancelToken, ConnInfo, RequestContext};
+    use crate::{conn, metadata, stream, transport, StreamRecv, StreamSend};
+
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net;
+    use tokio::time::{self, Instant};
+
+    #[tokio::test]
+    async fn cancel_token() {
+        let token = CancelToken::default();
+        let waiter = token.clone();
+        let wait = tokio::spawn(async move { waiter.cancelled().await });
+
+        tokio::task::yield_now().await;
+        assert!(!token.is_cancelled());
+        token.cancel();
+        wait.await.unwrap();
+
+        // waits after the cancel return right away.
+        token.cancelled().await;
+        assert!(token.is_cancelled());
+    }
+
+    // tagged is the extension the tag mux adds for the muxes it wraps.
+    struct Tagged(&'static str);
+
+    #[derive(Clone)]
+    struct Tag<M>(M);
+
+    #[async_trait]
+    impl<M: Mux + Send + Sync> Mux for Tag<M> {
+        async fn serve<'a>(&self, rpc: &[u8], st: &mut stream::Stream<'a>) -> stream::Result<()> {
+            st.extensions_mut().insert(Tagged("tagged"));
+            self.0.serve(rpc, st).await
+        }
+    }
+
+    #[derive(Clone)]
+    struct Whoami;
+
+    #[async_trait]
+    impl Mux for Whoami {
+        async fn serve<'a>(&self, _: &[u8], st: &mut stream::Stream<'a>) -> stream::Result<()> {
+            let ctx = RequestContext::of(st).unwrap();
+            let out = format!(
+                "{}|{:?}|{:?}|{:?}|{}|{}|{}",
+                ctx.conn_id(),
+                ctx.peer_addr(),
+                ctx.local_addr(),
+                ctx.peer_certificates(),
+                ctx.stream_id(),
+                ctx.metadata().get("user").map_or("", String::as_str),
+                st.extensions().get::<Tagged>().map_or("", |tag| tag.0),
+            );
+            assert!(ctx.deadline().is_none());
+
+            st.recv_into(&mut Vec::new()).await?;
+            st.send(&out.into_bytes()).await?;
+            st.close_send().await
+        }
+    }
+
+    #[tokio::test]
+    async fn request_context() {
+        for &concurrent in &[None, Some(8)] {
+            let lis = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = lis.local_addr().unwrap();
+            let opts = Options {
+                max_concurrent_streams: concurrent,
+                ..Options::default()
+            };
+            tokio::spawn(run_with_options(lis, Tag(Whoami), opts));
+
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let socket = net::TcpStream::connect(addr).await.unwrap();
+                let local = socket.local_addr().unwrap();
+                let mut conn = conn::Conn::new(transport::Transport::new(socket));
+
+                let md = metadata::Metadata::from([("user".to_string(), "alice".to_string())]);
+                let mut st = conn.stream_with_metadata(b"/whoami", &md).await.unwrap();
+                st.send(&vec![]).await.unwrap();
+                st.close_send().await.unwrap();
+                let mut out = Vec::new();
+                st.recv_into(&mut out).await.unwrap();
+
+                let out = String::from_utf8(out).unwrap();
+                let (id, rest) = out.split_once('|').unwrap();
+                let expected = format!("Some({})|Some({})|None|1|alice|tagged", local, addr);
+                assert_eq!(rest, expected);
+                ids.push(id.to_string());
+            }
+            assert_ne!(ids[0], ids[1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_certificates() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let lis = Queue(tokio::sync::Mutex::new(rx));
+        tokio::spawn(run_with_options(lis, Tag(Whoami), Options::default()));
+
+        let (client, server) = tokio::io::duplex(1024);
+        let info = ConnInfo::new()
+            .peer_addr(([10, 0, 0, 1], 1234).into())
+            .peer_certificates(vec![vec![1, 2], vec![3]]);
+        tx.send(Ok((server, info))).unwrap();
+
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        let mut out = Vec::new();
+        conn.invoke_into(b"/whoami", &vec![], &mut out)
+            .await
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let rest = out.split_once('|').unwrap().1;
+        assert_eq!(
+            rest,
+            "Some(10.0.0.1:1234)|None|Some([[1, 2], [3]])|1||tagged"
+        );
+    }
+
+    #[derive(Clone)]
+    struct Cancelled(Arc<tokio::sync::Notify>);
+
+    #[async_trait]
+    impl Mux for Cancelled {
+        async fn serve<'a>(&self, _: &[u8], st: &mut stream::Stream<'a>) -> stream::Result<()> {
+            let ctx = RequestContext::of(st).unwrap();
+            let cancel = ctx.cancel_token().clone();
+            let deadline = ctx.deadline();
+            cancel.cancelled().await;
+            self.0.notify_one();
+
+            let expired = deadline.is_some_and(|at| Instant::now() >= at);
+            st.send(&vec![expired as u8]).await?;
+            st.close_send().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_cancellation() {
+        for &concurrent in &[None, Some(8)] {
+            let opts = Options {
+                max_concurrent_streams: concurrent,
+                stream_timeout: Some(Duration::from_secs(1)),
+                ..Options::default()
+            };
+            let mux = Cancelled(Arc::new(tokio::sync::Notify::new()));
+            let (client, server) = tokio::io::duplex(1024);
+            let mux_ = mux.clone();
+            tokio::spawn(async move {
+                serve_connection(server, ConnInfo::default(), mux_, &opts).await
+            });
+
+            // the context is cancelled at the deadline.
+            let mut conn = conn::Conn::new(transport::Transport::new(client));
+            let begin = Instant::now();
+            let mut st = conn.stream(b"/wait").await.unwrap();
+            let mut out = Vec::new();
+            st.recv_into(&mut out).await.unwrap();
+            assert_eq!(out, vec![1]);
+            assert_eq!(begin.elapsed(), Duration::from_secs(1));
+            mux.0.notified().await;
+        }
+
+        // with concurrent streams, it is also cancelled once the connection
+        // is closed.
+        let opts = Options {
+            max_concurrent_streams: Some(8),
+            ..Options::default()
+        };
+        let mux = Cancelled(Arc::new(tokio::sync::Notify::new()));
+        let (client, server) = tokio::io::duplex(1024);
+        let mux_ = mux.clone();
+        let handle = tokio::spawn(async move {
+            serve_connection(server, ConnInfo::default(), mux_, &opts).await
+        });
+
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        let mut st = conn.stream(b"/wait").await.unwrap();
+        st.transport().flush().await.unwrap();
+        drop(st);
+        time::sleep(Duration::from_secs(1)).await;
+        drop(conn);
+        mux.0.notified().await;
+        handle.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use super::Stats;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::stream;
+
+/// Overflow is what the server does with connections past max_connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Stop accepting until a connection is closed. New connections wait in
+    /// the listen backlog of the system.
+    #[default]
+    Wait,
+    /// Accept new connections and close them right away.
+    Reject,
+}
+
+/// Backoff is how long the server waits before accepting again after a
+/// transient accept error, such as running out of file descriptors. The
+/// wait starts at min and doubles with every consecutive error up to max.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            min: Duration::from_millis(5),
+            max: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Backoff {
+    // next returns the wait after the previous one, if any.
+    pub(crate) fn next(&self, prev: Option<Duration>) -> Duration {
+        match prev {
+            Some(prev) => (prev * 2).min(self.max),
+            None => self.min,
+        }
+    }
+}
+
+// transient returns true if an accept error is about the connection being
+// accepted or the resources of the system, rather than the listener itself.
+pub(crate) fn transient(err: &stream::Error) -> bool {
+    // EMFILE and ENFILE have the same numbers on every unix.
+    const EMFILE: i32 = 24;
+    const ENFILE: i32 = 23;
+
+    let err = match err {
+        stream::Error::IOError(err) => err,
+        _ => return false,
+    };
+    match err.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::OutOfMemory => true,
+        _ => cfg!(unix) && matches!(err.raw_os_error(), Some(EMFILE) | Some(ENFILE)),
+    }
+}
+
+// counts are the connections being served from every IP address.
+type Counts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+// limits track the connections being served to enforce max_connections and
+// max_connections_per_ip.
+pub(super) struct Limits {
+    conns: Option<Arc<Semaphore>>,
+    overflow: Overflow,
+    per_ip: Option<(usize, Counts)>,
+    stats: Stats,
+}
+
+// zero fails on a limit of zero, which would never let a connection or
+// stream in.
+fn zero<T>(name: &str) -> stream::Result<T> {
+    let msg = format!("{} must be positive", name);
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into())
+}
+
+// check_streams fails on stream limits of zero. It is called by every entry
+// point that serves a connection.
+pub(super) fn check_streams(opts: &super::Options) -> stream::Result<()> {
+    if opts.max_concurrent_streams == Some(0) {
+        return zero("max_concurrent_streams");
+    } else if opts.max_stream_queue == Some(0) {
+        return zero("max_stream_queue");
+    }
+    Ok(())
+}
+
+impl Limits {
+    // new fails on limits of zero.
+    pub(super) fn new(opts: &super::Options) -> stream::Result<Limits> {
+        if opts.max_connections == Some(0) {
+            return zero("max_connections");
+        } else if opts.max_connections_per_ip == Some(0) {
+            return zero("max_connections_per_ip");
+        }
+        check_streams(opts)?;
+
+        Ok(Limits {
+            conns: opts.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            overflow: opts.overflow,
+            per_ip: opts
+                .max_connections_per_ip
+                .map(|n| (n, Arc::new(Mutex::new(HashMap::new())))),
+            stats: opts.stats.clone(),
+        })
+    }
+
+    // wait waits for a free connection if the server waits on overflow, and
+    // returns the permit to hand to admit.
+    pub(super) async fn wait(&self) -> Option<OwnedSemaphorePermit> {
+        match (&self.conns, self.overflow) {
+            (Some(conns), Overflow::Wait) => conns.clone().acquire_owned().await.ok(),
+            _ => None,
+        }
+    }
+
+    // admit returns the slot of an accepted connection from ip, or None if
+    // the connection is over a limit and must be closed.
+    pub(super) fn admit(
+        &self,
+        permit: Option<OwnedSemaphorePermit>,
+        ip: Option<IpAddr>,
+    ) -> Option<Slot> {
+        let counters = &self.stats.0;
+        let permit = match (permit, &self.conns) {
+            (Some(permit), _) => Some(permit),
+            (None, Some(conns)) => match conns.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    Stats::count(&counters.rejected_connections);
+                    return None;
+                }
+            },
+            (None, None) => None,
+        };
+
+        let ip = match (&self.per_ip, ip) {
+            (Some((max, table)), Some(ip)) => {
+                let mut counts = table.lock().unwrap();
+                if counts.get(&ip).copied().unwrap_or(0) >= *max {
+                    Stats::count(&counters.rejected_per_ip);
+                    return None;
+                }
+                *counts.entry(ip).or_insert(0) += 1;
+                Some((ip, table.clone()))
+            }
+            _ => None,
+        };
+
+        Stats::count(&counters.active_connections);
+        Some(Slot {
+            _permit: permit,
+            ip,
+            stats: self.stats.clone(),
+        })
+    }
+}
+
+// slot is held by a connection while it is served, and frees its place under
+// the limits once dropped.
+pub(super) struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+    ip: Option<(IpAddr, Counts)>,
+    stats: Stats,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some((ip, table)) = &self.ip {
+            let mut counts = table.lock().unwrap();
+            if let Some(count) = counts.get_mut(ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(ip);
+                }
+            }
+        }
+        self.stats
+            .0
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{closed, connect, echo, serve, Queue};
+    use super::super::{
+        handle_transport_concurrent, run_with_options, serve_connection, ConnInfo, Options,
+    };
+    use super::Overflow;
+    use crate::stream;
+    use crate::testing::Echo;
+
+    use std::time::Duration;
+    use tokio::time::{self, Instant};
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_limits() {
+        let opts = Options {
+            max_connections: Some(2),
+            overflow: Overflow::Reject,
+            max_connections_per_ip: Some(1),
+            ..Options::default()
+        };
+        let stats = opts.stats.clone();
+        let tx = serve(opts);
+
+        let first = echo(connect(&tx, [10, 0, 0, 1])).await;
+        assert!(closed(connect(&tx, [10, 0, 0, 1])).await);
+        let second = echo(connect(&tx, [10, 0, 0, 2])).await;
+        assert!(closed(connect(&tx, [10, 0, 0, 3])).await);
+        assert_eq!(stats.active_connections(), 2);
+
+        // closing a connection frees its place under both limits.
+        drop(first);
+        time::sleep(Duration::from_millis(1)).await;
+        let third = echo(connect(&tx, [10, 0, 0, 1])).await;
+
+        assert_eq!(stats.accepted_connections(), 5);
+        assert_eq!(stats.rejected_per_ip(), 1);
+        assert_eq!(stats.rejected_connections(), 1);
+        assert_eq!(stats.active_connections(), 2);
+
+        drop((second, third));
+        time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(stats.active_connections(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_connections() {
+        let opts = Options {
+            max_connections: Some(1),
+            ..Options::default()
+        };
+        let stats = opts.stats.clone();
+        let tx = serve(opts);
+
+        let first = echo(connect(&tx, [10, 0, 0, 1])).await;
+        let waiting = connect(&tx, [10, 0, 0, 2]);
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(stats.accepted_connections(), 1);
+
+        drop(first);
+        echo(waiting).await;
+        assert_eq!(stats.accepted_connections(), 2);
+        assert_eq!(stats.rejected_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn zero_limits() {
+        let invalid = |res: stream::Result<()>| match res {
+            Err(stream::Error::IOError(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        };
+        let limits: [fn(&mut Options); 4] = [
+            |opts| opts.max_connections = Some(0),
+            |opts| opts.max_connections_per_ip = Some(0),
+            |opts| opts.max_concurrent_streams = Some(0),
+            |opts| opts.max_stream_queue = Some(0),
+        ];
+        for (i, limit) in limits.iter().enumerate() {
+            let mut opts = Options::default();
+            limit(&mut opts);
+            let (_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let lis = Queue(tokio::sync::Mutex::new(rx));
+            invalid(run_with_options(lis, Echo::default(), opts.clone()).await);
+
+            // the stream limits also fail serving a single connection.
+            if i < 2 {
+                continue;
+            }
+            let (_client, server) = tokio::io::duplex(1024);
+            let info = ConnInfo::default();
+            invalid(serve_connection(server, info, Echo::default(), &opts).await);
+            let (_client, server) = tokio::io::duplex(1024);
+            invalid(handle_transport_concurrent(server, Echo::default(), &opts).await);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accept_errors() {
+        let opts = Options::default();
+        let stats = opts.stats.clone();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let lis = Queue(tokio::sync::Mutex::new(rx));
+
+        // running out of file descriptors backs off and keeps accepting.
+        for _ in 0..3 {
+            let err = std::io::Error::from_raw_os_error(24);
+            tx.send(Err(err.into())).unwrap();
+        }
+        let client = connect(&tx, [10, 0, 0, 1]);
+        let start = Instant::now();
+        let server =
+            tokio::spawn(async move { run_with_options(lis, Echo::default(), opts).await });
+        echo(client).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(5 + 10 + 20));
+        assert_eq!(stats.accept_errors(), 3);
+
+        // other errors stop the server.
+        drop(tx);
+        assert!(server.await.unwrap().is_err());
+    }
+}
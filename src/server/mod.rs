@@ -1,10 +1,28 @@
 use crate::Transport;
-use crate::{stream, transport, wire::packet};
+use crate::{metadata, pool, stream, transport, wire::packet};
 
 use async_trait::async_trait;
+use std::future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use tokio::net;
 use tokio::task;
+use tokio::time::{self, Instant};
+
+mod concurrent;
+mod context;
+pub(crate) mod limits;
+mod panic;
+
+pub use context::{CancelToken, ConnInfo, RequestContext};
+pub use limits::{Backoff, Overflow};
+pub use panic::{Panic, PanicHook, INTERNAL_ERROR_CODE};
+
+/// Code of the error sent to the remote when the mux fails a stream, or when
+/// the server refuses or gives up on a stream.
+pub const ERROR_CODE: u64 = 10;
 
 #[async_trait]
 pub trait Mux: Clone {
@@ -12,8 +30,14 @@ pub trait Mux: Clone {
 }
 
 #[async_trait]
-pub trait Listener<T> {
+pub trait Listener<T>: Sync {
     async fn accept(&self) -> stream::Result<T>;
+
+    /// Accepts a connection along with what the listener knows about it.
+    /// Per IP limits only apply to connections with a peer address.
+    async fn accept_with_info(&self) -> stream::Result<(T, ConnInfo)> {
+        Ok((self.accept().await?, ConnInfo::default()))
+    }
 }
 
 #[async_trait]
@@ -23,6 +47,170 @@ impl Listener<net::TcpStream> for net::TcpListener {
         socket.set_nodelay(true)?;
         Ok(socket)
     }
+
+    async fn accept_with_info(&self) -> stream::Result<(net::TcpStream, ConnInfo)> {
+        let (socket, addr) = net::TcpListener::accept(self).await?;
+        socket.set_nodelay(true)?;
+        let info = ConnInfo {
+            peer_addr: Some(addr),
+            local_addr: socket.local_addr().ok(),
+            peer_certificates: None,
+        };
+        Ok((socket, info))
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Listener<net::UnixStream> for net::UnixListener {
+    async fn accept(&self) -> stream::Result<net::UnixStream> {
+        let socket = net::UnixListener::accept(self).await.map(|s| s.0)?;
+        Ok(socket)
+    }
+}
+
+/// Options configure how the server handles every connection.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Keepalive pings for connections. Disabled when None.
+    pub keepalive: Option<transport::Keepalive>,
+    /// How long a new connection may take to send its first invoke.
+    pub handshake_timeout: Option<Duration>,
+    /// How long a connection may take to send an invoke after a stream ends.
+    pub idle_timeout: Option<Duration>,
+    /// How long a read may wait for the rest of a partially received packet.
+    pub read_timeout: Option<Duration>,
+    /// Sizes and limits for the transport and streams of connections.
+    pub transport: transport::TransportOptions,
+    /// How many connections run serves at once. Unlimited when None, and
+    /// run fails right away when zero.
+    pub max_connections: Option<usize>,
+    /// What run does with connections past max_connections.
+    pub overflow: Overflow,
+    /// How many connections from the same IP address run serves at once.
+    /// Connections over the limit are closed right away. Run fails right away
+    /// when zero.
+    pub max_connections_per_ip: Option<usize>,
+    /// How long run waits after a transient accept error.
+    pub accept_backoff: Backoff,
+    /// Serves the streams of connections accepted by run in their own tasks,
+    /// at most this many at once per connection. Invokes past the limit fail
+    /// with an error. A stream failing with an error ends only that stream,
+    /// while a connection serving one stream at a time is closed by it. The
+    /// streams of a closed connection are cancelled, and aborted if still
+    /// running a second later. When None, run serves one stream at a time,
+    /// and run fails right away when zero.
+    pub max_concurrent_streams: Option<usize>,
+    /// How many bytes of packets are queued for a stream served in its own
+    /// task until it reads them, 4 MiB when None. A stream falling further
+    /// behind fails with an error rather than holding up the other streams
+    /// of its connection. Run fails right away when zero.
+    pub max_stream_queue: Option<usize>,
+    /// Called with every panic of the mux. Whether or not it is set, a panic
+    /// fails its stream with an error of INTERNAL_ERROR_CODE and the
+    /// connection goes on serving other streams.
+    pub panic_hook: Option<PanicHook>,
+    /// How long the mux may take to serve a stream. The request context of
+    /// the stream has the deadline and is cancelled once it passes. The mux
+    /// is not stopped.
+    pub stream_timeout: Option<Duration>,
+    /// Counts connections closed because of the timeouts and limits, and
+    /// what the transports of connections have read and written.
+    pub stats: Stats,
+}
+
+/// Stats is a handle to counters shared by every connection handled with the
+/// same Options.
+#[derive(Debug, Clone, Default)]
+pub struct Stats(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    handshake_timeouts: AtomicU64,
+    idle_timeouts: AtomicU64,
+    read_timeouts: AtomicU64,
+    accepted_connections: AtomicU64,
+    active_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    rejected_per_ip: AtomicU64,
+    accept_errors: AtomicU64,
+    refused_streams: AtomicU64,
+    panics: AtomicU64,
+    transport: Mutex<transport::Stats>,
+}
+
+impl Stats {
+    pub fn handshake_timeouts(&self) -> u64 {
+        self.0.handshake_timeouts.load(Ordering::Relaxed)
+    }
+
+    pub fn idle_timeouts(&self) -> u64 {
+        self.0.idle_timeouts.load(Ordering::Relaxed)
+    }
+
+    pub fn read_timeouts(&self) -> u64 {
+        self.0.read_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many connections run has accepted, including rejected
+    /// ones.
+    pub fn accepted_connections(&self) -> u64 {
+        self.0.accepted_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many connections run is serving.
+    pub fn active_connections(&self) -> u64 {
+        self.0.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many connections were closed because of max_connections.
+    pub fn rejected_connections(&self) -> u64 {
+        self.0.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many connections were closed because of
+    /// max_connections_per_ip.
+    pub fn rejected_per_ip(&self) -> u64 {
+        self.0.rejected_per_ip.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many transient accept errors run has backed off from.
+    pub fn accept_errors(&self) -> u64 {
+        self.0.accept_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many invokes failed because of max_concurrent_streams.
+    pub fn refused_streams(&self) -> u64 {
+        self.0.refused_streams.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many times the mux panicked.
+    pub fn panics(&self) -> u64 {
+        self.0.panics.load(Ordering::Relaxed)
+    }
+
+    /// Returns the transport stats of every connection added together. The
+    /// stats of a connection are added after each stream it serves and once
+    /// it is closed.
+    pub fn transport(&self) -> transport::Stats {
+        *self.0.transport.lock().unwrap()
+    }
+
+    // report adds the stats of a connection since they were last reported.
+    fn report(&self, stats: transport::Stats, reported: &mut transport::Stats) {
+        self.0.transport.lock().unwrap().add(&stats.since(reported));
+        *reported = stats;
+    }
+
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn transport_error(&self, err: &transport::Error) {
+        if err.kind() == transport::ErrorKind::ReadTimeout {
+            Stats::count(&self.0.read_timeouts);
+        }
+    }
 }
 
 pub async fn run<L, W, M>(lis: L, mux: M) -> stream::Result<()>
@@ -31,43 +219,539 @@ where
     W: crate::Wire + Send + 'static,
     M: Mux + Send + Sync + 'static,
 {
+    run_with_options(lis, mux, Options::default()).await
+}
+
+pub async fn run_with_options<L, W, M>(lis: L, mux: M, opts: Options) -> stream::Result<()>
+where
+    L: Listener<W>,
+    W: crate::Wire + Send + 'static,
+    M: Mux + Send + Sync + 'static,
+{
+    let limits = limits::Limits::new(&opts)?;
+    let mut backoff = None;
+
     loop {
-        let wire = lis.accept().await?;
+        let permit = limits.wait().await;
+        let (wire, info) = match lis.accept_with_info().await {
+            Ok(accepted) => accepted,
+            Err(err) if limits::transient(&err) => {
+                Stats::count(&opts.stats.0.accept_errors);
+                let delay = opts.accept_backoff.next(backoff);
+                backoff = Some(delay);
+                time::sleep(delay).await;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        backoff = None;
+        Stats::count(&opts.stats.0.accepted_connections);
+
+        // dropping the wire of a rejected connection closes it.
+        let slot = match limits.admit(permit, info.peer_addr.map(|addr| addr.ip())) {
+            Some(slot) => slot,
+            None => continue,
+        };
         let mux = mux.clone();
+        let opts = opts.clone();
         task::spawn(async move {
-            let _ = handle_transport::<W, M>(wire, mux).await;
+            let _slot = slot;
+            serve_connection(wire, info, mux, &opts).await
         });
     }
 }
 
-pub async fn handle_transport<W, M>(mut wire: W, mux: M)
+/// Serves a connection accepted outside of run like run would, with the
+/// streams served concurrently if max_concurrent_streams is set. Like run, it
+/// fails without serving the connection on stream limits of zero.
+pub async fn serve_connection<W, M>(
+    wire: W,
+    info: ConnInfo,
+    mux: M,
+    opts: &Options,
+) -> stream::Result<()>
+where
+    W: crate::Wire,
+    M: Mux + Send + Sync + 'static,
+{
+    limits::check_streams(opts)?;
+    let conn = context::Conn::new(info);
+    match opts.max_concurrent_streams {
+        Some(_) => concurrent::handle(wire, &conn, mux, opts).await,
+        None => handle_sequential(wire, &conn, mux, opts).await,
+    }
+    Ok(())
+}
+
+pub async fn handle_transport<W, M>(wire: W, mux: M)
+where
+    W: crate::Wire,
+    M: Mux,
+{
+    handle_transport_with_options(wire, mux, &Options::default()).await
+}
+
+pub async fn handle_transport_with_options<W, M>(wire: W, mux: M, opts: &Options)
+where
+    W: crate::Wire,
+    M: Mux,
+{
+    let conn = context::Conn::new(ConnInfo::default());
+    handle_sequential(wire, &conn, mux, opts).await
+}
+
+async fn handle_sequential<W, M>(mut wire: W, conn: &Arc<context::Conn>, mux: M, opts: &Options)
 where
     W: crate::Wire,
     M: Mux,
 {
-    let mut tr = transport::Transport::new(&mut wire);
-    let mut mbuf = Vec::new();
-    let mut sbuf = Vec::new();
+    let mut tr = transport(&mut wire, opts);
+    let mut reported = transport::Stats::default();
+    serve_transport(&mut tr, conn, mux, opts, &mut reported).await;
+    opts.stats.report(tr.stats(), &mut reported);
+}
+
+/// Like handle_transport_with_options, but serves every stream in its own
+/// task so that a slow stream does not hold up the others. At most
+/// max_concurrent_streams are served at once, or any number if it is None.
+/// It fails without serving the connection on stream limits of zero.
+pub async fn handle_transport_concurrent<W, M>(
+    wire: W,
+    mux: M,
+    opts: &Options,
+) -> stream::Result<()>
+where
+    W: crate::Wire,
+    M: Mux + Send + Sync + 'static,
+{
+    limits::check_streams(opts)?;
+    let conn = context::Conn::new(ConnInfo::default());
+    concurrent::handle(wire, &conn, mux, opts).await;
+    Ok(())
+}
+
+// transport returns a transport for a connection configured by the options.
+fn transport<W: crate::Wire>(wire: W, opts: &Options) -> transport::Transport<W> {
+    let mut tr = transport::Transport::with_options(wire, opts.transport.clone());
+    if let Some(keepalive) = opts.keepalive {
+        tr.set_keepalive(keepalive);
+    }
+    if let Some(timeout) = opts.read_timeout {
+        tr.set_read_timeout(timeout);
+    }
+    tr
+}
+
+impl Options {
+    // wait_timeout returns how long a connection may wait for an invoke and
+    // the counter of connections closed for waiting too long. served is true
+    // once the connection has served a stream.
+    fn wait_timeout(&self, served: bool) -> (Option<Duration>, &AtomicU64) {
+        match served {
+            false => (self.handshake_timeout, &self.stats.0.handshake_timeouts),
+            true => (self.idle_timeout, &self.stats.0.idle_timeouts),
+        }
+    }
+
+    // request_context returns the context of a stream invoked now.
+    fn request_context(
+        &self,
+        conn: &Arc<context::Conn>,
+        stream: u64,
+        md: metadata::Metadata,
+    ) -> RequestContext {
+        let deadline = self.stream_timeout.map(|timeout| Instant::now() + timeout);
+        RequestContext::new(conn.clone(), stream, md, deadline)
+    }
+}
+
+// take_metadata returns the metadata received for the stream being invoked.
+// metadata is only sent right before the invoke of its stream.
+fn take_metadata(
+    pending: &mut Option<(u64, metadata::Metadata)>,
+    stream: u64,
+) -> metadata::Metadata {
+    match pending.take() {
+        Some((sid, md)) if sid == stream => md,
+        _ => metadata::Metadata::new(),
+    }
+}
+
+// parse_metadata returns the metadata of an InvokeMetadata packet. metadata
+// that does not parse is ignored like other packets that are not invokes.
+fn parse_metadata(stream: u64, data: &[u8]) -> Option<(u64, metadata::Metadata)> {
+    metadata::read(data).ok().map(|md| (stream, md))
+}
+
+// expire waits until the deadline, or forever if there is none.
+async fn expire(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+async fn serve_transport<M: Mux>(
+    tr: &mut dyn crate::Transport,
+    conn: &Arc<context::Conn>,
+    mux: M,
+    opts: &Options,
+    reported: &mut transport::Stats,
+) {
+    let mut bufs = Buffers {
+        pool: &opts.transport.pool,
+        mbuf: Vec::new(),
+        sbuf: Vec::new(),
+    };
+    let mut served = false;
+    let mut md = None;
 
     loop {
-        let (id, kind) = match tr.read_packet_into(&mut mbuf).await {
-            Ok((id, kind)) => (id, kind),
-            Err(_) => return,
+        // the deadline covers every packet read until the next invoke so that
+        // other packets can not keep the connection open.
+        let (timeout, counter) = opts.wait_timeout(served);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        let id = loop {
+            let read = tr.read_packet_into(&mut bufs.mbuf);
+            let res = match deadline {
+                Some(deadline) => match time::timeout_at(deadline, read).await {
+                    Ok(res) => res,
+                    Err(_) => return Stats::count(counter),
+                },
+                None => read.await,
+            };
+
+            match res {
+                Ok((id, packet::Kind::Invoke)) => break id,
+                Ok((id, packet::Kind::InvokeMetadata)) => {
+                    md = parse_metadata(id.stream, &bufs.mbuf);
+                }
+                Ok(_) => continue,
+                Err(err) => return opts.stats.transport_error(&err),
+            }
         };
-        if kind != packet::Kind::Invoke {
-            continue;
+        served = true;
+
+        let ctx = opts.request_context(conn, id.stream, take_metadata(&mut md, id.stream));
+        let mut st = stream::Stream::with_options(id.stream, tr, &mut bufs.sbuf, &opts.transport);
+        st.extensions_mut().insert(ctx);
+        if !serve_stream(&mux, &bufs.mbuf, &mut st, opts).await {
+            return;
+        }
+        drop(st);
+
+        // idle connections hold on to as little memory as possible.
+        bufs.release();
+        opts.stats.report(tr.stats(), reported);
+    }
+}
+
+// serve_stream serves a stream with the mux, sending the error the mux fails
+// or panics with to the remote, and cancels its context at the deadline. it
+// returns false if the mux failed, or panicked midway through a write.
+async fn serve_stream<M: Mux>(
+    mux: &M,
+    rpc: &[u8],
+    st: &mut stream::Stream<'_>,
+    opts: &Options,
+) -> bool {
+    let (deadline, cancel) = match RequestContext::of(st) {
+        Some(ctx) => (ctx.deadline(), ctx.cancel_token().clone()),
+        None => (None, CancelToken::default()),
+    };
+
+    let res = {
+        let serve = panic::catch_unwind(mux.serve(rpc, st));
+        tokio::pin!(serve);
+        loop {
+            tokio::select! {
+                res = &mut serve => break res,
+                _ = expire(deadline), if !cancel.is_cancelled() => cancel.cancel(),
+            }
         }
+    };
 
-        let mut st = stream::Stream::new(id.stream, &mut tr, &mut sbuf);
+    let res = match res {
+        Ok(res) => res,
+        Err(payload) => {
+            Stats::count(&opts.stats.0.panics);
+            if let Some(hook) = &opts.panic_hook {
+                hook.call(&Panic {
+                    rpc,
+                    stream: st.id(),
+                    payload: payload.as_ref(),
+                });
+            }
+            // the mux may have left frames in the write buffer, or even a
+            // write to the wire midway, which the error must not follow.
+            if !st.transport().discard() {
+                return false;
+            }
+            let msg = panic::INTERNAL_ERROR_MESSAGE;
+            let _ = st.error(msg, INTERNAL_ERROR_CODE).await;
+            return true;
+        }
+    };
 
-        match mux.serve(&mbuf, &mut st).await {
-            Ok(()) => (),
-            Err(stream::Error::StateError(stream::State::EOF)) => (),
-            Err(err) => {
-                let msg = err.to_string();
-                let _ = st.error(&*msg, 10).await;
-                return;
+    match res {
+        Ok(()) => true,
+        Err(stream::Error::StateError(stream::State::EOF)) => true,
+        Err(err) => {
+            if let stream::Error::TransportError(err) = &err {
+                opts.stats.transport_error(err);
             }
+            let msg = err.to_string();
+            let _ = st.error(&msg, ERROR_CODE).await;
+            false
+        }
+    }
+}
+
+// buffers are the buffers of a connection, given back to the pool when the
+// connection is done with them.
+struct Buffers<'a> {
+    pool: &'a Option<Arc<dyn pool::Pool>>,
+    mbuf: Vec<u8>,
+    sbuf: Vec<u8>,
+}
+
+impl<'a> Buffers<'a> {
+    fn release(&mut self) {
+        pool::release(self.pool, &mut self.mbuf);
+        pool::release(self.pool, &mut self.sbuf);
+    }
+}
+
+impl<'a> Drop for Buffers<'a> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn;
+    use crate::testing::Echo;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    pub(super) fn options() -> Options {
+        Options {
+            handshake_timeout: Some(Duration::from_secs(1)),
+            idle_timeout: Some(Duration::from_secs(5)),
+            read_timeout: Some(Duration::from_secs(2)),
+            ..Options::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_timeout() {
+        let opts = options();
+        let stats = opts.stats.clone();
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(
+            async move { handle_transport_with_options(server, Echo::default(), &opts).await },
+        );
+
+        // packets other than invokes do not extend the deadline.
+        let start = Instant::now();
+        for _ in 0..4 {
+            client.write_all(&[0x05, 0x01, 0x01, 0x00]).await.unwrap();
+            time::sleep(Duration::from_millis(200)).await;
         }
+        client.read_to_end(&mut Vec::new()).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(stats.handshake_timeouts(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_and_read_timeouts() {
+        let opts = options();
+        let stats = opts.stats.clone();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let opts_ = opts.clone();
+        tokio::spawn(async move {
+            handle_transport_with_options(server, Echo::default(), &opts_).await
+        });
+
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        let mut out = Vec::new();
+        conn.invoke_into(b"/echo", &vec![1], &mut out)
+            .await
+            .unwrap();
+        time::sleep(Duration::from_secs(6)).await;
+        let res = conn.invoke_into(b"/echo", &vec![1], &mut out).await;
+        assert!(res.is_err());
+        assert_eq!(stats.idle_timeouts(), 1);
+
+        // a partial frame stalls the read inside of the stream.
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(
+            async move { handle_transport_with_options(server, Echo::default(), &opts).await },
+        );
+        client
+            .write_all(&[0x03, 0x01, 0x01, 0x01, b'/', 0x05, 0x01, 0x02, 0x02, 0x00])
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(stats.read_timeouts(), 1);
+        assert_eq!(stats.handshake_timeouts(), 0);
+    }
+
+    #[tokio::test]
+    async fn pooled_buffers() {
+        let pool = pool::BufferPool::new(1 << 20, 4 << 20);
+        let opts = Options {
+            transport: transport::TransportOptions::new().pool(pool.clone()),
+            ..Options::default()
+        };
+
+        for _ in 0..10 {
+            let (client, server) = tokio::io::duplex(1024);
+            let opts = opts.clone();
+            let handle = tokio::spawn(async move {
+                handle_transport_with_options(server, Echo::default(), &opts).await
+            });
+
+            let mut conn = conn::Conn::new(transport::Transport::new(client));
+            let mut out = Vec::new();
+            conn.invoke_into(b"/echo", &vec![1; 100_000], &mut out)
+                .await
+                .unwrap();
+            assert_eq!(out.len(), 100_000);
+            drop(conn);
+            handle.await.unwrap();
+        }
+
+        // connections after the first reuse the buffers of earlier ones, and
+        // every buffer is given back once its connection is closed.
+        let stats = pool.stats();
+        assert!(stats.hits >= 9 * 3, "{:?}", stats);
+        assert!(stats.retained_buffers > 0);
+        assert!(stats.retained_bytes <= 4 << 20);
+        assert_eq!(stats.gets, stats.puts, "{:?}", stats);
+    }
+
+    #[tokio::test]
+    async fn transport_stats() {
+        let opts = Options::default();
+        let stats = opts.stats.clone();
+
+        for _ in 0..3 {
+            let (client, server) = tokio::io::duplex(1024);
+            let opts = opts.clone();
+            let handle = tokio::spawn(async move {
+                handle_transport_with_options(server, Echo::default(), &opts).await
+            });
+
+            let mut conn = conn::Conn::new(transport::Transport::new(client));
+            let mut out = Vec::new();
+            conn.invoke_into(b"/echo", &vec![1], &mut out)
+                .await
+                .unwrap();
+            conn.invoke_into(b"/echo", &vec![2], &mut out)
+                .await
+                .unwrap();
+            let sent = conn.transport().stats();
+            drop(conn);
+            handle.await.unwrap();
+
+            assert_eq!(sent.packets_out.invoke, 2);
+        }
+
+        let stats = stats.transport();
+        assert_eq!(stats.packets_in.invoke, 6);
+        assert_eq!(stats.packets_in.message, 6);
+        assert_eq!(stats.packets_in.close, 6);
+        assert_eq!(stats.packets_out.message, 6);
+        assert_eq!(stats.packets_out.close_send, 6);
+        assert_eq!(stats.errors, 0);
+    }
+
+    #[tokio::test]
+    async fn transport_error_message() {
+        // a mux failing with the transport error of a backend it called.
+        #[derive(Clone)]
+        struct Backend;
+
+        #[async_trait]
+        impl Mux for Backend {
+            async fn serve<'a>(&self, _: &[u8], _: &mut stream::Stream<'a>) -> stream::Result<()> {
+                let err = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+                Err(transport::Error::io(transport::Op::Write, err).into())
+            }
+        }
+
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move { handle_transport(server, Backend).await });
+
+        // the message is the Display of the error, which peers may match on.
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        match conn.invoke_into(b"/rpc", &vec![1], &mut Vec::new()).await {
+            Err(stream::Error::StateError(stream::State::RemoteError((code, msg)))) => {
+                assert_eq!(code, ERROR_CODE);
+                assert_eq!(msg, "TransportError(transport write: IOError: broken pipe)");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    pub(super) type Accepted = stream::Result<(tokio::io::DuplexStream, ConnInfo)>;
+
+    // queue is a listener that accepts whatever is sent to it.
+    pub(super) struct Queue(
+        pub(super) tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Accepted>>,
+    );
+
+    #[async_trait]
+    impl Listener<tokio::io::DuplexStream> for Queue {
+        async fn accept(&self) -> stream::Result<tokio::io::DuplexStream> {
+            Ok(self.accept_with_info().await?.0)
+        }
+
+        async fn accept_with_info(&self) -> Accepted {
+            match self.0.lock().await.recv().await {
+                Some(accepted) => accepted,
+                None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into()),
+            }
+        }
+    }
+
+    pub(super) fn serve(opts: Options) -> tokio::sync::mpsc::UnboundedSender<Accepted> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let lis = Queue(tokio::sync::Mutex::new(rx));
+        tokio::spawn(async move { run_with_options(lis, Echo::default(), opts).await });
+        tx
+    }
+
+    pub(super) fn connect(
+        tx: &tokio::sync::mpsc::UnboundedSender<Accepted>,
+        ip: [u8; 4],
+    ) -> tokio::io::DuplexStream {
+        let (client, server) = tokio::io::duplex(1024);
+        let info = ConnInfo::new().peer_addr((ip, 1234).into());
+        tx.send(Ok((server, info))).unwrap();
+        client
+    }
+
+    pub(super) async fn echo(
+        client: tokio::io::DuplexStream,
+    ) -> conn::Conn<transport::Transport<tokio::io::DuplexStream>> {
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        let mut out = Vec::new();
+        conn.invoke_into(b"/echo", &vec![1], &mut out)
+            .await
+            .unwrap();
+        conn
+    }
+
+    pub(super) async fn closed(mut client: tokio::io::DuplexStream) -> bool {
+        client.read_to_end(&mut Vec::new()).await.is_ok()
     }
 }
@@ -0,0 +1,247 @@
+use std::any::Any;
+use std::fmt;
+use std::future::{self, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+
+/// Code of the error sent to the remote when the mux panics while serving a
+/// stream. The message of the error does not say why it panicked.
+pub const INTERNAL_ERROR_CODE: u64 = 13;
+
+pub(super) const INTERNAL_ERROR_MESSAGE: &str = "internal error";
+
+/// Panic is a panic of the mux while serving a stream.
+pub struct Panic<'a> {
+    pub rpc: &'a [u8],
+    pub stream: u64,
+    /// The value the mux panicked with.
+    pub payload: &'a (dyn Any + Send),
+}
+
+impl Panic<'_> {
+    /// Returns the message the mux panicked with, if it was a string.
+    pub fn message(&self) -> Option<&str> {
+        match self.payload.downcast_ref::<&str>() {
+            Some(msg) => Some(msg),
+            None => self.payload.downcast_ref::<String>().map(String::as_str),
+        }
+    }
+}
+
+/// PanicHook is called with every panic of the mux, to log or report it.
+#[derive(Clone)]
+pub struct PanicHook(Arc<dyn Fn(&Panic<'_>) + Send + Sync>);
+
+impl PanicHook {
+    pub fn new<F>(f: F) -> PanicHook
+    where
+        F: Fn(&Panic<'_>) + Send + Sync + 'static,
+    {
+        PanicHook(Arc::new(f))
+    }
+
+    pub(super) fn call(&self, panic: &Panic<'_>) {
+        (self.0)(panic)
+    }
+}
+
+impl fmt::Debug for PanicHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PanicHook")
+    }
+}
+
+// catch_unwind returns the output of the future, or the payload of a panic
+// while polling it.
+pub(super) async fn catch_unwind<F>(mut fut: F) -> Result<F::Output, Box<dyn Any + Send>>
+where
+    F: Future + Unpin,
+{
+    future::poll_fn(|cx| {
+        match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut fut).poll(cx))) {
+            Ok(Poll::Ready(v)) => Poll::Ready(Ok(v)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{handle_transport_concurrent, handle_transport_with_options, Mux, Options};
+    use super::{PanicHook, INTERNAL_ERROR_CODE};
+    use crate::testing::Echo;
+    use crate::wire::{frame, id::ID, packet};
+    use crate::{conn, stream, transport, StreamSend, Transport as _};
+
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct Panicky;
+
+    #[async_trait]
+    impl Mux for Panicky {
+        async fn serve<'a>(&self, rpc: &[u8], st: &mut stream::Stream<'a>) -> stream::Result<()> {
+            match rpc {
+                b"/panic" => panic!("secret {}", 42),
+
+                // between sends a message and the first frame of another
+                // before panicking, without flushing either.
+                b"/between" => {
+                    st.send(&vec![1]).await?;
+                    let fr = frame::Frame {
+                        data: &[2],
+                        id: ID::new(st.id(), 10),
+                        kind: 2,
+                        done: false,
+                        control: false,
+                    };
+                    st.transport().write_frame(fr).await?;
+                    panic!("between sends");
+                }
+
+                // interrupted panics while a message is halfway written to
+                // a wire nothing reads from.
+                b"/interrupted" => {
+                    let send = async {
+                        st.send(&vec![1; 100_000]).await?;
+                        st.transport().flush().await?;
+                        Ok(())
+                    };
+                    tokio::select! {
+                        res = send => res,
+                        _ = tokio::time::sleep(Duration::from_millis(1)) => panic!("interrupted"),
+                    }
+                }
+                _ => Echo::default().serve(rpc, st).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn panics() {
+        for &concurrent in &[None, Some(8)] {
+            let panicked = Arc::new(Mutex::new(Vec::new()));
+            let seen = panicked.clone();
+            let opts = Options {
+                max_concurrent_streams: concurrent,
+                panic_hook: Some(PanicHook::new(move |p| {
+                    let msg = p.message().unwrap_or_default().to_string();
+                    seen.lock().unwrap().push((p.rpc.to_vec(), p.stream, msg));
+                })),
+                ..Options::default()
+            };
+            let stats = opts.stats.clone();
+            let (client, server) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                match opts.max_concurrent_streams {
+                    Some(_) => handle_transport_concurrent(server, Panicky, &opts)
+                        .await
+                        .unwrap(),
+                    None => handle_transport_with_options(server, Panicky, &opts).await,
+                }
+            });
+
+            // the panic fails its stream without saying why, and the
+            // connection serves the next one.
+            let mut conn = conn::Conn::new(transport::Transport::new(client));
+            let mut out = Vec::new();
+            match conn.invoke_into(b"/panic", &vec![1], &mut out).await {
+                Err(stream::Error::StateError(stream::State::RemoteError((code, msg)))) => {
+                    assert_eq!(
+                        (code, msg.as_str()),
+                        (INTERNAL_ERROR_CODE, "internal error")
+                    )
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+            conn.invoke_into(b"/echo", &vec![2], &mut out)
+                .await
+                .unwrap();
+            assert_eq!(out, vec![2]);
+
+            assert_eq!(stats.panics(), 1);
+            assert_eq!(
+                *panicked.lock().unwrap(),
+                vec![(b"/panic".to_vec(), 1, "secret 42".to_string())]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn panic_between_sends() {
+        for &concurrent in &[None, Some(8)] {
+            let opts = Options {
+                max_concurrent_streams: concurrent,
+                ..Options::default()
+            };
+            let (client, server) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                match opts.max_concurrent_streams {
+                    Some(_) => handle_transport_concurrent(server, Panicky, &opts)
+                        .await
+                        .unwrap(),
+                    None => handle_transport_with_options(server, Panicky, &opts).await,
+                }
+            });
+
+            // what the mux left unsent when it panicked is dropped instead
+            // of being sent ahead of the error. concurrent streams hand whole
+            // packets to the writer as they are written, so only the frame
+            // of the unfinished one is dropped.
+            let mut tr = transport::Transport::new(client);
+            let mut buf = Vec::new();
+            let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+            st.invoke(b"/between").await.unwrap();
+            st.close_send().await.unwrap();
+            drop(st);
+
+            let mut error = INTERNAL_ERROR_CODE.to_be_bytes().to_vec();
+            error.extend_from_slice(b"internal error");
+            let mut expected = vec![(packet::Kind::Error, error)];
+            if concurrent.is_some() {
+                expected.insert(0, (packet::Kind::Message, vec![1]));
+            }
+            for want in expected {
+                let (id, kind) = tr.read_packet_into(&mut buf).await.unwrap();
+                assert_eq!((id.stream, kind, buf.clone()), (1, want.0, want.1));
+            }
+
+            // and the connection serves the next stream.
+            let mut conn = conn::Conn::new(tr);
+            let mut out = Vec::new();
+            conn.invoke_into(b"/echo", &vec![3], &mut out)
+                .await
+                .unwrap();
+            assert_eq!(out, vec![3]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panic_mid_write() {
+        let opts = Options::default();
+        let stats = opts.stats.clone();
+        let (client, server) = tokio::io::duplex(1024);
+        let handle =
+            tokio::spawn(
+                async move { handle_transport_with_options(server, Panicky, &opts).await },
+            );
+
+        // the wire holds part of a frame, so the connection is closed
+        // instead of writing the error after it.
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        let mut st = conn.stream(b"/interrupted").await.unwrap();
+        st.send(&vec![1]).await.unwrap();
+        st.close_send().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("connection not closed")
+            .unwrap();
+        assert_eq!(stats.panics(), 1);
+    }
+}
@@ -0,0 +1,55 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Extensions holds at most one value of every type.
+#[derive(Default)]
+pub struct Extensions(HashMap<TypeId, Box<dyn Any + Send + Sync>>);
+
+impl Extensions {
+    /// Inserts a value, returning the value of the same type it replaces.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, val: T) -> Option<T> {
+        let old = self.0.insert(TypeId::of::<T>(), Box::new(val))?;
+        old.downcast().ok().map(|old| *old)
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.0.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.0.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        let val = self.0.remove(&TypeId::of::<T>())?;
+        val.downcast().ok().map(|val| *val)
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Extensions;
+
+    #[test]
+    fn extensions() {
+        let mut ext = Extensions::default();
+        assert_eq!(ext.insert(1u32), None);
+        assert_eq!(ext.insert(2u32), Some(1));
+        ext.insert(String::from("user"));
+        *ext.get_mut::<u32>().unwrap() += 1;
+
+        assert_eq!(ext.get::<u32>(), Some(&3));
+        assert_eq!(ext.remove::<String>().as_deref(), Some("user"));
+        assert_eq!(ext.get::<String>(), None);
+        assert_eq!(ext.get::<u64>(), None);
+    }
+}
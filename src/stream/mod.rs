@@ -1,10 +1,16 @@
 use async_trait::async_trait;
 
 use crate::{
-    enc, transport,
+    enc, metadata,
+    pool::{self, Pool},
+    transport,
     wire::{self, id, packet},
 };
 use std::convert::TryInto;
+use std::sync::Arc;
+
+mod extensions;
+pub use extensions::Extensions;
 
 #[derive(Debug, Clone)]
 pub enum State {
@@ -38,11 +44,55 @@ pub enum Error {
     EncodingError(enc::Error),
 }
 
-impl std::error::Error for Error {}
+impl Error {
+    /// Returns true if a transport or wire timeout failed the stream.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::TransportError(err) => err.is_timeout(),
+            Error::IOError(err) => err.kind() == std::io::ErrorKind::TimedOut,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the remote end closed the stream or went away.
+    pub fn is_remote_closed(&self) -> bool {
+        match self {
+            Error::StateError(State::RemoteClosed) => true,
+            Error::TransportError(err) => err.is_remote_closed(),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the remote end broke the protocol.
+    pub fn is_protocol_violation(&self) -> bool {
+        match self {
+            Error::StateError(State::InvalidInvoke) => true,
+            Error::StateError(State::UnknownPacketKind(_)) => true,
+            Error::TransportError(err) => err.is_protocol_violation(),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::StateError(_) => None,
+            Error::TransportError(err) => Some(err),
+            Error::IOError(err) => Some(err),
+            Error::EncodingError(err) => Some(err.as_ref()),
+        }
+    }
+}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
+        match self {
+            Error::StateError(state) => write!(f, "StateError({:?})", state),
+            Error::TransportError(err) => write!(f, "TransportError({})", err),
+            Error::IOError(err) => write!(f, "IOError({})", err),
+            Error::EncodingError(err) => write!(f, "EncodingError({})", err),
+        }
     }
 }
 
@@ -80,7 +130,7 @@ trait SetOnce<T> {
 
 impl<T> SetOnce<T> for Option<T> {
     fn set_once(&mut self, t: T) {
-        if let None = self {
+        if self.is_none() {
             *self = Some(t)
         }
     }
@@ -108,6 +158,9 @@ pub struct Stream<'a> {
     id: id::ID,
     tr: &'a mut dyn crate::Transport,
     buf: &'a mut Vec<u8>,
+    split_size: usize,
+    pool: Option<Arc<dyn Pool>>,
+    extensions: Extensions,
 
     send: Option<State>,
     recv: Option<State>,
@@ -116,10 +169,23 @@ pub struct Stream<'a> {
 
 impl<'a> Stream<'a> {
     pub fn new(sid: u64, tr: &'a mut dyn crate::Transport, buf: &'a mut Vec<u8>) -> Self {
+        Self::with_options(sid, tr, buf, &transport::TransportOptions::default())
+    }
+
+    /// Like new, but splits packets into frames as the options say.
+    pub fn with_options(
+        sid: u64,
+        tr: &'a mut dyn crate::Transport,
+        buf: &'a mut Vec<u8>,
+        opts: &transport::TransportOptions,
+    ) -> Self {
         Stream {
             id: id::ID::new(sid, 0),
             tr,
             buf,
+            split_size: opts.split_size,
+            pool: opts.pool.clone(),
+            extensions: Extensions::default(),
 
             send: None,
             recv: None,
@@ -135,8 +201,25 @@ impl<'a> Stream<'a> {
         self.id.stream
     }
 
+    /// Returns the values added to the stream by what serves it, like the
+    /// request context of the server.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
     //
 
+    // reset_buf clears the buffer, taking one from the pool if it was given
+    // back.
+    fn reset_buf(&mut self) {
+        self.buf.clear();
+        pool::acquire(&self.pool, self.buf, 0);
+    }
+
     async fn write_buf(&mut self, kind: packet::Kind) -> Result<()> {
         self.id.message += 1;
 
@@ -146,35 +229,37 @@ impl<'a> Stream<'a> {
             kind,
         };
 
-        for fr in wire::split::split(&pkt, 64 * 1024) {
-            self.tr.write_frame(fr).await?;
+        let sid = self.id.stream;
+        for fr in wire::split::split(&pkt, self.split_size) {
+            let res = self.tr.write_frame(fr).await;
+            res.map_err(|err| err.in_stream(sid))?;
         }
 
         Ok(())
     }
 
+    async fn flush_transport(&mut self) -> Result<()> {
+        let sid = self.id.stream;
+        let res = self.tr.flush().await;
+        Ok(res.map_err(|err| err.in_stream(sid))?)
+    }
+
     async fn recv_buf(&mut self) -> Result<()> {
         loop {
             self.recv.as_error()?;
             self.term.as_error()?;
 
-            let id;
-            let kind;
-
-            match self.tr.read_packet_into(&mut self.buf).await {
-                Ok((id_, kind_)) => {
-                    id = id_;
-                    kind = kind_
-                }
+            let (id, kind) = match self.tr.read_packet_into(self.buf).await {
+                Ok((id, kind)) => (id, kind),
 
-                Err(transport::Error::RemoteClosed) => {
+                Err(err) if err.kind() == transport::ErrorKind::RemoteClosed => {
                     self.recv.set_once(State::EOF);
                     self.term.set_once(State::RemoteClosed);
                     continue;
                 }
 
-                Err(e) => {
-                    return Err(Error::TransportError(e));
+                Err(err) => {
+                    return Err(Error::TransportError(err.in_stream(self.id.stream)));
                 }
             };
 
@@ -222,14 +307,33 @@ impl<'a> Stream<'a> {
         }
     }
 
+    // release_if_terminated gives the buffer back to the pool once the stream
+    // has no more use for it.
+    fn release_if_terminated(&mut self) {
+        if self.term.is_some() {
+            pool::release(&self.pool, self.buf);
+        }
+    }
+
     //
 
     pub async fn invoke(&mut self, rpc: &[u8]) -> Result<()> {
-        self.buf.clear();
+        self.reset_buf();
         self.buf.extend_from_slice(rpc);
         self.write_buf(packet::Kind::Invoke).await
     }
 
+    pub async fn invoke_with_metadata(
+        &mut self,
+        rpc: &[u8],
+        md: &metadata::Metadata,
+    ) -> Result<()> {
+        self.reset_buf();
+        metadata::append(self.buf, md);
+        self.write_buf(packet::Kind::InvokeMetadata).await?;
+        self.invoke(rpc).await
+    }
+
     pub async fn close_send(&mut self) -> Result<()> {
         if self.send.is_some() || self.term.is_some() {
             return Ok(());
@@ -239,8 +343,10 @@ impl<'a> Stream<'a> {
         self.terminate_if_both_closed();
 
         self.buf.clear();
-        self.write_buf(packet::Kind::CloseSend).await?;
-        self.tr.flush().await?;
+        let res = self.write_buf(packet::Kind::CloseSend).await;
+        self.release_if_terminated();
+        res?;
+        self.flush_transport().await?;
         Ok(())
     }
 
@@ -252,8 +358,10 @@ impl<'a> Stream<'a> {
         self.term.set_once(State::TerminatedSentClose);
 
         self.buf.clear();
-        self.write_buf(packet::Kind::Close).await?;
-        self.tr.flush().await?;
+        let res = self.write_buf(packet::Kind::Close).await;
+        self.release_if_terminated();
+        res?;
+        self.flush_transport().await?;
         Ok(())
     }
 
@@ -265,12 +373,14 @@ impl<'a> Stream<'a> {
         self.send.set_once(State::EOF);
         self.term.set_once(State::TerminatedSentError);
 
-        self.buf.clear();
+        self.reset_buf();
         self.buf.reserve(8 + msg.len());
         self.buf.extend_from_slice(&code.to_be_bytes());
         self.buf.extend_from_slice(msg.as_bytes());
-        self.write_buf(packet::Kind::Error).await?;
-        self.tr.flush().await?;
+        let res = self.write_buf(packet::Kind::Error).await;
+        self.release_if_terminated();
+        res?;
+        self.flush_transport().await?;
         Ok(())
     }
 }
@@ -292,9 +402,12 @@ impl<'a, Out: enc::Unmarshal> crate::StreamRecv<Out> for Stream<'a> {
         self.recv.as_error()?;
         self.term.as_error()?;
 
-        self.tr.flush().await?;
-        self.recv_buf().await?;
-        out.unmarshal(&self.buf)?;
+        self.flush_transport().await?;
+        if let Err(err) = self.recv_buf().await {
+            self.release_if_terminated();
+            return Err(err);
+        }
+        out.unmarshal(self.buf)?;
         Ok(())
     }
 }
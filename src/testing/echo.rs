@@ -0,0 +1,82 @@
+use crate::stream::{self, Error, State};
+use crate::{server, StreamRecv, StreamSend};
+
+use async_trait::async_trait;
+use std::time::Duration;
+
+/// Echo is the mux served by the tests of this crate. It answers every
+/// message of an rpc with the message, until the client closes its side.
+/// Some rpcs answer differently:
+///
+/// - /len answers a message with its length as text.
+/// - /sum answers the total length of the messages as 8 big-endian bytes.
+/// - /fanout answers a message n with n messages, the ith being 1000 * i
+///   bytes of i.
+/// - /slow answers a message after a second.
+/// - /hang sends a message and never finishes.
+///
+/// Every answer starts with the tag of the Echo, which is empty by default.
+#[derive(Clone, Default)]
+pub(crate) struct Echo {
+    tag: &'static [u8],
+}
+
+impl Echo {
+    pub(crate) fn tagged(tag: &'static [u8]) -> Echo {
+        Echo { tag }
+    }
+
+    async fn answer(&self, st: &mut stream::Stream<'_>, data: &[u8]) -> stream::Result<()> {
+        let mut out = self.tag.to_vec();
+        out.extend_from_slice(data);
+        st.send(&out).await
+    }
+}
+
+#[async_trait]
+impl server::Mux for Echo {
+    async fn serve<'a>(&self, rpc: &[u8], st: &mut stream::Stream<'a>) -> stream::Result<()> {
+        let mut buf = Vec::new();
+        match rpc {
+            b"/len" => {
+                st.recv_into(&mut buf).await?;
+                self.answer(st, buf.len().to_string().as_bytes()).await?;
+            }
+            b"/sum" => {
+                let mut total = 0u64;
+                loop {
+                    match st.recv_into(&mut buf).await {
+                        Ok(()) => total += buf.len() as u64,
+                        Err(Error::StateError(State::EOF)) => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+                self.answer(st, &total.to_be_bytes()).await?;
+            }
+            b"/fanout" => {
+                st.recv_into(&mut buf).await?;
+                for i in 0..buf[0] {
+                    self.answer(st, &vec![i; 1000 * i as usize]).await?;
+                }
+            }
+            b"/slow" => {
+                st.recv_into(&mut buf).await?;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                self.answer(st, &buf).await?;
+            }
+            b"/hang" => {
+                self.answer(st, b"hanging").await?;
+                st.transport().flush().await?;
+                std::future::pending::<()>().await;
+            }
+            _ => loop {
+                match st.recv_into(&mut buf).await {
+                    Ok(()) => self.answer(st, &buf).await?,
+                    Err(Error::StateError(State::EOF)) => break,
+                    Err(err) => return Err(err),
+                }
+            },
+        }
+        st.close_send().await
+    }
+}
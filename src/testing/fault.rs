@@ -0,0 +1,520 @@
+use crate::{server, stream};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+// a fault wire wraps another wire and asks a policy before every read and
+// write whether to inject a fault into it. a read or write that returns
+// pending is not asked about again until it completes.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Fails the read with an io error.
+    ReadError,
+    /// Fails the write with an io error.
+    WriteError,
+    /// Reads at most this many bytes.
+    ShortRead(usize),
+    /// Writes at most this many bytes.
+    ShortWrite(usize),
+    /// Waits before doing the read or write.
+    Delay(Duration),
+    /// Xors the byte at offset, modulo the length, with the mask.
+    Corrupt { offset: usize, mask: u8 },
+    /// Returns EOF from this and every later read and fails later writes.
+    Eof,
+}
+
+impl Fault {
+    fn applies(&self, op: Op) -> bool {
+        match self {
+            Fault::ReadError | Fault::ShortRead(_) | Fault::Eof => op == Op::Read,
+            Fault::WriteError | Fault::ShortWrite(_) => op == Op::Write,
+            Fault::Delay(_) | Fault::Corrupt { .. } => true,
+        }
+    }
+}
+
+pub trait Policy: Send {
+    /// Returns the fault to inject into the index'th read or write.
+    fn decide(&mut self, op: Op, index: u64) -> Option<Fault>;
+}
+
+// random
+
+/// Random injects faults with fixed probabilities using a seeded generator, so
+/// the same seed against the same traffic injects the same faults.
+pub struct Random {
+    pub read_error: f64,
+    pub write_error: f64,
+    pub short_read: f64,
+    pub short_write: f64,
+    pub delay: f64,
+    pub max_delay: Duration,
+    pub corrupt: f64,
+    pub eof: f64,
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random {
+            read_error: 0.0,
+            write_error: 0.0,
+            short_read: 0.0,
+            short_write: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(10),
+            corrupt: 0.0,
+            eof: 0.0,
+            state: seed,
+        }
+    }
+
+    // splitmix64
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    fn size(&mut self) -> usize {
+        1 + (self.next() % 16) as usize
+    }
+}
+
+impl Policy for Random {
+    fn decide(&mut self, op: Op, _: u64) -> Option<Fault> {
+        let (error, short) = match op {
+            Op::Read => (self.read_error, self.short_read),
+            Op::Write => (self.write_error, self.short_write),
+        };
+
+        if op == Op::Read && self.chance(self.eof) {
+            Some(Fault::Eof)
+        } else if self.chance(error) {
+            Some(match op {
+                Op::Read => Fault::ReadError,
+                Op::Write => Fault::WriteError,
+            })
+        } else if self.chance(self.delay) {
+            let nanos = self.next() % (self.max_delay.as_nanos() as u64).max(1);
+            Some(Fault::Delay(Duration::from_nanos(nanos)))
+        } else if self.chance(self.corrupt) {
+            let offset = self.next() as usize;
+            Some(Fault::Corrupt {
+                offset,
+                mask: 1 << (self.next() % 8),
+            })
+        } else if self.chance(short) {
+            let n = self.size();
+            Some(match op {
+                Op::Read => Fault::ShortRead(n),
+                Op::Write => Fault::ShortWrite(n),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+// schedule
+
+/// Schedule injects faults into specific reads and writes, counted from zero
+/// separately for each direction.
+#[derive(Default)]
+pub struct Schedule {
+    faults: HashMap<(Op, u64), Fault>,
+}
+
+impl Schedule {
+    pub fn new() -> Schedule {
+        Schedule::default()
+    }
+
+    pub fn on_read(mut self, index: u64, fault: Fault) -> Self {
+        self.faults.insert((Op::Read, index), fault);
+        self
+    }
+
+    pub fn on_write(mut self, index: u64, fault: Fault) -> Self {
+        self.faults.insert((Op::Write, index), fault);
+        self
+    }
+}
+
+impl Policy for Schedule {
+    fn decide(&mut self, op: Op, index: u64) -> Option<Fault> {
+        self.faults.remove(&(op, index))
+    }
+}
+
+// tally
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Counts {
+    pub read_errors: u64,
+    pub write_errors: u64,
+    pub short_reads: u64,
+    pub short_writes: u64,
+    pub delays: u64,
+    pub corruptions: u64,
+    pub eofs: u64,
+}
+
+/// Tally is a handle to the counts of faults injected by one or more wires.
+#[derive(Clone, Default)]
+pub struct Tally(Arc<Mutex<Counts>>);
+
+impl Tally {
+    pub fn counts(&self) -> Counts {
+        *self.0.lock().unwrap()
+    }
+
+    fn add(&self, fault: &Fault) {
+        let mut counts = self.0.lock().unwrap();
+        let count = match fault {
+            Fault::ReadError => &mut counts.read_errors,
+            Fault::WriteError => &mut counts.write_errors,
+            Fault::ShortRead(_) => &mut counts.short_reads,
+            Fault::ShortWrite(_) => &mut counts.short_writes,
+            Fault::Delay(_) => &mut counts.delays,
+            Fault::Corrupt { .. } => &mut counts.corruptions,
+            Fault::Eof => &mut counts.eofs,
+        };
+        *count += 1;
+    }
+}
+
+// wire
+
+// pending is a read or write that has started with a decided fault.
+struct Pending {
+    fault: Option<Fault>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+pub struct FaultWire<W> {
+    w: W,
+    policy: Box<dyn Policy>,
+    tally: Tally,
+    eof: bool,
+    reads: u64,
+    writes: u64,
+    read: Option<Pending>,
+    write: Option<Pending>,
+}
+
+impl<W: crate::Wire> FaultWire<W> {
+    pub fn new(w: W, policy: impl Policy + 'static) -> FaultWire<W> {
+        FaultWire::with_tally(w, policy, Tally::default())
+    }
+
+    /// Like new, but counts injected faults into an existing tally.
+    pub fn with_tally(w: W, policy: impl Policy + 'static, tally: Tally) -> FaultWire<W> {
+        FaultWire {
+            w,
+            policy: Box::new(policy),
+            tally,
+            eof: false,
+            reads: 0,
+            writes: 0,
+            read: None,
+            write: None,
+        }
+    }
+
+    pub fn tally(&self) -> Tally {
+        self.tally.clone()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
+    fn start(&mut self, op: Op) -> Pending {
+        let index = match op {
+            Op::Read => &mut self.reads,
+            Op::Write => &mut self.writes,
+        };
+        let fault = self
+            .policy
+            .decide(op, *index)
+            .filter(|fault| fault.applies(op));
+        *index += 1;
+
+        if let Some(fault) = &fault {
+            self.tally.add(fault);
+        }
+        let sleep = match fault {
+            Some(Fault::Delay(d)) => Some(Box::pin(tokio::time::sleep(d))),
+            _ => None,
+        };
+        Pending { fault, sleep }
+    }
+}
+
+fn injected(what: &str) -> io::Error {
+    io::Error::other(format!("injected {}", what))
+}
+
+fn corrupt(data: &mut [u8], offset: usize, mask: u8) {
+    if !data.is_empty() {
+        data[offset % data.len()] ^= mask;
+    }
+}
+
+impl<W: crate::Wire> AsyncRead for FaultWire<W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if this.read.is_none() {
+            this.read = Some(this.start(Op::Read));
+        }
+        let pending = this.read.as_mut().unwrap();
+        if let Some(sleep) = &mut pending.sleep {
+            ready!(sleep.as_mut().poll(cx));
+            pending.sleep = None;
+        }
+
+        let before = buf.filled().len();
+        let res = match pending.fault {
+            Some(Fault::ReadError) => Poll::Ready(Err(injected("read error"))),
+            Some(Fault::Eof) => {
+                this.eof = true;
+                Poll::Ready(Ok(()))
+            }
+            Some(Fault::ShortRead(n)) => {
+                let mut tmp = vec![0; n.clamp(1, buf.remaining().max(1))];
+                let mut short = ReadBuf::new(&mut tmp);
+                let res = Pin::new(&mut this.w).poll_read(cx, &mut short);
+                buf.put_slice(short.filled());
+                res
+            }
+            Some(Fault::Corrupt { offset, mask }) => {
+                let res = Pin::new(&mut this.w).poll_read(cx, buf);
+                corrupt(&mut buf.filled_mut()[before..], offset, mask);
+                res
+            }
+            _ => Pin::new(&mut this.w).poll_read(cx, buf),
+        };
+
+        if res.is_ready() {
+            this.read = None;
+        }
+        res
+    }
+}
+
+impl<W: crate::Wire> AsyncWrite for FaultWire<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.eof {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if this.write.is_none() {
+            this.write = Some(this.start(Op::Write));
+        }
+        let pending = this.write.as_mut().unwrap();
+        if let Some(sleep) = &mut pending.sleep {
+            ready!(sleep.as_mut().poll(cx));
+            pending.sleep = None;
+        }
+
+        let res = match pending.fault {
+            Some(Fault::WriteError) => Poll::Ready(Err(injected("write error"))),
+            Some(Fault::ShortWrite(n)) => {
+                let n = n.clamp(1, buf.len().max(1)).min(buf.len());
+                Pin::new(&mut this.w).poll_write(cx, &buf[..n])
+            }
+            Some(Fault::Corrupt { offset, mask }) => {
+                let mut data = buf.to_vec();
+                corrupt(&mut data, offset, mask);
+                Pin::new(&mut this.w).poll_write(cx, &data)
+            }
+            _ => Pin::new(&mut this.w).poll_write(cx, buf),
+        };
+
+        if res.is_ready() {
+            this.write = None;
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().w).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().w).poll_shutdown(cx)
+    }
+}
+
+// listener
+
+/// FaultListener wraps the connections accepted by a listener in fault wires
+/// so that server::run can be tested against them. The policy function is
+/// called with the index of every accepted connection.
+pub struct FaultListener<L, F> {
+    lis: L,
+    policy: F,
+    tally: Tally,
+    conns: AtomicU64,
+}
+
+impl<L, F> FaultListener<L, F> {
+    pub fn new(lis: L, policy: F) -> FaultListener<L, F> {
+        FaultListener {
+            lis,
+            policy,
+            tally: Tally::default(),
+            conns: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the tally of faults injected into every accepted connection.
+    pub fn tally(&self) -> Tally {
+        self.tally.clone()
+    }
+
+    fn wrap<W, P>(&self, w: W) -> FaultWire<W>
+    where
+        W: crate::Wire,
+        F: Fn(u64) -> P,
+        P: Policy + 'static,
+    {
+        let index = self.conns.fetch_add(1, Ordering::Relaxed);
+        FaultWire::with_tally(w, (self.policy)(index), self.tally.clone())
+    }
+}
+
+#[async_trait]
+impl<L, W, F, P> server::Listener<FaultWire<W>> for FaultListener<L, F>
+where
+    L: server::Listener<W> + Send + Sync,
+    W: crate::Wire + 'static,
+    F: Fn(u64) -> P + Send + Sync,
+    P: Policy + 'static,
+{
+    async fn accept(&self) -> stream::Result<FaultWire<W>> {
+        let w = self.lis.accept().await?;
+        Ok(self.wrap(w))
+    }
+
+    async fn accept_with_info(&self) -> stream::Result<(FaultWire<W>, server::ConnInfo)> {
+        let (w, info) = self.lis.accept_with_info().await?;
+        Ok((self.wrap(w), info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Echo;
+    use crate::{conn, transport};
+
+    #[tokio::test]
+    async fn short_reads_and_writes() {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut policy = Random::new(1);
+        policy.short_read = 0.5;
+        policy.short_write = 0.5;
+        let client = FaultWire::new(client, policy);
+        let tally = client.tally();
+
+        tokio::spawn(server::handle_transport(server, Echo::default()));
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        for i in 0..10u8 {
+            let mut out = Vec::new();
+            conn.invoke_into(b"/echo", &vec![i; 100], &mut out)
+                .await
+                .unwrap();
+            assert_eq!(out, vec![i; 100]);
+        }
+
+        let counts = tally.counts();
+        assert!(counts.short_reads > 0);
+        assert!(counts.short_writes > 0);
+        assert_eq!(counts.read_errors + counts.write_errors + counts.eofs, 0);
+    }
+
+    #[tokio::test]
+    async fn write_error_source() {
+        let (client, server) = tokio::io::duplex(4096);
+        let client = FaultWire::new(client, Schedule::new().on_write(0, Fault::WriteError));
+        tokio::spawn(server::handle_transport(server, Echo::default()));
+
+        let mut conn = conn::Conn::new(transport::Transport::new(client));
+        let mut out = Vec::new();
+        let err = match conn.invoke_into(b"/echo", &vec![1], &mut out).await {
+            Err(stream::Error::TransportError(err)) => err,
+            res => panic!("unexpected result: {:?}", res),
+        };
+
+        // the error says what failed where, and keeps the io error.
+        assert_eq!(err.kind(), transport::ErrorKind::IOError);
+        assert_eq!(err.op(), transport::Op::Write);
+        assert_eq!(err.stream(), Some(1));
+        assert_eq!(err.io_error().unwrap().to_string(), "injected write error");
+        assert!(!err.is_remote_closed() && !err.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn server_eof() {
+        let lis = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = lis.local_addr().unwrap();
+        let lis = FaultListener::new(lis, |index| match index {
+            0 => Schedule::new().on_read(0, Fault::Eof),
+            _ => Schedule::new(),
+        });
+        let tally = lis.tally();
+        tokio::spawn(server::run(lis, Echo::default()));
+
+        for (i, ok) in [false, true].iter().enumerate() {
+            let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut conn = conn::Conn::new(transport::Transport::new(socket));
+            let mut out = Vec::new();
+            let res = conn.invoke_into(b"/echo", &vec![1], &mut out).await;
+            assert_eq!(res.is_ok(), *ok, "connection {}: {:?}", i, res);
+        }
+
+        assert_eq!(
+            tally.counts(),
+            Counts {
+                eofs: 1,
+                ..Counts::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use crate::{conn, server, transport};
+
+use tokio::io::DuplexStream;
+use tokio::task::JoinHandle;
+
+pub mod fault;
+
+#[cfg(test)]
+mod echo;
+
+#[cfg(test)]
+pub(crate) use self::echo::Echo;
+
+// helpers for testing rpc logic in memory. the client and server talk over a
+// tokio::io::duplex pipe so no sockets are involved.
+
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+pub type Conn = conn::Conn<transport::Transport<DuplexStream>>;
+
+/// Returns a client connection to a task serving the mux. The task exits once
+/// the connection is dropped.
+pub fn pair<M>(mux: M) -> (Conn, JoinHandle<()>)
+where
+    M: server::Mux + Send + Sync + 'static,
+{
+    pair_with_buffer(mux, DEFAULT_BUFFER_SIZE)
+}
+
+/// Like pair, but with the given number of bytes buffered in each direction
+/// of the pipe before writes wait on the other side to read.
+pub fn pair_with_buffer<M>(mux: M, size: usize) -> (Conn, JoinHandle<()>)
+where
+    M: server::Mux + Send + Sync + 'static,
+{
+    let (client, server) = tokio::io::duplex(size);
+    let handle = tokio::spawn(server::handle_transport(server, mux));
+    (conn::Conn::new(transport::Transport::new(client)), handle)
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn small_buffer() {
+        let (mut conn, handle) = super::pair_with_buffer(super::Echo::default(), 16);
+
+        let mut out = Vec::new();
+        conn.invoke_into(b"/len", &vec![0; 1 << 20], &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"1048576");
+
+        drop(conn);
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use crate::wire::{id, packet};
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// ErrorKind is what went wrong in a transport.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    RemoteClosed,
+    ParseError,
+    IDMonotonicityError,
+    PacketKindChangeError,
+    DataOverflowError,
+    IOError,
+    KeepaliveTimeout,
+    ReadTimeout,
+}
+
+/// Op is the transport operation that failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Read => f.write_str("read"),
+            Op::Write => f.write_str("write"),
+            Op::Flush => f.write_str("flush"),
+        }
+    }
+}
+
+/// Error is a transport failure with what is known about where it happened.
+/// It is cheap to clone, so a failed transport returns the same error to
+/// every later caller.
+#[derive(Debug, Clone)]
+pub struct Error {
+    kind: ErrorKind,
+    op: Op,
+    stream: Option<u64>,
+    packet: Option<(id::ID, packet::Kind)>,
+    source: Option<Arc<io::Error>>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, op: Op) -> Error {
+        Error {
+            kind,
+            op,
+            stream: None,
+            packet: None,
+            source: None,
+        }
+    }
+
+    /// Returns an IOError caused by err.
+    pub fn io(op: Op, err: io::Error) -> Error {
+        Error {
+            source: Some(Arc::new(err)),
+            ..Error::new(ErrorKind::IOError, op)
+        }
+    }
+
+    // with_packet notes the frame being read when the error happened.
+    pub(crate) fn with_packet(mut self, id: id::ID, kind: packet::Kind) -> Error {
+        self.packet = Some((id, kind));
+        self
+    }
+
+    // in_stream notes the stream that was using the transport, unless one
+    // was noted already.
+    pub(crate) fn in_stream(mut self, stream: u64) -> Error {
+        self.stream.get_or_insert(stream);
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn op(&self) -> Op {
+        self.op
+    }
+
+    /// Returns the id of the stream that was using the transport, if the
+    /// error was returned through a stream.
+    pub fn stream(&self) -> Option<u64> {
+        self.stream
+    }
+
+    /// Returns the id and kind of the frame that broke the protocol, if the
+    /// error was caused by one.
+    pub fn packet(&self) -> Option<(id::ID, packet::Kind)> {
+        self.packet
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        self.source.as_deref()
+    }
+
+    /// Returns true if the error is a transport or wire timeout.
+    pub fn is_timeout(&self) -> bool {
+        match self.kind {
+            ErrorKind::KeepaliveTimeout | ErrorKind::ReadTimeout => true,
+            _ => self.io_kind() == Some(io::ErrorKind::TimedOut),
+        }
+    }
+
+    /// Returns true if the remote end went away, whether it closed the
+    /// connection or it was broken.
+    pub fn is_remote_closed(&self) -> bool {
+        match self.kind {
+            ErrorKind::RemoteClosed => true,
+            _ => matches!(
+                self.io_kind(),
+                Some(io::ErrorKind::ConnectionReset)
+                    | Some(io::ErrorKind::ConnectionAborted)
+                    | Some(io::ErrorKind::BrokenPipe)
+                    | Some(io::ErrorKind::UnexpectedEof)
+            ),
+        }
+    }
+
+    /// Returns true if the remote end sent data that breaks the protocol.
+    pub fn is_protocol_violation(&self) -> bool {
+        matches!(
+            self.kind,
+            ErrorKind::ParseError
+                | ErrorKind::IDMonotonicityError
+                | ErrorKind::PacketKindChangeError
+                | ErrorKind::DataOverflowError
+        )
+    }
+
+    fn io_kind(&self) -> Option<io::ErrorKind> {
+        self.io_error().map(io::Error::kind)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transport {}: {:?}", self.op, self.kind)?;
+        if let Some(stream) = self.stream {
+            write!(f, " in stream {}", stream)?;
+        }
+        if let Some((id, kind)) = self.packet {
+            write!(
+                f,
+                " at frame {}/{} of kind {:?}",
+                id.stream, id.message, kind
+            )?;
+        }
+        if let Some(err) = &self.source {
+            write!(f, ": {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.source {
+            Some(err) => Some(err.as_ref()),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, ErrorKind, Op};
+    use crate::wire::{id::ID, packet::Kind};
+
+    use std::error::Error as _;
+    use std::io;
+
+    #[test]
+    fn classify() {
+        let reset = Error::io(Op::Write, io::ErrorKind::ConnectionReset.into());
+        assert!(reset.is_remote_closed());
+        assert!(!reset.is_timeout());
+        assert_eq!(
+            reset
+                .source()
+                .unwrap()
+                .downcast_ref::<io::Error>()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::ConnectionReset
+        );
+
+        let timeout = Error::io(Op::Read, io::ErrorKind::TimedOut.into());
+        assert!(timeout.is_timeout());
+        assert!(Error::new(ErrorKind::ReadTimeout, Op::Read).is_timeout());
+
+        let violation = Error::new(ErrorKind::IDMonotonicityError, Op::Read)
+            .with_packet(ID::new(1, 2), Kind::Message)
+            .in_stream(1)
+            .in_stream(3);
+        assert!(violation.is_protocol_violation());
+        assert!(!violation.is_remote_closed());
+        assert!(violation.source().is_none());
+        assert_eq!(
+            violation.to_string(),
+            "transport read: IDMonotonicityError in stream 1 at frame 1/2 of kind Message"
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use crate::transport::{
+    Assembler, Error, ErrorKind, Op, ReadBuffer, Result, Stats, TransportOptions,
+};
+use crate::wire::{frame, id, packet};
+
+use async_trait::async_trait;
+use std::collections::VecDeque;
+
+// the mock transport is driven by a script of steps that must happen in
+// order. inbound bytes go through the same reassembly as a real transport, so
+// scripts can contain malformed frames, out of order ids and kind changes.
+
+#[derive(Debug)]
+enum Step {
+    Read(Vec<u8>),
+    Fail(Error),
+    Write(Vec<u8>),
+    Flush,
+}
+
+fn encode(fr: &frame::Frame<'_>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    frame::append_frame(&mut buf, fr);
+    buf
+}
+
+fn done_frame(id: id::ID, kind: packet::Kind, data: &[u8]) -> frame::Frame<'_> {
+    frame::Frame {
+        data,
+        id,
+        kind: kind.into(),
+        done: true,
+        control: false,
+    }
+}
+
+/// Mock is a transport that checks written frames against a script and
+/// returns scripted packets from reads. It panics as soon as the transport
+/// is used in a way the script does not expect. Reads past the end of the
+/// script return RemoteClosed. Its stats count frames and packets, but not
+/// bytes or flushes.
+pub struct Mock {
+    wire: tokio::io::Empty,
+    script: VecDeque<Step>,
+    rbuf: ReadBuffer,
+    stats: Stats,
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Mock::new()
+    }
+}
+
+impl Mock {
+    pub fn new() -> Mock {
+        Mock {
+            wire: tokio::io::empty(),
+            script: VecDeque::new(),
+            rbuf: ReadBuffer::default(),
+            stats: Stats::default(),
+        }
+    }
+
+    /// Makes the bytes available to reads as if they arrived on a wire.
+    pub fn read_bytes(mut self, data: &[u8]) -> Self {
+        self.script.push_back(Step::Read(data.to_vec()));
+        self
+    }
+
+    pub fn read_frame(self, fr: frame::Frame<'_>) -> Self {
+        self.read_bytes(&encode(&fr))
+    }
+
+    /// Makes a packet sent in a single frame available to reads.
+    pub fn read_packet(self, id: id::ID, kind: packet::Kind, data: &[u8]) -> Self {
+        self.read_frame(done_frame(id, kind, data))
+    }
+
+    /// Fails the read that needs data from this step.
+    pub fn fail_read(mut self, err: Error) -> Self {
+        self.script.push_back(Step::Fail(err));
+        self
+    }
+
+    pub fn expect_frame(mut self, fr: frame::Frame<'_>) -> Self {
+        self.script.push_back(Step::Write(encode(&fr)));
+        self
+    }
+
+    /// Expects a packet written in a single frame.
+    pub fn expect_packet(self, id: id::ID, kind: packet::Kind, data: &[u8]) -> Self {
+        self.expect_frame(done_frame(id, kind, data))
+    }
+
+    /// Expects a flush. Flushes are ignored unless a flush is the next step.
+    pub fn expect_flush(mut self) -> Self {
+        self.script.push_back(Step::Flush);
+        self
+    }
+
+    /// Panics if any step of the script has not happened.
+    pub fn assert_done(&self) {
+        assert!(
+            self.script.is_empty(),
+            "unfinished mock script: {:?}",
+            self.script
+        );
+    }
+}
+
+#[async_trait]
+impl crate::Transport for Mock {
+    fn wire(&mut self) -> &mut dyn crate::Wire {
+        &mut self.wire
+    }
+
+    async fn read_packet_into(&mut self, buf: &mut Vec<u8>) -> Result<(id::ID, packet::Kind)> {
+        let mut asm = Assembler::new(&TransportOptions::default());
+        buf.clear();
+
+        loop {
+            if let Some(v) = asm.next(&mut self.rbuf, buf, &mut self.stats)? {
+                return Ok(v);
+            }
+
+            match self.script.pop_front() {
+                Some(Step::Read(data)) => self.rbuf.extend_from_slice(&data),
+                Some(Step::Fail(err)) => return Err(err),
+                None => return Err(Error::new(ErrorKind::RemoteClosed, Op::Read)),
+                Some(step) => panic!("mock read while expecting {:?}", step),
+            }
+        }
+    }
+
+    async fn write_frame(&mut self, fr: frame::Frame<'_>) -> Result<()> {
+        if !fr.control {
+            self.stats.frames_out.count(fr.kind.into());
+            if fr.done {
+                self.stats.packets_out.count(fr.kind.into());
+            }
+        }
+
+        match self.script.pop_front() {
+            Some(Step::Write(expected)) if expected == encode(&fr) => Ok(()),
+            Some(Step::Write(expected)) => panic!(
+                "mock expected frame {:?} but got {:?}",
+                frame::parse_frame(&expected).map(|(fr, _)| fr),
+                fr
+            ),
+            step => panic!("mock expected {:?} but got frame {:?}", step, fr),
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if let Some(Step::Flush) = self.script.front() {
+            self.script.pop_front();
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mock;
+    use crate::stream::{Error, State, Stream};
+    use crate::transport::{self, ErrorKind};
+    use crate::wire::{frame, id::ID, packet::Kind};
+    use crate::{StreamRecv, StreamSend};
+
+    #[tokio::test]
+    async fn unitary() {
+        let mut mock = Mock::new()
+            .expect_packet(ID::new(1, 1), Kind::Invoke, b"/rpc")
+            .expect_packet(ID::new(1, 2), Kind::Message, b"in")
+            .expect_packet(ID::new(1, 3), Kind::CloseSend, b"")
+            .expect_flush()
+            .read_packet(ID::new(1, 1), Kind::Message, b"out")
+            .read_packet(ID::new(1, 2), Kind::Close, b"");
+
+        let mut buf = Vec::new();
+        let mut st = Stream::new(1, &mut mock, &mut buf);
+        let mut out = Vec::new();
+        st.invoke(b"/rpc").await.unwrap();
+        st.send(&b"in".to_vec()).await.unwrap();
+        st.close_send().await.unwrap();
+        st.recv_into(&mut out).await.unwrap();
+        assert_eq!(out, b"out");
+        assert!(matches!(
+            st.recv_into(&mut out).await,
+            Err(Error::StateError(State::EOF))
+        ));
+
+        // the remote close terminated the stream so closing sends nothing.
+        st.close().await.unwrap();
+        mock.assert_done();
+    }
+
+    #[tokio::test]
+    async fn close_after_close_send() {
+        let mut mock = Mock::new()
+            .expect_packet(ID::new(3, 1), Kind::CloseSend, b"")
+            .expect_flush()
+            .expect_packet(ID::new(3, 2), Kind::Close, b"")
+            .expect_flush();
+
+        let mut buf = Vec::new();
+        let mut st = Stream::new(3, &mut mock, &mut buf);
+        st.close_send().await.unwrap();
+        st.close_send().await.unwrap();
+        st.close().await.unwrap();
+        st.close().await.unwrap();
+        assert!(matches!(
+            st.send(&Vec::new()).await,
+            Err(Error::StateError(State::SendClosed))
+        ));
+        mock.assert_done();
+    }
+
+    #[tokio::test]
+    async fn remote_error_mid_stream() {
+        let mut error = 7u64.to_be_bytes().to_vec();
+        error.extend_from_slice(b"boom");
+
+        let mut mock = Mock::new()
+            .read_packet(ID::new(1, 1), Kind::Message, b"a")
+            .read_packet(ID::new(2, 1), Kind::Message, b"other stream")
+            .read_packet(ID::new(1, 2), Kind::Error, &error);
+
+        let mut buf = Vec::new();
+        let mut st = Stream::new(1, &mut mock, &mut buf);
+        let mut out = Vec::new();
+        st.recv_into(&mut out).await.unwrap();
+        assert_eq!(out, b"a");
+        match st.recv_into(&mut out).await {
+            Err(Error::StateError(State::RemoteError((7, msg)))) => assert_eq!(msg, "boom"),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // sends fail after the remote error without writing anything.
+        assert!(st.send(&Vec::new()).await.is_err());
+        mock.assert_done();
+    }
+
+    #[tokio::test]
+    async fn malformed_packets() {
+        let partial = frame::Frame {
+            data: b"part",
+            id: ID::new(1, 2),
+            kind: Kind::Message.into(),
+            done: false,
+            control: false,
+        };
+        let cases = vec![
+            (
+                Mock::new()
+                    .read_frame(partial)
+                    .read_packet(ID::new(1, 1), Kind::Message, b""),
+                ErrorKind::IDMonotonicityError,
+            ),
+            (
+                Mock::new()
+                    .read_frame(partial)
+                    .read_packet(ID::new(1, 2), Kind::Error, b""),
+                ErrorKind::PacketKindChangeError,
+            ),
+            (
+                Mock::new().read_bytes(&[0, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128]),
+                ErrorKind::ParseError,
+            ),
+            (
+                Mock::new().fail_read(transport::Error::io(
+                    transport::Op::Read,
+                    std::io::ErrorKind::ConnectionReset.into(),
+                )),
+                ErrorKind::IOError,
+            ),
+        ];
+
+        for (mut mock, expected) in cases {
+            let mut buf = Vec::new();
+            let mut st = Stream::new(1, &mut mock, &mut buf);
+            match st.recv_into(&mut Vec::new()).await {
+                Err(Error::TransportError(err)) => {
+                    assert_eq!(err.kind(), expected);
+                    assert_eq!(err.stream(), Some(1));
+                }
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+}
@@ -1,72 +1,610 @@
+use crate::pool::{self, Pool};
 use crate::wire::{frame, id, packet};
 
 use async_trait::async_trait;
+use bytes::BufMut;
+use std::io::{self, IoSlice};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{self, Instant};
+
+mod error;
+mod mock;
+pub(crate) mod split;
+mod stats;
+
+pub use error::{Error, ErrorKind, Op};
+pub use mock::Mock;
+pub use split::SplitTransport;
+pub use stats::{KindCounts, Stats};
 
 // error
 
-#[derive(Debug, Copy, Clone)]
-pub enum Error {
-    RemoteClosed,
-    ParseError,
-    IDMonotonicityError,
-    PacketKindChangeError,
-    DataOverflowError,
-    IOError,
+pub type Result<T> = std::result::Result<T, Error>;
+
+// options
+
+// the largest encoded frame header: the header byte and three varints.
+pub(crate) const MAX_HEADER_SIZE: usize = 1 + 10 + 10 + 10;
+
+/// Sizes and limits used by a transport and the streams over it. The defaults
+/// match the go implementation.
+#[derive(Clone)]
+pub struct TransportOptions {
+    pub(crate) max_packet_size: usize,
+    pub(crate) max_frame_size: usize,
+    pub(crate) split_size: usize,
+    pub(crate) flush_threshold: usize,
+    pub(crate) read_chunk_size: usize,
+    pub(crate) vectored_write_size: usize,
+    pub(crate) pool: Option<Arc<dyn Pool>>,
 }
 
-impl std::fmt::Display for Error {
+impl std::fmt::Debug for TransportOptions {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
+        f.debug_struct("TransportOptions")
+            .field("max_packet_size", &self.max_packet_size)
+            .field("max_frame_size", &self.max_frame_size)
+            .field("split_size", &self.split_size)
+            .field("flush_threshold", &self.flush_threshold)
+            .field("read_chunk_size", &self.read_chunk_size)
+            .field("vectored_write_size", &self.vectored_write_size)
+            .field("pool", &self.pool.is_some())
+            .finish()
     }
 }
 
-impl std::error::Error for Error {}
+impl Default for TransportOptions {
+    fn default() -> Self {
+        TransportOptions {
+            max_packet_size: 4 << 20,
+            max_frame_size: 4 << 20,
+            split_size: 64 << 10,
+            flush_threshold: 64 << 10,
+            read_chunk_size: 4 << 10,
+            vectored_write_size: 16 << 10,
+            pool: None,
+        }
+    }
+}
 
-pub type Result<T> = std::result::Result<T, Error>;
+impl TransportOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails reads of packets with more data than this with
+    /// DataOverflowError.
+    pub fn max_packet_size(mut self, n: usize) -> Self {
+        self.max_packet_size = n;
+        self
+    }
+
+    /// Fails reads of frames with more data than this with
+    /// DataOverflowError. At most this much data plus a frame header is
+    /// buffered waiting for a frame to complete.
+    pub fn max_frame_size(mut self, n: usize) -> Self {
+        self.max_frame_size = n;
+        self
+    }
+
+    /// Streams split packets into frames with at most this much data. Zero
+    /// sends every packet in a single frame.
+    pub fn split_size(mut self, n: usize) -> Self {
+        self.split_size = n;
+        self
+    }
+
+    /// Writes are flushed to the wire once this many bytes are buffered.
+    pub fn flush_threshold(mut self, n: usize) -> Self {
+        self.flush_threshold = n;
+        self
+    }
+
+    /// Reads from the wire ask for at most this many bytes at a time. Zero is
+    /// treated as one.
+    pub fn read_chunk_size(mut self, n: usize) -> Self {
+        self.read_chunk_size = n.max(1);
+        self
+    }
+
+    /// Frames with at least this much data are written to wires that support
+    /// vectored writes right away, along with anything buffered, without
+    /// copying their data. Smaller frames are buffered until a flush.
+    pub fn vectored_write_size(mut self, n: usize) -> Self {
+        self.vectored_write_size = n;
+        self
+    }
+
+    /// Takes buffers from the pool when they are needed and gives them back
+    /// as soon as they are drained, instead of every connection keeping its
+    /// own buffers as large as the largest packet it has seen.
+    pub fn pool<P: Pool + 'static>(mut self, pool: P) -> Self {
+        self.pool = Some(Arc::new(pool));
+        self
+    }
+}
+
+// read buffer
+
+// the read buffer holds unread bytes after a cursor. consuming bytes only
+// advances the cursor, and the consumed space is reclaimed before a read once
+// at least as many bytes were consumed as are left, so bytes are moved a
+// constant number of times on average however frames line up with reads.
+#[derive(Default)]
+pub(crate) struct ReadBuffer {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadBuffer {
+    pub(crate) fn unread(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    pub(crate) fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    fn extend_from_slice(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    // release resets the buffer and gives it back to the pool.
+    fn release(&mut self, pool: &Option<Arc<dyn Pool>>) {
+        self.buf.clear();
+        self.pos = 0;
+        pool::release(pool, &mut self.buf);
+    }
+
+    // spare reclaims consumed space if it is cheap and returns at most n
+    // bytes of spare capacity to read into.
+    pub(crate) fn spare(&mut self, n: usize) -> bytes::buf::Limit<&mut Vec<u8>> {
+        if self.pos > 0 && self.pos >= self.buf.len() - self.pos {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.buf.reserve(n);
+        (&mut self.buf).limit(n)
+    }
+}
+
+// assembler
+
+// assembler reassembles a packet out of the frames in a read buffer. control
+// frames are not part of any packet and are collected separately.
+struct Assembler {
+    id: id::ID,
+    kind: packet::Kind,
+    control: Vec<(u8, Vec<u8>)>,
+
+    max_packet_size: usize,
+    max_frame_size: usize,
+}
+
+impl Assembler {
+    fn new(opts: &TransportOptions) -> Assembler {
+        Assembler {
+            id: id::ID::default(),
+            kind: packet::Kind::default(),
+            control: Vec::new(),
+
+            max_packet_size: opts.max_packet_size,
+            max_frame_size: opts.max_frame_size,
+        }
+    }
+
+    // partial returns true if a frame of a packet has been consumed.
+    fn partial(&self) -> bool {
+        self.id != id::ID::default()
+    }
+
+    // next consumes frames from rbuf, appending their data to buf and
+    // counting them in stats. it returns the id and kind of the packet once
+    // its last frame is consumed, or None if rbuf needs more data first.
+    fn next(
+        &mut self,
+        rbuf: &mut ReadBuffer,
+        buf: &mut Vec<u8>,
+        stats: &mut Stats,
+    ) -> Result<Option<(id::ID, packet::Kind)>> {
+        let mut parsed = 0;
+        let res = self.parse(rbuf.unread(), &mut parsed, buf, stats);
+        rbuf.consume(parsed);
+        res
+    }
+
+    fn parse(
+        &mut self,
+        rbuf: &[u8],
+        parsed: &mut usize,
+        buf: &mut Vec<u8>,
+        stats: &mut Stats,
+    ) -> Result<Option<(id::ID, packet::Kind)>> {
+        loop {
+            let (fr, read) = match parse_frame(&rbuf[*parsed..], self.max_frame_size)? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            *parsed += read;
+
+            // protocol errors note the frame that caused them.
+            let violation =
+                |kind| Err(Error::new(kind, Op::Read).with_packet(fr.id, fr.kind.into()));
+
+            if fr.control {
+                stats.control_frames_in += 1;
+                self.control.push((fr.kind, fr.data.to_vec()));
+                continue;
+            }
+
+            stats.frames_in.count(fr.kind.into());
+            if fr.id < self.id {
+                return violation(ErrorKind::IDMonotonicityError);
+            } else if self.id < fr.id {
+                buf.clear();
+                self.id = fr.id;
+                self.kind = fr.kind.into();
+            } else if self.kind != fr.kind.into() {
+                return violation(ErrorKind::PacketKindChangeError);
+            }
+
+            buf.extend_from_slice(fr.data);
+
+            if buf.len() > self.max_packet_size {
+                return violation(ErrorKind::DataOverflowError);
+            } else if fr.done {
+                stats.packets_in.count(self.kind);
+                return Ok(Some((self.id, self.kind)));
+            }
+        }
+    }
+}
+
+// parse_frame parses the frame at the start of buf along with its size,
+// failing frames with more data than max_frame_size. it returns None if buf
+// needs more data first.
+pub(crate) fn parse_frame(
+    buf: &[u8],
+    max_frame_size: usize,
+) -> Result<Option<(frame::Frame<'_>, usize)>> {
+    match frame::parse_frame(buf) {
+        Ok((fr, _)) if fr.data.len() > max_frame_size => {
+            let err = Error::new(ErrorKind::DataOverflowError, Op::Read);
+            Err(err.with_packet(fr.id, fr.kind.into()))
+        }
+        Ok(v) => Ok(Some(v)),
+
+        // an incomplete frame this long has too much data whatever its
+        // header says, so fail without waiting for the rest.
+        Err(frame::Error::NotEnoughData) if buf.len() > max_frame_size + MAX_HEADER_SIZE => {
+            Err(Error::new(ErrorKind::DataOverflowError, Op::Read))
+        }
+        Err(frame::Error::NotEnoughData) => Ok(None),
+        Err(frame::Error::ParseError) => Err(Error::new(ErrorKind::ParseError, Op::Read)),
+    }
+}
+
+// keepalive
+
+// keepalive pings are control frames on stream 0 with the ping kind. a peer
+// answers a ping with a pong control frame carrying the same data. pings are
+// sent by reads waiting for data, so a transport nothing reads from sends
+// none. the timeout runs from the first ping since data was last received,
+// and any data received stops it, pongs or not. it is only armed once the
+// peer has answered a ping, as peers that ignore control frames never do.
+
+pub(crate) const CONTROL_PING: u8 = 1;
+pub(crate) const CONTROL_PONG: u8 = 2;
+
+pub(crate) fn control_frame(kind: u8, data: &[u8]) -> frame::Frame<'_> {
+    frame::Frame {
+        data,
+        id: id::ID::default(),
+        kind,
+        done: true,
+        control: true,
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Keepalive {
+    /// How long reads wait for data before sending a ping, and between
+    /// pings.
+    pub interval: Duration,
+    /// How long reads wait for data after the first ping before failing with
+    /// KeepaliveTimeout. Reads only fail once the peer has answered a ping,
+    /// so peers that do not answer them are only timed out when writing a
+    /// ping waits longer than this, as it does once the peer stops reading.
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(90),
+        }
+    }
+}
 
 // transport
 
 pub struct Transport<W> {
     w: W,
     wbuf: Vec<u8>,
-    rbuf: Vec<u8>,
+    // whether a write to the wire is underway, or was dropped midway.
+    writing: bool,
+    rbuf: ReadBuffer,
     err: Result<()>,
+    opts: TransportOptions,
+    stats: Stats,
+
+    read_timeout: Option<Duration>,
+    keepalive: Option<Keepalive>,
+    last_recv: Instant,
+    last_ping: Instant,
+    // when the first ping since data was last received was sent.
+    first_ping: Option<Instant>,
+    pings: u64,
+    // whether a pong was received, arming the keepalive timeout.
+    ponged: bool,
 }
 
 impl<W: crate::Wire> Transport<W> {
     pub fn new(w: W) -> Transport<W> {
+        Self::with_options(w, TransportOptions::default())
+    }
+
+    pub fn with_options(w: W, opts: TransportOptions) -> Transport<W> {
         Transport {
             w,
             wbuf: Vec::new(),
-            rbuf: Vec::new(),
+            writing: false,
+            rbuf: ReadBuffer::default(),
             err: Ok(()),
+            opts,
+            stats: Stats::default(),
+
+            read_timeout: None,
+            keepalive: None,
+            last_recv: Instant::now(),
+            last_ping: Instant::now(),
+            first_ping: None,
+            pings: 0,
+            ponged: false,
         }
     }
 
-    fn set_errored<V>(&mut self) -> Result<V> {
-        self.err = Err(Error::IOError);
-        Err(Error::IOError)
+    /// Enables sending pings while reads wait for data, and failing reads
+    /// that see no data for too long after a ping once the peer has answered
+    /// one. Pings are only sent while a read waits, so a transport that is
+    /// not read from, like that of an idle client connection, sends none and
+    /// notices a dead peer once it is read from again. Pings are always
+    /// answered whether or not this is enabled.
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.keepalive = Some(keepalive);
+    }
+
+    fn set_errored<V>(&mut self, err: Error) -> Result<V> {
+        self.stats.errors += 1;
+        self.err = Err(err.clone());
+        Err(err)
+    }
+
+    // raw_read appends at most a read chunk to rbuf.
+    async fn raw_read(&mut self) -> Result<usize> {
+        self.err.clone()?;
+        let chunk = self.opts.read_chunk_size;
+        pool::acquire(&self.opts.pool, &mut self.rbuf.buf, chunk);
+        let mut spare = self.rbuf.spare(chunk);
+        match self.w.read_buf(&mut spare).await {
+            Ok(v) => {
+                self.stats.bytes_read += v as u64;
+                Ok(v)
+            }
+            Err(err) => self.set_errored(Error::io(Op::Read, err)),
+        }
+    }
+
+    /// Fails reads that wait longer than the timeout for more data while a
+    /// packet is partially received.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = Some(timeout);
+    }
+
+    // read_some reads directly into the spare capacity of rbuf, sending pings
+    // while no data arrives if keepalive is enabled. partial is true if a
+    // packet is partially received.
+    async fn read_some(&mut self, partial: bool) -> Result<usize> {
+        let stall_at = match self.read_timeout {
+            Some(timeout) if partial => Some(Instant::now() + timeout),
+            _ => None,
+        };
+
+        loop {
+            let mut wake = stall_at;
+            if let Some(ka) = self.keepalive {
+                let mut at = self.ping_at(ka);
+                if let Some(first) = self.first_ping.filter(|_| self.ponged) {
+                    at = at.min(first + ka.timeout);
+                }
+                wake = Some(wake.map_or(at, |wake| wake.min(at)));
+            }
+
+            let res = match wake {
+                Some(wake) => time::timeout_at(wake, self.raw_read()).await,
+                None => Ok(self.raw_read().await),
+            };
+            if let Ok(res) = res {
+                let n = res?;
+                self.last_recv = Instant::now();
+                self.first_ping = None;
+                return Ok(n);
+            }
+
+            let now = Instant::now();
+            if stall_at.is_some_and(|at| now >= at) {
+                return self.set_errored(Error::new(ErrorKind::ReadTimeout, Op::Read));
+            }
+
+            let ka = match self.keepalive {
+                Some(ka) => ka,
+                None => continue,
+            };
+            if self.ponged
+                && self
+                    .first_ping
+                    .is_some_and(|first| now >= first + ka.timeout)
+            {
+                return self.set_errored(Error::new(ErrorKind::KeepaliveTimeout, Op::Read));
+            } else if now >= self.ping_at(ka) {
+                self.pings += 1;
+                let data = self.pings.to_be_bytes();
+                let fr = control_frame(CONTROL_PING, &data);
+                frame::append_frame(self.wbuf(fr.size()), &fr);
+                self.last_ping = now;
+                let first = *self.first_ping.get_or_insert(now);
+
+                // a peer that stops reading leaves the write waiting for
+                // room, so it is bounded by the timeout too.
+                let deadline = match self.ponged {
+                    true => first + ka.timeout,
+                    false => now + ka.timeout,
+                };
+                match time::timeout_at(deadline, self.flush_wbuf()).await {
+                    Ok(res) => res?,
+                    Err(_) => {
+                        let err = Error::new(ErrorKind::KeepaliveTimeout, Op::Write);
+                        return self.set_errored(err);
+                    }
+                }
+            }
+        }
+    }
+
+    // ping_at returns when the next ping is due: an interval after data was
+    // last received, and then an interval after every ping.
+    fn ping_at(&self, ka: Keepalive) -> Instant {
+        match self.first_ping {
+            Some(_) => self.last_ping + ka.interval,
+            None => self.last_recv + ka.interval,
+        }
+    }
+
+    // handle_control answers pings seen by the assembler. pongs arm the
+    // keepalive timeout and otherwise only matter as data received.
+    async fn handle_control(&mut self, asm: &mut Assembler) -> Result<()> {
+        if asm.control.is_empty() {
+            return Ok(());
+        }
+
+        for (kind, data) in asm.control.drain(..) {
+            match kind {
+                CONTROL_PING => {
+                    let fr = control_frame(CONTROL_PONG, &data);
+                    frame::append_frame(self.wbuf(fr.size()), &fr)
+                }
+                CONTROL_PONG => self.ponged = true,
+                _ => {}
+            }
+        }
+        self.flush_wbuf().await
+    }
+
+    // wbuf returns the write buffer, taking one that can hold size bytes from
+    // the pool if it was given back.
+    fn wbuf(&mut self, size: usize) -> &mut Vec<u8> {
+        pool::acquire(&self.opts.pool, &mut self.wbuf, size);
+        &mut self.wbuf
+    }
+
+    fn release_wbuf(&mut self) {
+        self.wbuf.clear();
+        pool::release(&self.opts.pool, &mut self.wbuf);
     }
 
-    async fn raw_read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.err?;
-        match self.w.read(buf).await {
-            Ok(v) => Ok(v),
-            Err(_) => self.set_errored(),
+    async fn flush_wbuf(&mut self) -> Result<()> {
+        self.err.clone()?;
+
+        if !self.wbuf.is_empty() {
+            let res = self.raw_flush().await;
+            self.release_wbuf();
+            res?
         }
+
+        Ok(())
     }
 
     async fn raw_flush(&mut self) -> Result<()> {
-        self.err?;
-        match self.w.write(&self.wbuf).await {
-            Err(_) => self.set_errored(),
-            Ok(_) => match self.w.flush().await {
-                Err(_) => self.set_errored(),
-                Ok(v) => Ok(v),
-            },
+        self.err.clone()?;
+        self.writing = true;
+        let res = self.w.write_all(&self.wbuf).await;
+        self.writing = false;
+        match res {
+            Err(err) => self.set_errored(Error::io(Op::Write, err)),
+            Ok(_) => {
+                self.stats.bytes_written += self.wbuf.len() as u64;
+                self.flush_wire().await
+            }
         }
     }
+
+    async fn flush_wire(&mut self) -> Result<()> {
+        match self.w.flush().await {
+            Err(err) => self.set_errored(Error::io(Op::Flush, err)),
+            Ok(v) => {
+                self.stats.flushes += 1;
+                Ok(v)
+            }
+        }
+    }
+
+    // write_vectored writes wbuf followed by data to the wire and flushes it,
+    // without copying data into wbuf.
+    async fn write_vectored(&mut self, data: &[u8]) -> Result<()> {
+        self.err.clone()?;
+
+        self.writing = true;
+        let (mut head, mut tail) = (0, 0);
+        while head < self.wbuf.len() || tail < data.len() {
+            let bufs = [
+                IoSlice::new(&self.wbuf[head..]),
+                IoSlice::new(&data[tail..]),
+            ];
+            let n = match self.w.write_vectored(&bufs).await {
+                Ok(0) => Err(io::ErrorKind::WriteZero.into()),
+                res => res,
+            };
+            let n = match n {
+                Err(err) => {
+                    self.writing = false;
+                    self.release_wbuf();
+                    return self.set_errored(Error::io(Op::Write, err));
+                }
+                Ok(n) => n,
+            };
+            self.stats.bytes_written += n as u64;
+            let from_head = n.min(self.wbuf.len() - head);
+            head += from_head;
+            tail += n - from_head;
+        }
+        self.writing = false;
+        self.release_wbuf();
+        self.flush_wire().await
+    }
+}
+
+impl<W> Drop for Transport<W> {
+    fn drop(&mut self) {
+        self.rbuf.release(&self.opts.pool);
+        pool::release(&self.opts.pool, &mut self.wbuf);
+    }
 }
 
 #[async_trait]
@@ -76,85 +614,530 @@ impl<W: crate::Wire> crate::Transport for Transport<W> {
     }
 
     async fn read_packet_into(&mut self, buf: &mut Vec<u8>) -> Result<(id::ID, packet::Kind)> {
-        self.err?;
-
-        let mut tmp = [0; 4096];
-        let mut parsed = 0;
+        self.err.clone()?;
 
+        let mut asm = Assembler::new(&self.opts);
         buf.clear();
-        let mut id = id::ID::default();
-        let mut kind = packet::Kind::default();
+        pool::acquire(&self.opts.pool, buf, 0);
 
         loop {
-            if parsed > 0 {
-                self.rbuf.drain(0..parsed);
-                parsed = 0;
+            // protocol errors leave rbuf mid packet, so they fail the
+            // transport like wire errors do.
+            let res = match asm.next(&mut self.rbuf, buf, &mut self.stats) {
+                Ok(res) => res,
+                Err(err) => return self.set_errored(err),
+            };
+            self.handle_control(&mut asm).await?;
+            if let Some(v) = res {
+                if self.rbuf.is_empty() {
+                    self.rbuf.release(&self.opts.pool);
+                }
+                return Ok(v);
             }
 
-            if self.rbuf.len() > (4 << 20 + 1 + 9 + 9 + 9) {
-                return Err(Error::DataOverflowError);
+            let partial = asm.partial() || !self.rbuf.is_empty();
+            if self.read_some(partial).await? == 0 {
+                return Err(Error::new(ErrorKind::RemoteClosed, Op::Read));
             }
+        }
+    }
 
-            match frame::parse_frame(&self.rbuf) {
-                Ok((fr, read)) => {
-                    parsed = read;
+    async fn write_frame(&mut self, fr: frame::Frame<'_>) -> Result<()> {
+        self.err.clone()?;
 
-                    if fr.control {
-                        continue;
-                    } else if fr.id < id {
-                        return Err(Error::IDMonotonicityError);
-                    } else if id < fr.id {
-                        buf.clear();
-                        id = fr.id;
-                        kind = fr.kind.into();
-                    } else if kind != fr.kind.into() {
-                        return Err(Error::PacketKindChangeError);
-                    }
+        if !fr.control {
+            self.stats.frames_out.count(fr.kind.into());
+            if fr.done {
+                self.stats.packets_out.count(fr.kind.into());
+            }
+        }
 
-                    buf.extend_from_slice(fr.data);
+        if fr.data.len() >= self.opts.vectored_write_size && self.w.is_write_vectored() {
+            frame::append_header(self.wbuf(MAX_HEADER_SIZE), &fr);
+            return self.write_vectored(fr.data).await;
+        }
 
-                    if buf.len() > (4 << 20) {
-                        return Err(Error::DataOverflowError);
-                    } else if fr.done {
-                        self.rbuf.drain(0..parsed);
-                        return Ok((id, kind));
-                    }
-                }
+        frame::append_frame(self.wbuf(fr.size()), &fr);
+        if self.wbuf.len() >= self.opts.flush_threshold {
+            self.flush().await?;
+        }
 
-                Err(frame::Error::NotEnoughData) => {
-                    // TODO: can we do this read directly into spare vector capacity?
-                    let n = self.raw_read(&mut tmp).await?;
-                    if n == 0 {
-                        return Err(Error::RemoteClosed);
-                    }
-                    self.rbuf.extend_from_slice(&tmp[0..n]);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.flush_wbuf().await
+    }
+
+    fn discard(&mut self) -> bool {
+        if self.writing {
+            return false;
+        }
+        self.release_wbuf();
+        true
+    }
+
+    fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    fn options(&self) -> TransportOptions {
+        self.opts.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wire::{frame, id, packet, split};
+    use crate::Transport as _;
+
+    use proptest::prelude::*;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::time::{self, Instant};
+
+    type Packets = Vec<(id::ID, packet::Kind, Vec<u8>)>;
+
+    // read_all reads packets from the input until the transport errors.
+    fn read_all(input: &[u8]) -> (Packets, super::Error) {
+        read_all_with(input, &super::TransportOptions::default())
+    }
+
+    fn read_all_with(input: &[u8], opts: &super::TransportOptions) -> (Packets, super::Error) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let wire = tokio::io::join(input, tokio::io::sink());
+            let mut tr = super::Transport::with_options(wire, opts.clone());
+            let mut buf = Vec::new();
+            let mut pkts = Vec::new();
+            loop {
+                match tr.read_packet_into(&mut buf).await {
+                    Ok((id, kind)) => pkts.push((id, kind, buf.clone())),
+                    Err(err) => return (pkts, err),
                 }
+            }
+        })
+    }
+
+    fn encode(pkt: &packet::Packet<Vec<u8>>, n: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for fr in split::split(pkt, n) {
+            frame::append_frame(&mut buf, &fr);
+        }
+        buf
+    }
+
+    fn message(len: usize) -> packet::Packet<Vec<u8>> {
+        packet::Packet {
+            data: vec![7; len],
+            id: id::ID::new(1, 1),
+            kind: packet::Kind::Message,
+        }
+    }
+
+    #[test]
+    fn max_packet_size() {
+        let opts = super::TransportOptions::new()
+            .max_packet_size(8)
+            .read_chunk_size(1);
+
+        let (pkts, err) = read_all_with(&encode(&message(8), 3), &opts);
+        assert_eq!(pkts.len(), 1);
+        assert_eq!(err.kind(), super::ErrorKind::RemoteClosed);
+
+        let (pkts, err) = read_all_with(&encode(&message(9), 3), &opts);
+        assert!(pkts.is_empty());
+        assert_eq!(err.kind(), super::ErrorKind::DataOverflowError);
+    }
+
+    #[test]
+    fn max_frame_size() {
+        let opts = super::TransportOptions::new().max_frame_size(8);
+
+        let (pkts, err) = read_all_with(&encode(&message(8), 0), &opts);
+        assert_eq!(pkts.len(), 1);
+        assert_eq!(err.kind(), super::ErrorKind::RemoteClosed);
 
-                Err(frame::Error::ParseError) => return Err(Error::ParseError),
+        let (pkts, err) = read_all_with(&encode(&message(9), 0), &opts);
+        assert!(pkts.is_empty());
+        assert_eq!(err.kind(), super::ErrorKind::DataOverflowError);
+
+        // a frame claiming too much data fails once more than a frame
+        // header and the maximum data are buffered.
+        let input = encode(&message(1 << 20), 0);
+        let limit = 8 + super::MAX_HEADER_SIZE;
+        let (_, err) = read_all_with(&input[..limit], &opts);
+        assert_eq!(err.kind(), super::ErrorKind::RemoteClosed);
+        let (_, err) = read_all_with(&input[..limit + 1], &opts);
+        assert_eq!(err.kind(), super::ErrorKind::DataOverflowError);
+    }
+
+    #[tokio::test]
+    async fn flush_threshold() {
+        // a message frame with d bytes of data encodes to 4 + d bytes.
+        let opts = super::TransportOptions::new().flush_threshold(10);
+        let pkt = message(6);
+
+        let mut wire = tokio::io::join(tokio::io::empty(), Vec::new());
+        let mut tr = super::Transport::with_options(&mut wire, opts.clone());
+        tr.write_frame(split::split(&pkt, 5).next().unwrap())
+            .await
+            .unwrap();
+        drop(tr);
+        assert!(wire.writer().is_empty());
+
+        let mut tr = super::Transport::with_options(&mut wire, opts.clone());
+        tr.write_frame(split::split(&pkt, 0).next().unwrap())
+            .await
+            .unwrap();
+        drop(tr);
+        assert_eq!(wire.writer().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn split_size() {
+        let opts = super::TransportOptions::new().split_size(3);
+
+        let mut wire = tokio::io::join(tokio::io::empty(), Vec::new());
+        let mut tr = super::Transport::with_options(&mut wire, opts.clone());
+        let mut buf = Vec::new();
+        let mut st = crate::stream::Stream::with_options(1, &mut tr, &mut buf, &opts);
+        crate::StreamSend::send(&mut st, &vec![7; 7]).await.unwrap();
+        drop(st);
+        tr.flush().await.unwrap();
+        drop(tr);
+
+        let mut expected = message(7);
+        expected.id.message = 1;
+        assert_eq!(wire.writer(), &encode(&expected, 3));
+    }
+
+    // chunky accepts at most max bytes per write and counts vectored writes.
+    struct Chunky {
+        out: Vec<u8>,
+        max: usize,
+        vectored: bool,
+        writes: usize,
+        vectored_writes: usize,
+    }
+
+    impl tokio::io::AsyncWrite for Chunky {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(self.max);
+            self.writes += 1;
+            self.out.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            bufs: &[io::IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let mut n = 0;
+            for buf in bufs {
+                let m = buf.len().min(self.max - n);
+                self.out.extend_from_slice(&buf[..m]);
+                n += m;
             }
+            self.vectored_writes += 1;
+            Poll::Ready(Ok(n))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            self.vectored
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
         }
     }
 
-    async fn write_frame(&mut self, fr: frame::Frame<'_>) -> Result<()> {
-        self.err?;
+    #[tokio::test]
+    async fn vectored_writes() {
+        let opts = super::TransportOptions::new().vectored_write_size(8);
+        let (small, large) = (message(3), message(20));
+        let mut expected = encode(&small, 0);
+        expected.extend(encode(&large, 0));
 
-        frame::append_frame(&mut self.wbuf, &fr);
-        if self.wbuf.len() >= 64 * 1024 {
-            self.flush().await?;
+        for &vectored in &[true, false] {
+            let chunky = Chunky {
+                out: Vec::new(),
+                max: 7,
+                vectored,
+                writes: 0,
+                vectored_writes: 0,
+            };
+            let mut wire = tokio::io::join(tokio::io::empty(), chunky);
+            let mut tr = super::Transport::with_options(&mut wire, opts.clone());
+            tr.write_frame(split::split(&small, 0).next().unwrap())
+                .await
+                .unwrap();
+            tr.write_frame(split::split(&large, 0).next().unwrap())
+                .await
+                .unwrap();
+            tr.flush().await.unwrap();
+            drop(tr);
+
+            let chunky = wire.writer();
+            assert_eq!(chunky.out, expected);
+            if vectored {
+                // the small frame is written along with the large one.
+                assert_eq!((chunky.writes, chunky.vectored_writes), (0, 5));
+            } else {
+                assert_eq!((chunky.writes, chunky.vectored_writes), (5, 0));
+            }
         }
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn stats() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = super::Transport::new(client);
+        let mut server = super::Transport::new(server);
+
+        let invoke = packet::Packet {
+            data: b"/rpc".to_vec(),
+            id: id::ID::new(1, 1),
+            kind: packet::Kind::Invoke,
+        };
+        let mut msg = message(3);
+        msg.id.message = 2;
+        let ping = 1u64.to_be_bytes();
+
+        let mut written = encode(&invoke, 2);
+        written.extend(encode(&msg, 0));
+        for fr in split::split(&invoke, 2) {
+            client.write_frame(fr).await.unwrap();
+        }
+        client
+            .write_frame(super::control_frame(super::CONTROL_PING, &ping))
+            .await
+            .unwrap();
+        client
+            .write_frame(split::split(&msg, 0).next().unwrap())
+            .await
+            .unwrap();
+        client.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        server.read_packet_into(&mut buf).await.unwrap();
+        server.read_packet_into(&mut buf).await.unwrap();
+
+        let stats = client.stats();
+        assert_eq!(stats.frames_out.invoke, 2);
+        assert_eq!(stats.frames_out.total(), 3);
+        assert_eq!(stats.packets_out.get(packet::Kind::Invoke), 1);
+        assert_eq!(stats.packets_out.get(packet::Kind::Message), 1);
+        assert_eq!(stats.bytes_written as usize, written.len() + 12);
+        assert_eq!(stats.flushes, 1);
+
+        // the server skipped the ping and answered it with a pong.
+        let stats = server.stats();
+        assert_eq!(stats.frames_in, client.stats().frames_out);
+        assert_eq!(stats.packets_in, client.stats().packets_out);
+        assert_eq!(stats.bytes_read, client.stats().bytes_written);
+        assert_eq!(stats.control_frames_in, 1);
+        assert_eq!((stats.bytes_written, stats.flushes), (12, 1));
+        assert_eq!(stats.errors, 0);
     }
 
-    async fn flush(&mut self) -> Result<()> {
-        self.err?;
+    #[tokio::test]
+    async fn stats_protocol_errors() {
+        let mut input = encode(&message(8), 0);
+        input.extend(encode(&message(9), 0));
 
-        if self.wbuf.len() > 0 {
-            let res = self.raw_flush().await;
-            self.wbuf.clear();
-            res?
+        let opts = super::TransportOptions::new().max_frame_size(8);
+        let wire = tokio::io::join(&input[..], tokio::io::sink());
+        let mut tr = super::Transport::with_options(wire, opts);
+        let mut buf = Vec::new();
+        tr.read_packet_into(&mut buf).await.unwrap();
+        let before = tr.stats();
+
+        // the violation fails the transport and is counted once.
+        let err = tr.read_packet_into(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), super::ErrorKind::DataOverflowError);
+        let err = tr.read_packet_into(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), super::ErrorKind::DataOverflowError);
+
+        let stats = tr.stats();
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.since(&before).errors, 1);
+        assert_eq!(before.since(&stats), super::Stats::default());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_timeout() {
+        let ka = super::Keepalive {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+        };
+        let (client, mut server) = tokio::io::duplex(1024);
+
+        // the server answers pings for a while and then stops reading
+        // without closing the connection.
+        tokio::spawn(async move {
+            let mut tr = super::Transport::new(&mut server);
+            let mut buf = Vec::new();
+            let read = tr.read_packet_into(&mut buf);
+            let _ = time::timeout(Duration::from_millis(200), read).await;
+            drop(tr);
+            time::sleep(Duration::from_secs(3600)).await;
+        });
+
+        let mut tr = super::Transport::new(client);
+        tr.set_keepalive(ka);
+        let start = Instant::now();
+        let res = tr.read_packet_into(&mut Vec::new()).await;
+        assert_eq!(res.unwrap_err().kind(), super::ErrorKind::KeepaliveTimeout);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(start.elapsed() < Duration::from_millis(300));
+
+        // the transport stays failed for anything waiting on it.
+        let res = tr.read_packet_into(&mut Vec::new()).await;
+        assert_eq!(res.unwrap_err().kind(), super::ErrorKind::KeepaliveTimeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_unanswered_pings() {
+        let ka = super::Keepalive {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+        };
+        let (client, server) = tokio::io::duplex(1024);
+
+        // a peer that ignores control frames never answers pings, and sends
+        // a message long after the timeout.
+        let (mut rd, mut wr) = tokio::io::split(server);
+        let peer = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            let _ = tokio::io::AsyncReadExt::read_to_end(&mut rd, &mut buf).await;
+            buf
+        });
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(205)).await;
+            let msg = encode(&message(1), 0);
+            tokio::io::AsyncWriteExt::write_all(&mut wr, &msg)
+                .await
+                .unwrap();
+            time::sleep(Duration::from_secs(3600)).await;
+        });
+
+        // so the timeout is never armed and the message is read.
+        let mut tr = super::Transport::new(client);
+        tr.set_keepalive(ka);
+        let start = Instant::now();
+        let mut buf = Vec::new();
+        tr.read_packet_into(&mut buf).await.unwrap();
+        assert_eq!(buf, message(1).data);
+        assert_eq!(start.elapsed(), Duration::from_millis(205));
+        drop(tr);
+
+        // pings went out every interval while the read waited.
+        let pings = peer.await.unwrap();
+        assert_eq!(&pings[..4], &[0x83, 0, 0, 8]);
+        assert_eq!(pings.len(), 12 * 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_peer_not_reading() {
+        let ka = super::Keepalive {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+        };
+
+        // the pipe holds the first ping and part of the second, which then
+        // waits for a peer that never reads.
+        let (client, _server) = tokio::io::duplex(16);
+        let mut tr = super::Transport::new(client);
+        tr.set_keepalive(ka);
+        let start = Instant::now();
+        let res = tr.read_packet_into(&mut Vec::new()).await;
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), super::ErrorKind::KeepaliveTimeout);
+        assert_eq!(err.op(), super::Op::Write);
+        assert_eq!(start.elapsed(), Duration::from_millis(70));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_idle_conn() {
+        let ka = super::Keepalive {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+        };
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut tr = super::Transport::new(client);
+        tr.set_keepalive(ka);
+        let mut conn = crate::conn::Conn::new(tr);
+
+        // an idle conn is not read from, so it sends no pings.
+        time::sleep(Duration::from_secs(1)).await;
+        let mut buf = [0; 64];
+        let read = tokio::io::AsyncReadExt::read(&mut server, &mut buf);
+        assert!(time::timeout(Duration::from_millis(1), read).await.is_err());
+
+        // a peer that answers the first ping of the next rpc and then goes
+        // silent fails it once the timeout of the ping after that passes.
+        tokio::spawn(async move {
+            let mut tr = super::Transport::new(&mut server);
+            let mut buf = Vec::new();
+            let read = async { while tr.read_packet_into(&mut buf).await.is_ok() {} };
+            let _ = time::timeout(Duration::from_millis(15), read).await;
+            drop(tr);
+            time::sleep(Duration::from_secs(3600)).await;
+        });
+        let start = Instant::now();
+        let mut out = Vec::new();
+        let res = conn.invoke_into(b"/rpc", &vec![1], &mut out).await;
+        assert!(res.unwrap_err().is_timeout());
+        assert_eq!(start.elapsed(), Duration::from_millis(70));
+    }
+
+    proptest! {
+        #[test]
+        fn reassemble_split_packets(
+            datas in prop::collection::vec((1u8..8, any::<Vec<u8>>()), 0..8),
+            n in 1usize..32,
+            chunk in 1usize..64,
+        ) {
+            let mut input = Vec::new();
+            let mut expected = Vec::new();
+            for (i, (kind, data)) in datas.into_iter().enumerate() {
+                let pkt = packet::Packet {
+                    data,
+                    id: id::ID::new(1, i as u64 + 1),
+                    kind: kind.into(),
+                };
+                for fr in split::split(&pkt, n) {
+                    frame::append_frame(&mut input, &fr);
+                }
+                expected.push((pkt.id, pkt.kind, pkt.data));
+            }
+
+            let opts = super::TransportOptions::new().read_chunk_size(chunk);
+            let (pkts, err) = read_all_with(&input, &opts);
+            prop_assert_eq!(pkts, expected);
+            prop_assert_eq!(err.kind(), super::ErrorKind::RemoteClosed);
         }
 
-        Ok(())
+        #[test]
+        fn read_arbitrary(input: Vec<u8>) {
+            let (pkts, _) = read_all(&input);
+            let total: usize = pkts.iter().map(|(_, _, data)| data.len()).sum();
+            prop_assert!(total <= input.len());
+        }
     }
 }
@@ -0,0 +1,412 @@
+use crate::pool;
+use crate::transport::{Error, ErrorKind, Op, Result, Stats, Transport, TransportOptions};
+use crate::wire::{frame, id, packet, split};
+use crate::Transport as _;
+
+use async_trait::async_trait;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::AbortHandle;
+
+// a split wire is read and written by tasks of their own. the reader queues
+// the packets it reads for the transports over the wire, and the writer
+// writes the packets every transport hands it whole, so the frames of
+// different packets are never interleaved. the transports only wait on
+// channels to the tasks, which loses nothing when cancelled.
+
+// how many bytes of packets are queued for a transport by default.
+pub(crate) const QUEUE_SIZE: usize = 4 << 20;
+
+pub(crate) type Packet = (id::ID, packet::Kind, Vec<u8>);
+
+pub(crate) enum Command {
+    Packet(packet::Packet<Vec<u8>>, oneshot::Sender<Result<()>>),
+    Flush(oneshot::Sender<Result<()>>),
+    // frames written by the transport reading the wire, which are only
+    // keepalive pings and pongs.
+    Control(Vec<u8>),
+}
+
+// queue returns the two ends of a queue of packets bounded to about limit
+// bytes. every packet takes up its data and a frame header, and a packet
+// larger than the limit takes up the whole queue.
+pub(crate) fn queue(limit: usize) -> (QueueSender, QueueReceiver) {
+    let limit = limit.clamp(1, u32::MAX as usize);
+    let (tx, rx) = mpsc::unbounded_channel();
+    let room = Arc::new(Semaphore::new(limit));
+    let tx = QueueSender {
+        packets: tx,
+        room: room.clone(),
+        limit,
+    };
+    let rx = QueueReceiver {
+        packets: rx,
+        room,
+        limit,
+    };
+    (tx, rx)
+}
+
+fn queue_cost(pkt: &Packet, limit: usize) -> u32 {
+    (pkt.2.len() + super::MAX_HEADER_SIZE).min(limit) as u32
+}
+
+pub(crate) struct QueueSender {
+    packets: mpsc::UnboundedSender<Result<Packet>>,
+    room: Arc<Semaphore>,
+    limit: usize,
+}
+
+impl QueueSender {
+    // try_send queues the packet if there is room for it, and returns false
+    // otherwise. packets sent after the receiver is gone are dropped.
+    pub(crate) fn try_send(&self, pkt: Packet) -> bool {
+        match self.room.try_acquire_many(queue_cost(&pkt, self.limit)) {
+            Ok(permit) => permit.forget(),
+            Err(_) => return false,
+        }
+        let _ = self.packets.send(Ok(pkt));
+        true
+    }
+
+    // send waits for room for the packet and queues it. it returns false if
+    // the receiver is gone.
+    pub(crate) async fn send(&self, pkt: Packet) -> bool {
+        match self.room.acquire_many(queue_cost(&pkt, self.limit)).await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return false,
+        }
+        self.packets.send(Ok(pkt)).is_ok()
+    }
+
+    // fail makes the receiver fail with err once it received the packets
+    // already queued.
+    pub(crate) fn fail(&self, err: Error) {
+        let _ = self.packets.send(Err(err));
+    }
+}
+
+pub(crate) struct QueueReceiver {
+    packets: mpsc::UnboundedReceiver<Result<Packet>>,
+    room: Arc<Semaphore>,
+    limit: usize,
+}
+
+impl QueueReceiver {
+    async fn recv(&mut self) -> Option<Result<Packet>> {
+        let res = self.packets.recv().await?;
+        if let Ok(pkt) = &res {
+            self.room.add_permits(queue_cost(pkt, self.limit) as usize);
+        }
+        Some(res)
+    }
+}
+
+// write_loop writes the packets handed to it until every sender is gone,
+// reporting the stats of the wire after every flush.
+pub(crate) async fn write_loop<W>(
+    wr: W,
+    mut cmds: mpsc::UnboundedReceiver<Command>,
+    opts: &TransportOptions,
+    mut report: impl FnMut(Stats),
+) where
+    W: AsyncWrite + Unpin + Send,
+{
+    let wire = tokio::io::join(tokio::io::empty(), wr);
+    let mut tr = Transport::with_options(wire, opts.clone());
+
+    while let Some(cmd) = cmds.recv().await {
+        match cmd {
+            Command::Packet(mut pkt, done) => {
+                let _ = done.send(write_packet(&mut tr, &pkt, opts).await);
+                pool::release(&opts.pool, &mut pkt.data);
+            }
+            Command::Flush(done) => {
+                let _ = done.send(tr.flush().await);
+                report(tr.stats());
+            }
+            Command::Control(data) => {
+                // a failed wire fails the next packet or flush of a transport.
+                if tr.flush().await.is_ok() {
+                    let wire = crate::Transport::wire(&mut tr);
+                    let _ = wire.write_all(&data).await;
+                    let _ = wire.flush().await;
+                }
+            }
+        }
+    }
+
+    let _ = tr.flush().await;
+    report(tr.stats());
+}
+
+async fn write_packet<W: crate::Wire>(
+    tr: &mut Transport<W>,
+    pkt: &packet::Packet<Vec<u8>>,
+    opts: &TransportOptions,
+) -> Result<()> {
+    for fr in split::split(pkt, opts.split_size) {
+        tr.write_frame(fr).await?;
+    }
+    Ok(())
+}
+
+// control_wire hands what the transport reading the wire writes to the
+// writer. the transport writes whole frames with every write.
+pub(crate) struct ControlWire(pub(crate) mpsc::UnboundedSender<Command>);
+
+impl AsyncWrite for ControlWire {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.0.send(Command::Control(data.to_vec())) {
+            Ok(()) => Poll::Ready(Ok(data.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// channel_transport reads the packets queued for it, and hands every packet
+// written to the writer once its last frame is written.
+pub(crate) struct ChannelTransport {
+    wire: tokio::io::Empty,
+    packets: QueueReceiver,
+    cmds: mpsc::UnboundedSender<Command>,
+    pending: Vec<u8>,
+    opts: TransportOptions,
+}
+
+impl ChannelTransport {
+    pub(crate) fn new(
+        packets: QueueReceiver,
+        cmds: mpsc::UnboundedSender<Command>,
+        opts: &TransportOptions,
+    ) -> ChannelTransport {
+        ChannelTransport {
+            wire: tokio::io::empty(),
+            packets,
+            cmds,
+            pending: Vec::new(),
+            opts: opts.clone(),
+        }
+    }
+
+    // send hands a command to the writer and waits for its result.
+    async fn send(
+        &mut self,
+        op: Op,
+        cmd: impl FnOnce(oneshot::Sender<Result<()>>) -> Command,
+    ) -> Result<()> {
+        let closed = || Error::new(ErrorKind::RemoteClosed, op);
+        let (done, res) = oneshot::channel();
+        self.cmds.send(cmd(done)).map_err(|_| closed())?;
+        res.await.map_err(|_| closed())?
+    }
+}
+
+#[async_trait]
+impl crate::Transport for ChannelTransport {
+    fn wire(&mut self) -> &mut dyn crate::Wire {
+        &mut self.wire
+    }
+
+    async fn read_packet_into(&mut self, buf: &mut Vec<u8>) -> Result<(id::ID, packet::Kind)> {
+        match self.packets.recv().await {
+            Some(Ok((id, kind, data))) => {
+                pool::release(&self.opts.pool, buf);
+                *buf = data;
+                Ok((id, kind))
+            }
+            Some(Err(err)) => Err(err),
+            None => Err(Error::new(ErrorKind::RemoteClosed, Op::Read)),
+        }
+    }
+
+    async fn write_frame(&mut self, fr: frame::Frame<'_>) -> Result<()> {
+        pool::acquire(&self.opts.pool, &mut self.pending, fr.data.len());
+        self.pending.extend_from_slice(fr.data);
+        if !fr.done {
+            return Ok(());
+        }
+
+        let pkt = packet::Packet {
+            data: std::mem::take(&mut self.pending),
+            id: fr.id,
+            kind: fr.kind.into(),
+        };
+        self.send(Op::Write, |done| Command::Packet(pkt, done))
+            .await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.send(Op::Flush, Command::Flush).await
+    }
+
+    // packets are handed to the writer whole, so only the frames of an
+    // unfinished one are dropped.
+    fn discard(&mut self) -> bool {
+        self.pending.clear();
+        true
+    }
+    fn options(&self) -> TransportOptions {
+        self.opts.clone()
+    }
+}
+
+impl Drop for ChannelTransport {
+    fn drop(&mut self) {
+        pool::release(&self.opts.pool, &mut self.pending);
+    }
+}
+
+/// SplitTransport reads and writes its wire from tasks of its own, so that
+/// reads and writes cancelled midway lose nothing. A stream over it can wait
+/// for a message and for something to send at once. It must be created
+/// within a tokio runtime, and keeps no statistics.
+pub struct SplitTransport {
+    inner: ChannelTransport,
+    reader: AbortHandle,
+}
+
+impl SplitTransport {
+    pub fn new<W: crate::Wire + 'static>(wire: W, opts: TransportOptions) -> SplitTransport {
+        let (rd, wr) = tokio::io::split(wire);
+        let (cmds, rx) = mpsc::unbounded_channel();
+        let (tx, packets) = queue(QUEUE_SIZE);
+
+        let wopts = opts.clone();
+        tokio::spawn(async move { write_loop(wr, rx, &wopts, |_| ()).await });
+        let reader = tokio::spawn(read_loop(rd, cmds.clone(), tx, opts.clone()));
+        SplitTransport {
+            inner: ChannelTransport::new(packets, cmds, &opts),
+            reader: reader.abort_handle(),
+        }
+    }
+}
+
+// read_loop queues the packets read from the wire until the wire fails.
+async fn read_loop<R>(
+    rd: R,
+    cmds: mpsc::UnboundedSender<Command>,
+    packets: QueueSender,
+    opts: TransportOptions,
+) where
+    R: AsyncRead + Unpin + Send,
+{
+    let wire = tokio::io::join(rd, ControlWire(cmds));
+    let mut tr = Transport::with_options(wire, opts);
+    loop {
+        let mut buf = Vec::new();
+        match tr.read_packet_into(&mut buf).await {
+            Ok((id, kind)) => {
+                if !packets.send((id, kind, buf)).await {
+                    return;
+                }
+            }
+            Err(err) => return packets.fail(err),
+        }
+    }
+}
+
+#[async_trait]
+impl crate::Transport for SplitTransport {
+    fn wire(&mut self) -> &mut dyn crate::Wire {
+        self.inner.wire()
+    }
+
+    async fn read_packet_into(&mut self, buf: &mut Vec<u8>) -> Result<(id::ID, packet::Kind)> {
+        self.inner.read_packet_into(buf).await
+    }
+
+    async fn write_frame(&mut self, fr: frame::Frame<'_>) -> Result<()> {
+        self.inner.write_frame(fr).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn discard(&mut self) -> bool {
+        self.inner.discard()
+    }
+
+    fn options(&self) -> TransportOptions {
+        self.inner.options()
+    }
+}
+
+impl Drop for SplitTransport {
+    // the writer is done once the reader and the transport are gone, after
+    // writing what it was handed.
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SplitTransport;
+    use crate::transport::{Transport, TransportOptions};
+    use crate::wire::{id, packet, split};
+    use crate::Transport as _;
+
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_reads() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut tr = SplitTransport::new(client, TransportOptions::default());
+        let mut remote = Transport::new(server);
+
+        // a read cancelled halfway through a packet loses none of it.
+        let pkt = packet::Packet {
+            data: vec![1, 2, 3, 4],
+            id: id::ID::new(1, 1),
+            kind: packet::Kind::Message,
+        };
+        let mut frames = split::split(&pkt, 2);
+        remote.write_frame(frames.next().unwrap()).await.unwrap();
+        remote.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        let read = time::timeout(Duration::from_secs(1), tr.read_packet_into(&mut buf));
+        assert!(read.await.is_err());
+
+        for fr in frames {
+            remote.write_frame(fr).await.unwrap();
+        }
+        remote.flush().await.unwrap();
+        let (id, kind) = tr.read_packet_into(&mut buf).await.unwrap();
+        assert_eq!((id, kind, buf), (pkt.id, pkt.kind, pkt.data.clone()));
+
+        // written packets reach the remote once flushed.
+        for fr in split::split(&pkt, 3) {
+            tr.write_frame(fr).await.unwrap();
+        }
+        tr.flush().await.unwrap();
+        let mut buf = Vec::new();
+        let (id, kind) = remote.read_packet_into(&mut buf).await.unwrap();
+        assert_eq!((id, kind, buf), (pkt.id, pkt.kind, pkt.data));
+
+        // the transport fails reads once the remote is gone.
+        drop(remote);
+        let err = tr.read_packet_into(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), crate::transport::ErrorKind::RemoteClosed);
+    }
+}
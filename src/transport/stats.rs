@@ -0,0 +1,107 @@
+use crate::wire::packet::Kind;
+
+/// KindCounts counts frames or packets by kind.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KindCounts {
+    pub invoke: u64,
+    pub message: u64,
+    pub error: u64,
+    pub close: u64,
+    pub close_send: u64,
+    pub invoke_metadata: u64,
+    pub other: u64,
+}
+
+impl KindCounts {
+    pub fn get(&self, kind: Kind) -> u64 {
+        match kind {
+            Kind::Invoke => self.invoke,
+            Kind::Message => self.message,
+            Kind::Error => self.error,
+            Kind::Close => self.close,
+            Kind::CloseSend => self.close_send,
+            Kind::InvokeMetadata => self.invoke_metadata,
+            Kind::Other(_) => self.other,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.invoke
+            + self.message
+            + self.error
+            + self.close
+            + self.close_send
+            + self.invoke_metadata
+            + self.other
+    }
+
+    pub(crate) fn count(&mut self, kind: Kind) {
+        let n = match kind {
+            Kind::Invoke => &mut self.invoke,
+            Kind::Message => &mut self.message,
+            Kind::Error => &mut self.error,
+            Kind::Close => &mut self.close,
+            Kind::CloseSend => &mut self.close_send,
+            Kind::InvokeMetadata => &mut self.invoke_metadata,
+            Kind::Other(_) => &mut self.other,
+        };
+        *n += 1;
+    }
+
+    fn zip(&self, other: &KindCounts, f: impl Fn(u64, u64) -> u64) -> KindCounts {
+        KindCounts {
+            invoke: f(self.invoke, other.invoke),
+            message: f(self.message, other.message),
+            error: f(self.error, other.error),
+            close: f(self.close, other.close),
+            close_send: f(self.close_send, other.close_send),
+            invoke_metadata: f(self.invoke_metadata, other.invoke_metadata),
+            other: f(self.other, other.other),
+        }
+    }
+}
+
+/// Stats is a snapshot of what a transport has read and written. Control
+/// frames are not counted as frames of any kind.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub frames_in: KindCounts,
+    pub frames_out: KindCounts,
+    pub packets_in: KindCounts,
+    pub packets_out: KindCounts,
+    /// Control frames read, which are skipped when assembling packets.
+    pub control_frames_in: u64,
+    /// Flushes of the wire.
+    pub flushes: u64,
+    /// Errors that failed the transport.
+    pub errors: u64,
+}
+
+impl Stats {
+    /// Adds the counts of other to these.
+    pub fn add(&mut self, other: &Stats) {
+        *self = self.zip(other, |a, b| a + b);
+    }
+
+    /// Returns the counts since an earlier snapshot of the same transport.
+    /// Counts lower than in earlier are zero.
+    pub fn since(&self, earlier: &Stats) -> Stats {
+        self.zip(earlier, u64::saturating_sub)
+    }
+
+    fn zip(&self, other: &Stats, f: impl Fn(u64, u64) -> u64 + Copy) -> Stats {
+        Stats {
+            bytes_read: f(self.bytes_read, other.bytes_read),
+            bytes_written: f(self.bytes_written, other.bytes_written),
+            frames_in: self.frames_in.zip(&other.frames_in, f),
+            frames_out: self.frames_out.zip(&other.frames_out, f),
+            packets_in: self.packets_in.zip(&other.packets_in, f),
+            packets_out: self.packets_out.zip(&other.packets_out, f),
+            control_frames_in: f(self.control_frames_in, other.control_frames_in),
+            flushes: f(self.flushes, other.flushes),
+            errors: f(self.errors, other.errors),
+        }
+    }
+}
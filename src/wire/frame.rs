@@ -26,12 +26,12 @@ impl From<varint::Error> for Error {
 }
 
 impl<'a> Frame<'a> {
-    pub fn size(self: &Self) -> usize {
+    pub fn size(&self) -> usize {
         1 + 9 + 9 + 9 + self.data.len()
     }
 }
 
-pub fn parse_frame(buf: &[u8]) -> Result<(Frame, usize), Error> {
+pub fn parse_frame(buf: &[u8]) -> Result<(Frame<'_>, usize), Error> {
     let mut buf = buf;
     let mut fr: Frame = Default::default();
     let read: usize = 1;
@@ -67,6 +67,13 @@ pub fn parse_frame(buf: &[u8]) -> Result<(Frame, usize), Error> {
 }
 
 pub fn append_frame<'a>(buf: &mut Vec<u8>, fr: &Frame<'a>) {
+    append_header(buf, fr);
+    buf.extend_from_slice(fr.data);
+}
+
+/// Appends the header of the frame, which is followed on the wire by exactly
+/// fr.data.
+pub fn append_header<'a>(buf: &mut Vec<u8>, fr: &Frame<'a>) {
     let mut control = fr.kind << 1;
     if fr.done {
         control |= 0b00000001
@@ -79,7 +86,6 @@ pub fn append_frame<'a>(buf: &mut Vec<u8>, fr: &Frame<'a>) {
     varint::append(buf, fr.id.stream);
     varint::append(buf, fr.id.message);
     varint::append(buf, fr.data.len() as u64);
-    buf.extend_from_slice(fr.data);
 }
 
 #[cfg(test)]
@@ -120,4 +126,43 @@ mod tests {
             Err(super::Error::ParseError),
         )
     }
+
+    proptest::proptest! {
+        #[test]
+        fn append_parse(
+            data: Vec<u8>,
+            stream: u64,
+            message: u64,
+            kind in 0u8..64,
+            done: bool,
+            control: bool,
+            tail: Vec<u8>,
+        ) {
+            let fr = super::Frame {
+                data: &data,
+                id: super::id::ID::new(stream, message),
+                kind,
+                done,
+                control,
+            };
+            let mut buf = vec![];
+            super::append_frame(&mut buf, &fr);
+            let n = buf.len();
+            buf.extend_from_slice(&tail);
+
+            proptest::prop_assert_eq!(super::parse_frame(&buf), Ok((fr, n)));
+            for i in 0..n {
+                proptest::prop_assert_eq!(super::parse_frame(&buf[..i]), Err(super::Error::NotEnoughData));
+            }
+        }
+
+        #[test]
+        fn parse_arbitrary(buf: Vec<u8>) {
+            if let Ok((fr, n)) = super::parse_frame(&buf) {
+                proptest::prop_assert!(n <= buf.len());
+                proptest::prop_assert!(fr.data.len() < n);
+                proptest::prop_assert!(fr.kind < 64);
+            }
+        }
+    }
 }
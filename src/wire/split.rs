@@ -17,7 +17,7 @@ impl<'a> Iterator for Split<'a> {
         }
 
         let mut fr = frame::Frame {
-            data: &self.data,
+            data: self.data,
             id: self.id,
             kind: self.kind,
             done: true,
@@ -112,4 +112,25 @@ mod tests {
             }]
         )
     }
+
+    proptest::proptest! {
+        #[test]
+        fn split_reassemble(data: Vec<u8>, n in 0usize..64) {
+            let pkt = packet::Packet {
+                data: data.clone(),
+                id: ID,
+                kind: packet::Kind::Message,
+            };
+            let frames: Vec<_> = super::split(&pkt, n).collect();
+
+            let mut out = vec![];
+            for (i, fr) in frames.iter().enumerate() {
+                proptest::prop_assert_eq!(fr.done, i == frames.len() - 1);
+                proptest::prop_assert!(n == 0 || fr.data.len() <= n);
+                proptest::prop_assert!(fr.done || !fr.data.is_empty());
+                out.extend_from_slice(fr.data);
+            }
+            proptest::prop_assert_eq!(out, data);
+        }
+    }
 }
@@ -26,7 +26,7 @@ pub fn read(mut buf: &[u8]) -> Result<(u64, usize), Error> {
     Err(Error::VarintTooLong)
 }
 
-pub fn append(buf: &mut Vec<u8>, mut x: u64) -> () {
+pub fn append(buf: &mut Vec<u8>, mut x: u64) {
     while x >= 128 {
         buf.push((x & 127 | 128) as u8);
         x >>= 7;
@@ -72,4 +72,25 @@ mod tests {
         super::append(&mut buf, 49408);
         assert_eq!(&buf, &[128, 130, 3])
     }
+
+    proptest::proptest! {
+        #[test]
+        fn append_read(x: u64, tail: Vec<u8>) {
+            let mut buf = vec![];
+            super::append(&mut buf, x);
+            let n = buf.len();
+            buf.extend_from_slice(&tail);
+
+            proptest::prop_assert!(n <= 10);
+            proptest::prop_assert_eq!(super::read(&buf), Ok((x, n)));
+            proptest::prop_assert_eq!(super::read(&buf[..n - 1]), Err(super::Error::NotEnoughData));
+        }
+
+        #[test]
+        fn read_arbitrary(buf: Vec<u8>) {
+            if let Ok((_, n)) = super::read(&buf) {
+                proptest::prop_assert!(n <= buf.len() && n <= 10);
+            }
+        }
+    }
 }
@@ -0,0 +1,5 @@
+#[test]
+fn service() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/service_*.rs");
+}
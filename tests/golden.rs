@@ -0,0 +1,285 @@
+use drpc::record::{Direction, Event, Output, Recording, ReplayWire};
+use drpc::wire::{frame, id::ID, packet, split};
+use drpc::{metadata, stream, transport, StreamRecv, StreamSend, Transport};
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+fn corpus() -> BTreeMap<String, Vec<u8>> {
+    let mut cases = BTreeMap::new();
+    let mut name = String::new();
+
+    for line in include_str!("golden/wire.txt").lines() {
+        let line = line.split('#').next().unwrap();
+        let hex = match line.split_once(':') {
+            Some((case, hex)) => {
+                name = case.trim().to_string();
+                hex
+            }
+            None => line,
+        };
+        let bytes = hex
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16).unwrap());
+        if !name.is_empty() {
+            cases
+                .entry(name.clone())
+                .or_insert_with(Vec::new)
+                .extend(bytes);
+        }
+    }
+
+    cases
+}
+
+fn golden(name: &str) -> Vec<u8> {
+    match corpus().remove(name) {
+        Some(bytes) => bytes,
+        None => panic!("missing golden case {:?}", name),
+    }
+}
+
+// pipe returns a wire that reads the input and captures what is written.
+fn pipe(input: &[u8]) -> (ReplayWire, Output) {
+    let rec = Recording {
+        events: vec![Event {
+            at: Duration::default(),
+            dir: Direction::Read,
+            data: input.to_vec(),
+        }],
+    };
+    let wire = ReplayWire::new(&rec);
+    let output = wire.output();
+    (wire, output)
+}
+
+fn done(data: &[u8], id: ID, kind: packet::Kind) -> frame::Frame<'_> {
+    frame::Frame {
+        data,
+        id,
+        kind: kind.into(),
+        done: true,
+        control: false,
+    }
+}
+
+fn frames() -> Vec<(&'static str, frame::Frame<'static>)> {
+    vec![
+        (
+            "frame/invoke",
+            done(b"/a/b", ID::new(1, 1), packet::Kind::Invoke),
+        ),
+        (
+            "frame/empty-message",
+            done(b"", ID::new(1, 1), packet::Kind::Message),
+        ),
+        (
+            "frame/partial-message",
+            frame::Frame {
+                done: false,
+                ..done(b"abc", ID::new(1, 2), packet::Kind::Message)
+            },
+        ),
+        (
+            "frame/control",
+            frame::Frame {
+                control: true,
+                ..done(b"", ID::new(0, 0), packet::Kind::Message)
+            },
+        ),
+        (
+            "frame/max-varint",
+            done(b"", ID::new(u64::MAX, u64::MAX), packet::Kind::Message),
+        ),
+    ]
+}
+
+#[test]
+fn frame_roundtrip() {
+    for (name, fr) in frames() {
+        let bytes = golden(name);
+
+        let mut buf = Vec::new();
+        frame::append_frame(&mut buf, &fr);
+        assert_eq!(buf, bytes, "{}", name);
+        assert_eq!(
+            frame::parse_frame(&bytes),
+            Ok((fr, bytes.len())),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn split_produces() {
+    for (name, data, n) in &[
+        ("split/exact", &b"abcd"[..], 2),
+        ("split/remainder", &b"abcdef"[..], 4),
+    ] {
+        let pkt = packet::Packet {
+            data: *data,
+            id: ID::new(1, 1),
+            kind: packet::Kind::Message,
+        };
+
+        let mut buf = Vec::new();
+        for fr in split::split(&pkt, *n) {
+            frame::append_frame(&mut buf, &fr);
+        }
+        assert_eq!(buf, golden(name), "{}", name);
+    }
+}
+
+fn kv() -> metadata::Metadata {
+    let mut md = metadata::Metadata::new();
+    md.insert("k".into(), "v".into());
+    md
+}
+
+fn packets() -> Vec<(&'static str, ID, packet::Kind, Vec<u8>)> {
+    let mut error = 5u64.to_be_bytes().to_vec();
+    error.extend_from_slice(b"boom");
+
+    let mut md = Vec::new();
+    metadata::append(&mut md, &kv());
+
+    vec![
+        ("packet/error", ID::new(1, 1), packet::Kind::Error, error),
+        (
+            "packet/metadata",
+            ID::new(1, 1),
+            packet::Kind::InvokeMetadata,
+            md,
+        ),
+        ("packet/close", ID::new(1, 1), packet::Kind::Close, vec![]),
+        (
+            "packet/close-send",
+            ID::new(1, 1),
+            packet::Kind::CloseSend,
+            vec![],
+        ),
+        (
+            "split/exact",
+            ID::new(1, 1),
+            packet::Kind::Message,
+            b"abcd".to_vec(),
+        ),
+        (
+            "split/remainder",
+            ID::new(1, 1),
+            packet::Kind::Message,
+            b"abcdef".to_vec(),
+        ),
+        (
+            "frame/max-varint",
+            ID::new(u64::MAX, u64::MAX),
+            packet::Kind::Message,
+            vec![],
+        ),
+    ]
+}
+
+#[tokio::test]
+async fn transport_produces() {
+    for (name, id, kind, data) in packets().into_iter().filter(|p| p.0.starts_with("packet/")) {
+        let (wire, output) = pipe(&[]);
+        let mut tr = transport::Transport::new(wire);
+        tr.write_frame(done(&data, id, kind)).await.unwrap();
+        tr.flush().await.unwrap();
+        assert_eq!(output.bytes(), golden(name), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn transport_accepts() {
+    for (name, id, kind, data) in packets() {
+        // control frames are skipped wherever they appear.
+        let mut input = golden("frame/control");
+        input.extend(golden(name));
+
+        let (wire, _) = pipe(&input);
+        let mut tr = transport::Transport::new(wire);
+        let mut buf = Vec::new();
+        assert_eq!(
+            tr.read_packet_into(&mut buf).await.unwrap(),
+            (id, kind),
+            "{}",
+            name
+        );
+        assert_eq!(buf, data, "{}", name);
+        assert_eq!(
+            tr.read_packet_into(&mut buf).await.unwrap_err().kind(),
+            transport::ErrorKind::RemoteClosed
+        );
+    }
+}
+
+#[tokio::test]
+async fn stream_produces() {
+    let md = kv();
+    for (name, with_md) in &[
+        ("stream/client-unitary", false),
+        ("stream/client-metadata", true),
+    ] {
+        let (wire, output) = pipe(&[]);
+        let mut tr = transport::Transport::new(wire);
+        let mut buf = Vec::new();
+        let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+        if *with_md {
+            st.invoke_with_metadata(b"/a/b", &md).await.unwrap();
+        } else {
+            st.invoke(b"/a/b").await.unwrap();
+        }
+        st.send(&b"hi".to_vec()).await.unwrap();
+        st.close_send().await.unwrap();
+        assert_eq!(output.bytes(), golden(name), "{}", name);
+    }
+
+    let (wire, output) = pipe(&[]);
+    let mut tr = transport::Transport::new(wire);
+    let mut buf = Vec::new();
+    let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+    st.send(&b"ok".to_vec()).await.unwrap();
+    st.close_send().await.unwrap();
+    assert_eq!(output.bytes(), golden("stream/server-unitary"));
+
+    for name in &["packet/error", "packet/close", "packet/close-send"] {
+        let (wire, output) = pipe(&[]);
+        let mut tr = transport::Transport::new(wire);
+        let mut buf = Vec::new();
+        let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+        match *name {
+            "packet/error" => st.error("boom", 5).await.unwrap(),
+            "packet/close" => st.close().await.unwrap(),
+            _ => st.close_send().await.unwrap(),
+        }
+        assert_eq!(output.bytes(), golden(name), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn stream_accepts() {
+    let (wire, _) = pipe(&golden("stream/server-unitary"));
+    let mut tr = transport::Transport::new(wire);
+    let mut buf = Vec::new();
+    let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+    let mut out = Vec::new();
+    st.recv_into(&mut out).await.unwrap();
+    assert_eq!(out, b"ok");
+    assert!(matches!(
+        st.recv_into(&mut out).await,
+        Err(stream::Error::StateError(stream::State::EOF))
+    ));
+
+    let (wire, _) = pipe(&golden("packet/error"));
+    let mut tr = transport::Transport::new(wire);
+    let mut buf = Vec::new();
+    let mut st = stream::Stream::new(1, &mut tr, &mut buf);
+    match st.recv_into(&mut out).await {
+        Err(stream::Error::StateError(stream::State::RemoteError((5, msg)))) => {
+            assert_eq!(msg, "boom")
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
@@ -0,0 +1,6 @@
+#!/bin/sh
+# regenerates wire.txt with the storj.io/drpc version pinned in gen/go.mod.
+set -e
+cd "$(dirname "$0")/gen"
+go mod tidy
+go run . > ../wire.txt
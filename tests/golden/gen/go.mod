@@ -0,0 +1,5 @@
+module drpc-rs/tests/golden/gen
+
+go 1.21
+
+require storj.io/drpc v0.0.34
@@ -0,0 +1,185 @@
+// Command gen writes the golden drpc wire corpus read by tests/golden.rs,
+// encoding every case with storj.io/drpc. Run tests/golden/gen.sh to
+// regenerate tests/golden/wire.txt.
+package main
+
+import (
+	"bufio"
+	"bytes"
+	"context"
+	"errors"
+	"fmt"
+	"math"
+	"os"
+	"runtime/debug"
+	"strings"
+
+	"storj.io/drpc/drpcerr"
+	"storj.io/drpc/drpcmetadata"
+	"storj.io/drpc/drpcstream"
+	"storj.io/drpc/drpcwire"
+)
+
+var out = bufio.NewWriter(os.Stdout)
+
+func main() {
+	header()
+
+	emit("frame/invoke", frame(drpcwire.Frame{
+		Data: []byte("/a/b"), ID: id(1, 1), Kind: drpcwire.KindInvoke, Done: true,
+	}))
+	emit("frame/empty-message", frame(drpcwire.Frame{
+		ID: id(1, 1), Kind: drpcwire.KindMessage, Done: true,
+	}))
+	emit("frame/partial-message", frame(drpcwire.Frame{
+		Data: []byte("abc"), ID: id(1, 2), Kind: drpcwire.KindMessage,
+	}))
+	emit("frame/control", frame(drpcwire.Frame{
+		Kind: drpcwire.KindMessage, Done: true, Control: true,
+	}))
+	emit("frame/max-varint", frame(drpcwire.Frame{
+		ID: id(math.MaxUint64, math.MaxUint64), Kind: drpcwire.KindMessage, Done: true,
+	}))
+
+	comment(`"abcd" split every 2 bytes, and "abcdef" split every 4 bytes.`)
+	emit("split/exact", split("abcd", 2))
+	emit("split/remainder", split("abcdef", 4))
+
+	comment("error data is the code as 8 big-endian bytes followed by the message.")
+	comment("metadata data is the protobuf message { map<string, string> data = 1 }.")
+	emit("packet/error", send(func(st *drpcstream.Stream) error {
+		return st.SendError(drpcerr.WithCode(errors.New("boom"), 5))
+	}))
+	emit("packet/metadata", send(func(st *drpcstream.Stream) error {
+		return st.RawWrite(drpcwire.KindInvokeMetadata, metadata())
+	}))
+	emit("packet/close", send(func(st *drpcstream.Stream) error {
+		return st.Close()
+	}))
+	emit("packet/close-send", send(func(st *drpcstream.Stream) error {
+		return st.CloseSend()
+	}))
+
+	comment(`a client invoking /a/b with the message "hi", with and without the`)
+	comment(`metadata k=v, and a server answering with "ok".`)
+	emit("stream/client-unitary", send(func(st *drpcstream.Stream) error {
+		return invoke(st, nil)
+	}))
+	emit("stream/client-metadata", send(func(st *drpcstream.Stream) error {
+		return invoke(st, metadata())
+	}))
+	emit("stream/server-unitary", send(func(st *drpcstream.Stream) error {
+		if err := st.RawWrite(drpcwire.KindMessage, []byte("ok")); err != nil {
+			return err
+		}
+		return st.CloseSend()
+	}))
+
+	check(out.Flush())
+}
+
+func header() {
+	version := "unknown"
+	if info, ok := debug.ReadBuildInfo(); ok {
+		for _, dep := range info.Deps {
+			if dep.Path == "storj.io/drpc" {
+				version = dep.Version
+			}
+		}
+	}
+
+	comment("golden drpc wire bytes, generated by tests/golden/gen with storj.io/drpc")
+	comment(version + ". run tests/golden/gen.sh to regenerate them instead of")
+	comment("editing this file. a case is a name followed by a colon and hex bytes,")
+	comment("with a frame on every line. comments start with a #.")
+	comment("")
+	comment("a frame is a header byte (control << 7 | kind << 1 | done) followed by")
+	comment("varints for the stream id, the message id and the data length, and then")
+	comment("the data. kinds are invoke=1, message=2, error=3, close=5, close-send=6")
+	comment("and invoke-metadata=7.")
+	fmt.Fprintln(out)
+}
+
+// invoke writes what drpcconn writes to invoke an rpc with a message.
+func invoke(st *drpcstream.Stream, md []byte) error {
+	if len(md) > 0 {
+		if err := st.RawWrite(drpcwire.KindInvokeMetadata, md); err != nil {
+			return err
+		}
+	}
+	if err := st.RawWrite(drpcwire.KindInvoke, []byte("/a/b")); err != nil {
+		return err
+	}
+	if err := st.RawWrite(drpcwire.KindMessage, []byte("hi")); err != nil {
+		return err
+	}
+	return st.CloseSend()
+}
+
+func metadata() []byte {
+	md, err := drpcmetadata.Encode(nil, map[string]string{"k": "v"})
+	check(err)
+	return md
+}
+
+func id(stream, message uint64) drpcwire.ID {
+	return drpcwire.ID{Stream: stream, Message: message}
+}
+
+func frame(fr drpcwire.Frame) []byte {
+	return drpcwire.AppendFrame(nil, fr)
+}
+
+func split(data string, n int) []byte {
+	var buf []byte
+	pkt := drpcwire.Packet{Data: []byte(data), ID: id(1, 1), Kind: drpcwire.KindMessage}
+	check(drpcwire.SplitN(pkt, n, func(fr drpcwire.Frame) error {
+		buf = drpcwire.AppendFrame(buf, fr)
+		return nil
+	}))
+	return buf
+}
+
+// send returns what fn writes to stream 1.
+func send(fn func(st *drpcstream.Stream) error) []byte {
+	var buf bytes.Buffer
+	wr := drpcwire.NewWriter(&buf, 0)
+	st := drpcstream.New(context.Background(), 1, wr)
+	check(fn(st))
+	check(wr.Flush())
+	return buf.Bytes()
+}
+
+// emit writes a case with every frame on its own line.
+func emit(name string, data []byte) {
+	prefix := fmt.Sprintf("%-25s", name+":")
+	for len(data) > 0 {
+		rem, _, ok, err := drpcwire.ParseFrame(data)
+		check(err)
+		if !ok {
+			check(fmt.Errorf("%s: incomplete frame", name))
+		}
+		fmt.Fprintf(out, "%s% x\n", prefix, data[:len(data)-len(rem)])
+		prefix = strings.Repeat(" ", len(prefix))
+		data = rem
+	}
+	wrote = true
+}
+
+var wrote bool
+
+// comment writes a line of comment, separated from the cases before it.
+func comment(text string) {
+	if wrote {
+		fmt.Fprintln(out)
+		wrote = false
+	}
+	fmt.Fprintln(out, strings.TrimRight("# "+text, " "))
+}
+
+func check(err error) {
+	if err != nil {
+		fmt.Fprintln(os.Stderr, "error:", err)
+		os.Exit(1)
+	}
+}
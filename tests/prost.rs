@@ -0,0 +1,34 @@
+use drpc::enc::{Marshal, Unmarshal};
+
+#[derive(Clone, PartialEq, prost::Message, drpc::ProstMessage)]
+struct Cookie {
+    #[prost(string, tag = "1")]
+    flavor: String,
+    #[prost(uint32, tag = "2")]
+    chips: u32,
+}
+
+#[test]
+fn round_trip() {
+    let cookie = Cookie {
+        flavor: "chocolate".into(),
+        chips: 12,
+    };
+
+    let mut buf = vec![1, 2, 3];
+    cookie.marshal(&mut buf).unwrap();
+    assert_eq!(buf, prost::Message::encode_to_vec(&cookie));
+
+    let mut out = Cookie {
+        flavor: "oatmeal".into(),
+        chips: 0,
+    };
+    out.unmarshal(&buf).unwrap();
+    assert_eq!(out, cookie);
+}
+
+#[test]
+fn unmarshal_error() {
+    let mut out = Cookie::default();
+    assert!(out.unmarshal(&[0x0a, 0x05, b'a']).is_err());
+}
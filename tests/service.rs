@@ -0,0 +1,181 @@
+use drpc::{enc, stream, testing};
+
+#[derive(Debug, Default, PartialEq)]
+struct Count(u64);
+
+impl enc::Marshal for Count {
+    fn marshal(&self, buf: &mut Vec<u8>) -> enc::Result<()> {
+        buf.clear();
+        buf.extend_from_slice(&self.0.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl enc::Unmarshal for Count {
+    fn unmarshal(&mut self, buf: &[u8]) -> enc::Result<()> {
+        let mut raw = [0; 8];
+        raw.copy_from_slice(buf.get(..8).ok_or("short count")?);
+        self.0 = u64::from_be_bytes(raw);
+        Ok(())
+    }
+}
+
+#[drpc::service(name = "sesamestreet.CookieMonster")]
+trait CookieMonster {
+    #[drpc(name = "EatCookie")]
+    async fn eat_cookie(&self, cookie: Vec<u8>) -> stream::Result<Count>;
+
+    async fn count_cookies(&self, st: &mut dyn drpc::Stream<Count, Vec<u8>>) -> stream::Result<()>;
+
+    // server streaming, which is served over a bidirectional stream.
+    async fn crumble(&self, st: &mut dyn drpc::Stream<Count, Vec<u8>>) -> stream::Result<()>;
+}
+
+struct Monster;
+
+#[drpc::async_trait]
+impl CookieMonster for Monster {
+    async fn eat_cookie(&self, cookie: Vec<u8>) -> stream::Result<Count> {
+        Ok(Count(cookie.len() as u64))
+    }
+
+    async fn count_cookies(&self, st: &mut dyn drpc::Stream<Count, Vec<u8>>) -> stream::Result<()> {
+        let mut total = 0;
+        let mut cookie = Vec::new();
+        loop {
+            match st.recv_into(&mut cookie).await {
+                Ok(()) => total += 1,
+                Err(stream::Error::StateError(stream::State::EOF)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        st.send(&Count(total)).await
+    }
+
+    async fn crumble(&self, st: &mut dyn drpc::Stream<Count, Vec<u8>>) -> stream::Result<()> {
+        let mut cookie = Vec::new();
+        st.recv_into(&mut cookie).await?;
+        for crumb in cookie {
+            st.send(&Count(crumb.into())).await?;
+        }
+        Ok(())
+    }
+}
+
+fn client() -> CookieMonsterClient<testing::Conn> {
+    let (conn, _) = testing::pair(CookieMonsterServer::new(Monster));
+    CookieMonsterClient::new(conn)
+}
+
+#[test]
+fn description() {
+    assert_eq!(
+        CookieMonsterDescription::SERVICE,
+        "sesamestreet.CookieMonster"
+    );
+    assert_eq!(
+        CookieMonsterDescription::METHODS,
+        &[
+            "/sesamestreet.CookieMonster/EatCookie",
+            "/sesamestreet.CookieMonster/CountCookies",
+            "/sesamestreet.CookieMonster/Crumble",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unitary() {
+    let mut client = client();
+    assert_eq!(client.eat_cookie(&vec![1, 2, 3]).await.unwrap(), Count(3));
+    assert_eq!(client.eat_cookie(&vec![]).await.unwrap(), Count(0));
+}
+
+#[tokio::test]
+async fn streaming() {
+    let mut client = client();
+    let mut st = client.count_cookies().await.unwrap();
+    for i in 0..5 {
+        st.send(&vec![i]).await.unwrap();
+    }
+    st.close_send().await.unwrap();
+
+    let mut out = Count::default();
+    st.recv_into(&mut out).await.unwrap();
+    assert_eq!(out, Count(5));
+    st.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn server_streaming() {
+    let mut client = client();
+    let mut st = client.crumble().await.unwrap();
+    st.send(&vec![3, 1, 2]).await.unwrap();
+    st.close_send().await.unwrap();
+
+    let mut crumbs = Vec::new();
+    let mut out = Count::default();
+    loop {
+        match st.recv_into(&mut out).await {
+            Ok(()) => crumbs.push(out.0),
+            Err(stream::Error::StateError(stream::State::EOF)) => break,
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+    assert_eq!(crumbs, [3, 1, 2]);
+}
+
+#[tokio::test]
+async fn unknown_rpc() {
+    let mut client = client();
+    let res: stream::Result<Vec<u8>> =
+        drpc::Conn::invoke(client.conn(), b"/missing", &vec![]).await;
+    match res {
+        Err(stream::Error::StateError(stream::State::RemoteError((10, msg)))) => {
+            assert_eq!(msg, "unknown rpc: \"/missing\"")
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+// burnt is an error of the service's own, which only implements Display.
+#[derive(Debug)]
+struct Burnt(u64);
+
+impl std::fmt::Display for Burnt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "burnt {} cookies", self.0)
+    }
+}
+
+#[drpc::service]
+trait Oven {
+    async fn bake(&self, count: Count) -> Result<Count, Burnt>;
+}
+
+struct Hot;
+
+#[drpc::async_trait]
+impl Oven for Hot {
+    async fn bake(&self, count: Count) -> Result<Count, Burnt> {
+        match count.0 {
+            0..=12 => Ok(count),
+            n => Err(Burnt(n)),
+        }
+    }
+}
+
+#[tokio::test]
+async fn unitary_error() {
+    let (conn, _) = testing::pair(OvenServer::new(Hot));
+    let mut client = OvenClient::new(conn);
+    assert_eq!(client.bake(&Count(12)).await.unwrap(), Count(12));
+
+    match client.bake(&Count(13)).await {
+        Err(stream::Error::StateError(stream::State::RemoteError((10, msg)))) => {
+            assert_eq!(msg, "burnt 13 cookies")
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    // the failed rpc leaves the connection usable.
+    assert_eq!(client.bake(&Count(1)).await.unwrap(), Count(1));
+}
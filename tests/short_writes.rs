@@ -0,0 +1,75 @@
+use drpc::wire::{id::ID, packet, split};
+use drpc::Transport;
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// short_wire takes at most a few bytes with every write, like a socket with a
+// full send buffer.
+#[derive(Default, Clone)]
+struct ShortWire {
+    written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl AsyncRead for ShortWire {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        _: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ShortWire {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = data.len().min(3);
+        self.written.lock().unwrap().extend_from_slice(&data[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn flush_writes_whole_buffer() {
+    let pkts: Vec<_> = (1..=3)
+        .map(|message| packet::Packet {
+            data: vec![message as u8; 100],
+            id: ID::new(1, message),
+            kind: packet::Kind::Message,
+        })
+        .collect();
+
+    let short = ShortWire::default();
+    let mut tr = drpc::transport::Transport::new(short.clone());
+    for pkt in &pkts {
+        for fr in split::split(pkt, 40) {
+            tr.write_frame(fr).await.unwrap();
+        }
+    }
+    tr.flush().await.unwrap();
+
+    let written = short.written.lock().unwrap().clone();
+    let wire = tokio::io::join(&written[..], tokio::io::sink());
+    let mut tr = drpc::transport::Transport::new(wire);
+    let mut buf = Vec::new();
+    for pkt in &pkts {
+        let (id, kind) = tr.read_packet_into(&mut buf).await.unwrap();
+        assert_eq!((id, kind, &buf), (pkt.id, pkt.kind, &pkt.data));
+    }
+}
@@ -0,0 +1,16 @@
+#[drpc::service]
+trait Bakery {
+    async fn bake(&self, cookie: Vec<u8>) -> Option<Vec<u8>>;
+}
+
+#[drpc::service]
+trait Kitchen {
+    async fn cook(&self, cookie: Vec<u8>) -> Box<drpc::stream::Result<Vec<u8>>>;
+}
+
+#[drpc::service]
+trait Pantry {
+    async fn store(&self, cookie: Vec<u8>) -> Result<Vec<u8>, u8, u8>;
+}
+
+fn main() {}